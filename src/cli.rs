@@ -1,8 +1,14 @@
 use clap::{command, value_parser, Arg};
 use ftag::{
-    core::{self, get_all_tags, search, untracked_files, Error},
+    config::Config,
+    core::{self, get_all_tags, untracked_files, Error, TagEdit},
+    fingerprint,
+    index::Index,
     load::get_ftag_path,
+    output::{CountRecord, FileRecord, Format, OutputOptions, PathRecord, Printer, TagRecord},
     query::{count_files_tags, run_query, TagTable},
+    search::search,
+    walk::WalkOptions,
 };
 use std::path::PathBuf;
 
@@ -15,6 +21,26 @@ fn main() -> Result<(), Error> {
     } else {
         std::env::current_dir().map_err(|_| Error::InvalidWorkingDirectory)?
     };
+    let options = OutputOptions {
+        format: matches
+            .get_one::<String>(arg::FORMAT)
+            .and_then(|f| Format::from_name(f))
+            .unwrap_or(Format::Text),
+        print0: matches.get_flag(arg::PRINT0),
+        absolute: matches.get_flag(arg::ABSOLUTE),
+    };
+    let walk = WalkOptions {
+        threads: matches.get_one::<usize>(arg::THREADS).copied().unwrap_or(1),
+        no_ignore: matches.get_flag(arg::NO_IGNORE),
+        max_depth: matches.get_one::<usize>(arg::MAX_DEPTH).copied(),
+        follow_symlinks: matches.get_flag(arg::FOLLOW_SYMLINKS),
+        one_file_system: matches.get_flag(arg::ONE_FILE_SYSTEM),
+        strict: matches.get_flag(arg::STRICT),
+    };
+    let format = options.format;
+    if options.print0 && format != Format::Text {
+        return Err(Error::InvalidArgs); // NUL separators only make sense for text.
+    }
     // Handle tab completions first.
     if let Some(complete) = matches.subcommand_matches(cmd::BASH_COMPLETE) {
         // Bash completions can be registered with:
@@ -25,37 +51,94 @@ fn main() -> Result<(), Error> {
         return Ok(());
     }
     if let Some(_matches) = matches.subcommand_matches(cmd::COUNT) {
-        let (nfiles, ntags) = count_files_tags(current_dir)?;
-        println!("{} files; {} tags", nfiles, ntags);
+        let (nfiles, ntags) = count_files_tags(current_dir.clone(), &walk)?;
+        match format {
+            Format::Text => println!("{} files; {} tags", nfiles, ntags),
+            _ => {
+                let mut printer = Printer::new::<CountRecord>(&options, &current_dir);
+                printer.print(&CountRecord {
+                    files: nfiles,
+                    tags: ntags,
+                });
+                printer.finish();
+            }
+        }
         return Ok(());
     }
     if let Some(matches) = matches.subcommand_matches(cmd::QUERY) {
         let filter = matches
             .get_one::<String>(arg::FILTER)
             .ok_or(Error::InvalidArgs)?;
-        run_query(current_dir, filter)
+        run_query(
+            current_dir,
+            filter,
+            &options,
+            &walk,
+            matches.get_flag(arg::STRICT_TAGS),
+        )
     } else if let Some(matches) = matches.subcommand_matches(cmd::SEARCH) {
-        return search(
+        search(
             current_dir,
             matches
                 .get_one::<String>(arg::SEARCH_STR)
                 .ok_or(Error::InvalidArgs)?,
-        );
+            matches.get_flag(arg::SCORES),
+            &options,
+            &walk,
+        )
     } else if let Some(_matches) = matches.subcommand_matches(cmd::INTERACTIVE) {
-        return ftag::tui::start(TagTable::from_dir(current_dir)?)
-            .map_err(|err| Error::TUIFailure(format!("{:?}", err)));
+        ftag::tui::start(TagTable::from_dir(current_dir, &walk)?)
+            .map_err(|err| Error::TUIFailure(format!("{:?}", err)))
     } else if let Some(_matches) = matches.subcommand_matches(cmd::CHECK) {
-        return core::check(current_dir);
+        match format {
+            Format::Text => core::check(current_dir, &walk),
+            _ => {
+                let missing = core::unmatched_globs(current_dir.clone(), &walk)?;
+                let mut printer = Printer::new::<core::GlobInfo>(&options, &current_dir);
+                for info in missing.iter() {
+                    printer.print(info);
+                }
+                printer.finish();
+                if !missing.is_empty() {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+        }
     } else if let Some(matches) = matches.subcommand_matches(cmd::WHATIS) {
         match matches.get_one::<PathBuf>(arg::PATH) {
             Some(path) => {
                 let path = path
                     .canonicalize()
                     .map_err(|_| Error::InvalidPath(path.clone()))?;
-                println!("{}", core::what_is(&path)?);
-                return Ok(());
+                let explain = matches.get_flag(arg::EXPLAIN);
+                match format {
+                    Format::Text => {
+                        println!("{}", core::what_is(&path)?);
+                        if explain {
+                            let implied = core::implied_by_rules(&path)?;
+                            if !implied.is_empty() {
+                                println!("implied by rules:");
+                            }
+                            for (tag, source) in implied {
+                                println!("  {} ({} -> {})", tag, source, tag);
+                            }
+                        }
+                    }
+                    _ if explain => return Err(Error::InvalidArgs), // Only meant for humans.
+                    _ => {
+                        let mut record = core::describe(&path)?;
+                        if let Ok(relpath) = record.path.strip_prefix(&current_dir) {
+                            record.path = relpath.to_path_buf();
+                        }
+                        let mut printer = Printer::new::<FileRecord>(&options, &current_dir);
+                        printer.print(&record);
+                        printer.finish();
+                    }
+                }
+                Ok(())
             }
-            None => return Err(Error::InvalidArgs),
+            None => Err(Error::InvalidArgs),
         }
     } else if let Some(matches) = matches.subcommand_matches(cmd::EDIT) {
         let path = matches
@@ -65,27 +148,90 @@ fn main() -> Result<(), Error> {
             Some(fpath) => fpath,
             None => return Err(Error::InvalidPath(path.clone())),
         })
-        .map_err(|e| Error::EditCommandFailed(format!("{:?}", e)))?;
-        return Ok(());
+        .map_err(|e| Error::EditCommandFailed(format!("{:?}", e)))
+    } else if let Some(matches) = matches.subcommand_matches(cmd::TAG) {
+        edit_tags_args(matches, TagEdit::Add)
+    } else if let Some(matches) = matches.subcommand_matches(cmd::UNTAG) {
+        edit_tags_args(matches, TagEdit::Remove)
+    } else if let Some(matches) = matches.subcommand_matches(cmd::MOVE) {
+        match (
+            matches.get_one::<PathBuf>(arg::SRC),
+            matches.get_one::<PathBuf>(arg::DST),
+        ) {
+            (Some(src), Some(dst)) => core::move_file(src, dst),
+            _ => Err(Error::InvalidArgs),
+        }
     } else if let Some(_matches) = matches.subcommand_matches(cmd::CLEAN) {
-        core::clean(current_dir)
+        core::clean(current_dir, &walk)
+    } else if let Some(_matches) = matches.subcommand_matches(cmd::FINGERPRINT) {
+        fingerprint::update_fingerprints(current_dir, &walk)
+    } else if let Some(_matches) = matches.subcommand_matches(cmd::INDEX) {
+        Index::update(&current_dir).map(|_| ())
+    } else if let Some(matches) = matches.subcommand_matches(cmd::REPAIR) {
+        fingerprint::repair(current_dir, matches.get_flag(arg::YES), &walk)
     } else if let Some(_matches) = matches.subcommand_matches(cmd::UNTRACKED) {
-        for path in untracked_files(current_dir)? {
-            println!("{}", path.display());
+        let untracked = untracked_files(current_dir.clone(), &walk)?;
+        let mut printer = Printer::new::<PathRecord>(&options, &current_dir);
+        for path in untracked {
+            printer.print(&PathRecord(&path));
         }
-        return Ok(());
-    } else if let Some(_matches) = matches.subcommand_matches(cmd::TAGS) {
-        let mut tags: Box<[String]> = get_all_tags(current_dir)?.collect();
+        printer.finish();
+        Ok(())
+    } else if let Some(matches) = matches.subcommand_matches(cmd::TAGS) {
+        let tree = matches.get_flag(arg::TREE);
+        if tree && format != Format::Text {
+            return Err(Error::InvalidArgs); // The tree is only meant to be read by humans.
+        }
+        let mut tags: Box<[String]> = get_all_tags(current_dir.clone(), &walk)?.collect();
         tags.sort_unstable();
-        for tag in tags {
-            println!("{}", tag);
+        let config = Config::load(&current_dir)?;
+        let mut printer = Printer::new::<TagRecord>(&options, &current_dir);
+        // In text format, the aliases are listed after the tag, the same way
+        // they are declared in the config files.
+        let mut print = |label: &str, tag: &str| {
+            let aliases = config.aliases(tag);
+            match format {
+                Format::Text if !aliases.is_empty() => printer.print(&TagRecord {
+                    tag: &format!("{} = {}", label, aliases.join(" ")),
+                    aliases: &aliases,
+                }),
+                _ => printer.print(&TagRecord {
+                    tag: label,
+                    aliases: &aliases,
+                }),
+            }
+        };
+        if tree {
+            for (depth, tag) in core::tag_tree(&tags) {
+                let name = tag.rsplit('/').next().unwrap_or(tag);
+                print(&format!("{:indent$}{}", "", name, indent = 2 * depth), tag);
+            }
+        } else {
+            for tag in tags.iter() {
+                print(tag, tag);
+            }
         }
-        return Ok(());
+        printer.finish();
+        Ok(())
     } else {
-        return Err(Error::InvalidArgs);
+        Err(Error::InvalidArgs)
     }
 }
 
+fn edit_tags_args(matches: &clap::ArgMatches, edit: TagEdit) -> Result<(), Error> {
+    let paths: Vec<PathBuf> = matches
+        .get_many::<PathBuf>(arg::FILES)
+        .ok_or(Error::InvalidArgs)?
+        .cloned()
+        .collect();
+    let tags: Vec<String> = matches
+        .get_many::<String>(arg::TAGS)
+        .ok_or(Error::InvalidArgs)?
+        .cloned()
+        .collect();
+    core::edit_tags(&paths, &tags, edit)
+}
+
 fn handle_bash_completions(current_dir: PathBuf, mut words: Vec<&str>) {
     /*
     Bash completion always passes in 3 words. The first word will be the main
@@ -99,18 +245,35 @@ fn handle_bash_completions(current_dir: PathBuf, mut words: Vec<&str>) {
     if words[0] != "ftag" {
         return;
     }
-    const PREV_WORDS: [&str; 11] = [
+    const PREV_WORDS: [&str; 28] = [
         "query",
         "-q",
         "interactive",
         "check",
         "whatis",
         "edit",
+        "tag",
+        "untag",
+        "mv",
         "untracked",
         "tags",
         "clean",
+        "fingerprint",
+        "repair",
+        "index",
         "--path",
         "-p",
+        "--format",
+        "--print0",
+        "--absolute",
+        "--threads",
+        "-j",
+        "--no-ignore",
+        "--max-depth",
+        "--follow-symlinks",
+        "-L",
+        "--one-file-system",
+        "--strict",
     ];
     match words.pop() {
         Some("ftag") => {
@@ -121,12 +284,15 @@ fn handle_bash_completions(current_dir: PathBuf, mut words: Vec<&str>) {
             }
         }
         Some(cmd::QUERY) | Some(cmd::QUERY_SHORT) => {
-            if let (Some(word), Ok(tags)) = (words.pop(), get_all_tags(current_dir)) {
+            if let (Some(word), Ok(tags)) = (
+                words.pop(),
+                get_all_tags(current_dir, &WalkOptions::default()),
+            ) {
                 let (left, right) = {
                     let mut last = 0usize;
                     for (i, c) in word.char_indices() {
                         match c {
-                            '|' | '(' | ')' | '&' | '!' => last = i,
+                            '|' | '(' | ')' | '&' | '!' | '^' => last = i,
                             _ if c.is_whitespace() => last = i,
                             _ => {} // Do nothing.
                         }
@@ -152,6 +318,74 @@ fn parse_args() -> clap::ArgMatches {
                 .required(false)
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            Arg::new(arg::FORMAT)
+                .long("format")
+                .global(true)
+                .value_parser(Format::NAMES)
+                .default_value("text")
+                .help(about::FORMAT),
+        )
+        .arg(
+            Arg::new(arg::PRINT0)
+                .long("print0")
+                .short('0')
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help(about::PRINT0),
+        )
+        .arg(
+            Arg::new(arg::ABSOLUTE)
+                .long("absolute")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help(about::ABSOLUTE),
+        )
+        .arg(
+            Arg::new(arg::THREADS)
+                .long("threads")
+                .short('j')
+                .global(true)
+                .value_parser(value_parser!(usize))
+                .default_value("1")
+                .help(about::THREADS),
+        )
+        .arg(
+            Arg::new(arg::NO_IGNORE)
+                .long("no-ignore")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help(about::NO_IGNORE),
+        )
+        .arg(
+            Arg::new(arg::MAX_DEPTH)
+                .long("max-depth")
+                .global(true)
+                .value_parser(value_parser!(usize))
+                .help(about::MAX_DEPTH),
+        )
+        .arg(
+            Arg::new(arg::FOLLOW_SYMLINKS)
+                .long("follow-symlinks")
+                .short('L')
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help(about::FOLLOW_SYMLINKS),
+        )
+        .arg(
+            Arg::new(arg::ONE_FILE_SYSTEM)
+                .long("one-file-system")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help(about::ONE_FILE_SYSTEM),
+        )
+        .arg(
+            Arg::new(arg::STRICT)
+                .long("strict")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help(about::STRICT),
+        )
         .subcommand(clap::Command::new(cmd::COUNT).about(about::COUNT))
         .subcommand(
             clap::Command::new(cmd::QUERY)
@@ -162,6 +396,12 @@ fn parse_args() -> clap::ArgMatches {
                         .required(true)
                         .help(about::QUERY_FILTER)
                         .long_help(about::QUERY_FILTER_LONG),
+                )
+                .arg(
+                    Arg::new(arg::STRICT_TAGS)
+                        .long("strict-tags")
+                        .action(clap::ArgAction::SetTrue)
+                        .help(about::QUERY_STRICT_TAGS),
                 ),
         )
         .subcommand(
@@ -173,6 +413,12 @@ fn parse_args() -> clap::ArgMatches {
                         .required(true)
                         .help(about::SEARCH_STR)
                         .long_help(about::SEARCH_STR_LONG),
+                )
+                .arg(
+                    Arg::new(arg::SCORES)
+                        .long("scores")
+                        .action(clap::ArgAction::SetTrue)
+                        .help(about::SEARCH_SCORES),
                 ),
         )
         .subcommand(
@@ -189,12 +435,20 @@ fn parse_args() -> clap::ArgMatches {
             ),
         )
         .subcommand(
-            clap::Command::new(cmd::WHATIS).about(about::WHATIS).arg(
-                Arg::new(arg::PATH)
-                    .required(true)
-                    .value_parser(value_parser!(PathBuf))
-                    .help(about::WHATIS_PATH),
-            ),
+            clap::Command::new(cmd::WHATIS)
+                .about(about::WHATIS)
+                .arg(
+                    Arg::new(arg::PATH)
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help(about::WHATIS_PATH),
+                )
+                .arg(
+                    Arg::new(arg::EXPLAIN)
+                        .long("explain")
+                        .action(clap::ArgAction::SetTrue)
+                        .help(about::WHATIS_EXPLAIN),
+                ),
         )
         .subcommand(
             clap::Command::new(cmd::EDIT).about(about::EDIT).arg(
@@ -205,9 +459,79 @@ fn parse_args() -> clap::ArgMatches {
                     .default_value("."),
             ),
         )
+        .subcommand(
+            clap::Command::new(cmd::TAG)
+                .about(about::TAG)
+                .arg(
+                    Arg::new(arg::FILES)
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf))
+                        .help(about::TAG_FILES),
+                )
+                .arg(
+                    Arg::new(arg::TAGS)
+                        .required(true)
+                        .num_args(1..)
+                        .last(true)
+                        .help(about::TAG_TAGS),
+                ),
+        )
+        .subcommand(
+            clap::Command::new(cmd::UNTAG)
+                .about(about::UNTAG)
+                .arg(
+                    Arg::new(arg::FILES)
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf))
+                        .help(about::UNTAG_FILES),
+                )
+                .arg(
+                    Arg::new(arg::TAGS)
+                        .required(true)
+                        .num_args(1..)
+                        .last(true)
+                        .help(about::UNTAG_TAGS),
+                ),
+        )
+        .subcommand(
+            clap::Command::new(cmd::MOVE)
+                .about(about::MOVE)
+                .arg(
+                    Arg::new(arg::SRC)
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help(about::MOVE_SRC),
+                )
+                .arg(
+                    Arg::new(arg::DST)
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help(about::MOVE_DST),
+                ),
+        )
         .subcommand(clap::Command::new(cmd::CLEAN).about(about::CLEAN))
+        .subcommand(clap::Command::new(cmd::FINGERPRINT).about(about::FINGERPRINT))
+        .subcommand(
+            clap::Command::new(cmd::REPAIR).about(about::REPAIR).arg(
+                Arg::new(arg::YES)
+                    .long("yes")
+                    .short('y')
+                    .action(clap::ArgAction::SetTrue)
+                    .help(about::REPAIR_YES),
+            ),
+        )
+        .subcommand(clap::Command::new(cmd::INDEX).about(about::INDEX))
         .subcommand(clap::Command::new(cmd::UNTRACKED).about(about::UNTRACKED))
-        .subcommand(clap::Command::new(cmd::TAGS).about(about::TAGS))
+        .subcommand(
+            clap::Command::new(cmd::TAGS).about(about::TAGS).arg(
+                Arg::new(arg::TREE)
+                    .long("tree")
+                    .action(clap::ArgAction::SetTrue)
+                    .help(about::TAGS_TREE),
+            ),
+        )
         .subcommand(
             clap::Command::new(cmd::BASH_COMPLETE)
                 .arg(Arg::new(arg::BASH_COMPLETE_WORDS).num_args(3)),
@@ -225,7 +549,13 @@ mod cmd {
     pub const CHECK: &str = "check";
     pub const WHATIS: &str = "whatis";
     pub const EDIT: &str = "edit";
+    pub const TAG: &str = "tag";
+    pub const UNTAG: &str = "untag";
+    pub const MOVE: &str = "mv";
     pub const CLEAN: &str = "clean";
+    pub const FINGERPRINT: &str = "fingerprint";
+    pub const REPAIR: &str = "repair";
+    pub const INDEX: &str = "index";
     pub const UNTRACKED: &str = "untracked";
     pub const TAGS: &str = "tags";
     pub const BASH_COMPLETE: &str = "--bash-complete";
@@ -233,38 +563,100 @@ mod cmd {
 
 mod arg {
     pub const FILTER: &str = "filter"; // Query command.
+    pub const STRICT_TAGS: &str = "strict-tags"; // Fail on unknown tags in a query.
     pub const PATH: &str = "path"; // --path flag to run in a different path than cwd.
+    pub const FORMAT: &str = "format"; // --format flag for machine-readable output.
+    pub const PRINT0: &str = "print0"; // Separate paths with NUL.
+    pub const ABSOLUTE: &str = "absolute"; // Print absolute paths.
+    pub const THREADS: &str = "threads"; // Number of threads used to walk directories.
+    pub const NO_IGNORE: &str = "no-ignore"; // Don't skip ignored files.
+    pub const MAX_DEPTH: &str = "max-depth"; // Limit the depth of traversal.
+    pub const FOLLOW_SYMLINKS: &str = "follow-symlinks"; // Follow symlinks.
+    pub const ONE_FILE_SYSTEM: &str = "one-file-system"; // Don't cross mount points.
+    pub const STRICT: &str = "strict"; // Fail on unreadable directories and names.
     pub const SEARCH_STR: &str = "search string";
+    pub const SCORES: &str = "scores"; // Print the scores of search results.
+    pub const FILES: &str = "files"; // Files to tag / untag.
+    pub const TAGS: &str = "tags"; // Tags to add / remove.
+    pub const SRC: &str = "source"; // File to move.
+    pub const DST: &str = "destination"; // Where to move the file.
+    pub const YES: &str = "yes"; // Apply the repairs.
+    pub const TREE: &str = "tree"; // Print the tags as a tree.
+    pub const EXPLAIN: &str = "explain"; // Show the tags implied by rules.
     pub const BASH_COMPLETE_WORDS: &str = "bash-complete-words";
 }
 
 mod about {
+    pub const PRINT0: &str = "Separate the results with NUL characters instead of newlines, and print paths as raw bytes. This is safe to pipe into 'xargs -0', even if the file names contain newlines or are not valid UTF-8. Only valid with the text format.";
+    pub const ABSOLUTE: &str = "Print absolute paths, instead of paths relative to the directory where the traversal starts.";
+    pub const MAX_DEPTH: &str = "Don't descend more than this many levels below the starting directory. 0 only looks at the files in the starting directory.";
+    pub const FOLLOW_SYMLINKS: &str = "Follow symbolic links to files and directories. Links that lead back to a directory containing them are skipped.";
+    pub const ONE_FILE_SYSTEM: &str =
+        "Don't descend into directories on other filesystems than the starting directory.";
+    pub const STRICT: &str = "Stop with an error at the first directory that can't be read or name that isn't valid UTF-8, instead of skipping it and printing a summary of the problems at the end.";
+    pub const NO_IGNORE: &str =
+        "Don't skip the files and directories that match the patterns in .ftagignore files.";
+    pub const THREADS: &str = "Number of threads used to walk the directories and read the .ftag files, in query, check, untracked and interactive. 0 uses one thread per available core. With more than one thread, the results of a query are printed in no particular order.";
+    pub const FORMAT: &str = "Output format. The json, jsonl and csv formats print one record per file, tag or path, and are meant to be consumed by other programs. File records contain the path, all the tags, the tags split by where they come from (explicit, inherited and implicit), and the description.";
     pub const COUNT: &str = "Output the number of tracked files.";
     pub const QUERY: &str = "List all files that match the given query string.";
     pub const QUERY_FILTER: &str = "The query string to compare the files against.";
     pub const QUERY_FILTER_LONG: &str =
-        "The query string must be composed of tags and supported boolean operations:
-& (for and), | (for or) and ! (for not).  An example query
-string is 'foo & bar'. Using this will list all files that have both
-tags 'foo' and 'bar'.  More complex queries can be delimited using
-parentheses. For example: '(foo & bar) | !baz' will list all files
-that either have both 'foo' and 'bar' tags, or don't have the 'baz'
-tag.";
-    pub const SEARCH: &str = "Search all tags and descriptions for the given keywords";
+        "The query string is composed of tags and boolean operators: & (and), | (or),
+^ (xor) and ! (not). The words 'and', 'or', 'xor' and 'not' can be used instead
+of the symbols. NOT binds the tightest, followed by AND, XOR and OR, so
+'a | b & c' means 'a | (b & c)'. Parentheses group expressions. For example:
+'(foo & bar) | !baz' lists all files that either have both 'foo' and 'bar'
+tags, or don't have the 'baz' tag.
+
+Tags in double quotes, such as '\"rock&roll\"', can contain the operators and are
+always matched as tags. A tag followed by '/', such as 'place/', matches the
+files tagged with anything below it in the hierarchy.
+
+Besides tags, a query can contain these atoms:
+  trip-*            Glob, matches any tag that matches the glob.
+  /^20[01]\\d$/      Regular expression, matches any tag it finds a match in.
+  year>=2015        Compares year tags. Also >, <, <= and =.
+  year:2015..2019   Year tags in the range, both ends included.
+  ext:pdf           Files with the extension, ignoring case.
+  name~invoice      Files whose name matches the regular expression.
+  size>10MB         Compares the size of files. Units B, KB, MB, GB and TB.
+  mtime<2020-01-01  Compares the modification time of files with a UTC date.
+  desc:\"words\"      Files whose description contains the words, ignoring case.
+  desc~regex        Files whose description matches the regular expression.";
+    pub const QUERY_STRICT_TAGS: &str = "Fail with an error if the query contains tags that no file has, instead of printing warnings after the results.";
+    pub const SEARCH: &str = "Search all tags and descriptions for the given keywords, and list the matching files best match first.";
     pub const SEARCH_STR: &str = "A string of keywords to search for.";
-    pub const SEARCH_STR_LONG: &str = "Any file that contains any of the keywords in this string in either it's tags or description will included in the output.";
+    pub const SEARCH_STR_LONG: &str = "Any file that contains any of the keywords in this string in either it's tags or description will included in the output. The files are ranked by how well they match the keywords, using BM25. A keyword matches any part of a word, and \"quoted words\" and \"quoted phrases\" match whole words. Keywords prefixed with tag:, desc: or path: are only matched against the tags, description or the path of the file. Keywords prefixed with + are required, and files matching keywords prefixed with - are excluded. For example: 'beach +tag:2019 -desc:\"work trip\"'";
+    pub const SEARCH_SCORES: &str = "Print the score of each file before it's path.";
     pub const INTERACTIVE: &str = "\
 Launch interactive mode in the working directory. Interactive mode loads all the files and tags, and let's you incrementally refine your search criteria inside a TUI. More documentation on the interactive mode can be found here: https://github.com/ranjeethmahankali/ftag/blob/no-table/README.md";
     pub const CHECK: &str = "Recursively traverse directories starting from the working directory and check to see if all the files listed in every .ftag file is exists.";
     pub const CHECK_PATH:&str = "The directory path where to start checking recursively. If ommitted, the workind directory is assumed.";
     pub const WHATIS: &str = "Get the tags and description (if found) of the given file.";
     pub const WHATIS_PATH: &str = "Path of the file to describe.";
+    pub const WHATIS_EXPLAIN: &str = "Also list the tags implied by the rules in the .ftagconfig files, along with the rule that implied each of them. Only valid with the text format.";
     pub const EDIT: &str = "Edit the .ftag file of the given (optional) directory.
 If the environment variable EDITOR is set, it will be used to open the file. If it is not set, ftag can try to guess your default editor, but this is not guaranteed to work. Setting the EDITOR environment variable is recommended.";
     pub const EDIT_PATH: &str = "Path to the directory whose .ftag file you wish to edit. If no path is specified, the current working
 directory is used as default.";
-    pub const CLEAN: &str = "This commands cleans all the tag data. This includes deleting globs that don't match to any files on the disk, and merging globs that share the same tags and description into the same entry.";
+    pub const TAG: &str = "Add tags to the given files, globs or directories. Files and globs are tagged in the .ftag file of their parent directory, and a new entry is created if they're not already listed in it. The existing .ftag file is backed up to .ftagbak before it is modified.";
+    pub const TAG_FILES: &str = "Files, globs or directories to tag.";
+    pub const TAG_TAGS: &str = "Tags to add, listed after '--'.";
+    pub const UNTAG: &str = "Remove tags from the given files, globs or directories. Only the entries that list the given files or globs are modified, so tags inherited from other globs or directories are not removed. The existing .ftag file is backed up to .ftagbak before it is modified.";
+    pub const UNTAG_FILES: &str = "Files, globs or directories to untag.";
+    pub const UNTAG_TAGS: &str = "Tags to remove, listed after '--'.";
+    pub const MOVE: &str = "Move or rename a file, and carry its tags and description along to the .ftag file at the destination. Entries in the source .ftag file that list the file by name are removed. Both .ftag files are backed up to .ftagbak before they are modified.";
+    pub const MOVE_SRC: &str = "The file to move.";
+    pub const MOVE_DST: &str =
+        "The new path of the file, or an existing directory to move the file into.";
+    pub const CLEAN: &str = "This commands cleans all the tag data. This includes deleting globs that don't match to any files on the disk, and merging globs that share the same tags and description into the same entry. The rest of the .ftag file is left untouched.";
+    pub const FINGERPRINT: &str = "Record the size and content hash of every file that is listed by name in a .ftag file, recursively from the current directory. The fingerprints are stored in a .ftaghash file next to each .ftag file, and are used by the repair command to find files that were renamed or moved.";
+    pub const REPAIR: &str = "Find files that are listed in .ftag files but are missing on disk, and look for them among the untracked files using their recorded fingerprints. The proposed renames are printed, and applied to the .ftag files only if --yes is given.";
+    pub const INDEX: &str = "Create or update the .ftagindex file in the current directory. The index stores the tags of every file in the directory tree, and is used instead of walking the tree by query, count, tags and interactive when they are run from this directory. The index is updated incrementally, and only the directories that changed since the last update are loaded again.";
+    pub const REPAIR_YES: &str = "Apply the proposed renames to the .ftag files.";
     pub const UNTRACKED: &str =
         "List all files that are not tracked by ftag, recursively from the current directory.";
     pub const TAGS: &str = "List all tags found by traversing the directories recursively from the current directory. The output list of tags will not contain duplicates.";
+    pub const TAGS_TREE: &str = "Print the hierarchical tags as a tree, with each level indented below its parent. Only valid with the text format.";
 }
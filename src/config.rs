@@ -0,0 +1,328 @@
+use crate::{
+    core::{Error, FTAG_CONFIG_FILE},
+    load::implied_tags,
+};
+use smallvec::SmallVec;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/*
+Config files apply to the directory that contains them, and everything below
+it. Like `.ftag` files, they are made of headers in `[]` brackets, each followed
+by its content. Lines that start with '#' are comments. The `aliases` header is
+followed by one line per tag, listing the canonical name of the tag, followed by
+'=' and the aliases of that tag:
+
+[aliases]
+photo = photos pic picture
+
+The `rules` header is followed by implication rules, one per line. Each rule is
+a tag, followed by '->' and the tags it implies. Rules apply transitively, so
+with the rules below, a file tagged `dog` also has the tags `animal`, `pet` and
+`living-thing`. Rules also apply to files with tags below the tag of the rule
+in the hierarchy of tags, so a file tagged `dog/labrador` also has these tags.
+
+[rules]
+dog -> animal pet
+animal -> living-thing
+
+Config files are looked up in the directory where a command starts, and all the
+directories above it. Aliases declared in deeper config files take precedence
+over the ones declared above them, and rules are combined.
+ */
+
+/// Settings read from the config files that apply to a directory.
+#[derive(Default)]
+pub struct Config {
+    /// Alias -> canonical tag.
+    aliases: HashMap<String, String>,
+    /// Tag -> tags it implies directly.
+    rules: HashMap<String, Vec<String>>,
+}
+
+impl Config {
+    /// Load the config files that apply to `dirpath`.
+    pub fn load(dirpath: &Path) -> Result<Config, Error> {
+        let dirpath = std::fs::canonicalize(dirpath).unwrap_or_else(|_| dirpath.to_path_buf());
+        let mut config = Config::default();
+        let mut files: Vec<PathBuf> = dirpath
+            .ancestors()
+            .map(|dir| dir.join(FTAG_CONFIG_FILE))
+            .filter(|path| path.is_file())
+            .collect();
+        files.reverse(); // Outermost first.
+        for path in files {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|_| Error::CannotReadStoreFile(path.clone()))?;
+            config
+                .parse(&text)
+                .map_err(|message| Error::CannotParseFtagFile(path.clone(), message))?;
+        }
+        config.resolve_aliases();
+        config.resolve_rules()?;
+        Ok(config)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<(), String> {
+        let mut header = None;
+        for (lineno, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                header = match name.trim() {
+                    "aliases" | "rules" => Some(name.trim()),
+                    _ => return Err(format!("Line {}: Unknown header '{}'.", lineno, line)),
+                };
+                continue;
+            }
+            match header {
+                Some("aliases") => {
+                    let (canonical, aliases) = line.split_once('=').ok_or_else(|| {
+                        format!("Line {}: Expected 'tag = alias1 alias2 ...'.", lineno)
+                    })?;
+                    let canonical = canonical.trim();
+                    if canonical.is_empty() || canonical.contains(char::is_whitespace) {
+                        return Err(format!(
+                            "Line {}: Expected a single tag before '='.",
+                            lineno
+                        ));
+                    }
+                    // A tag declared here is no longer an alias of another tag.
+                    self.aliases.remove(canonical);
+                    for alias in aliases.split_whitespace().filter(|a| *a != canonical) {
+                        self.aliases
+                            .insert(alias.to_string(), canonical.to_string());
+                    }
+                }
+                Some("rules") => {
+                    let (tag, implied) = line.split_once("->").ok_or_else(|| {
+                        format!("Line {}: Expected 'tag -> implied1 implied2 ...'.", lineno)
+                    })?;
+                    let tag = tag.trim();
+                    if tag.is_empty() || tag.contains(char::is_whitespace) {
+                        return Err(format!(
+                            "Line {}: Expected a single tag before '->'.",
+                            lineno
+                        ));
+                    }
+                    self.rules
+                        .entry(tag.to_string())
+                        .or_default()
+                        .extend(implied.split_whitespace().map(|t| t.to_string()));
+                }
+                _ => return Err(format!("Line {}: Expected a header.", lineno)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Aliases can refer to tags that were declared as aliases of other tags
+    /// before. Point every alias directly to its canonical tag. This always
+    /// terminates, because an alias is only ever added for a tag that is not
+    /// an alias at that point, so there can't be any cycles.
+    fn resolve_aliases(&mut self) {
+        let mut resolved = HashMap::with_capacity(self.aliases.len());
+        for alias in self.aliases.keys() {
+            let mut canonical = alias;
+            while let Some(next) = self.aliases.get(canonical) {
+                canonical = next;
+            }
+            resolved.insert(alias.clone(), canonical.clone());
+        }
+        self.aliases = resolved;
+    }
+
+    /// Replace the tags in the rules with their canonical names, and make sure
+    /// no tag implies itself through a chain of rules.
+    fn resolve_rules(&mut self) -> Result<(), Error> {
+        let mut rules: HashMap<String, Vec<String>> = HashMap::with_capacity(self.rules.len());
+        for (tag, implied) in self.rules.iter() {
+            let tag = self.canonical(tag);
+            let dst = rules.entry(tag.to_string()).or_default();
+            for t in implied.iter().map(|t| self.canonical(t)) {
+                if t != tag && !dst.iter().any(|d| d == t) {
+                    dst.push(t.to_string());
+                }
+            }
+        }
+        self.rules = rules;
+        // Depth first search for cycles, starting from every tag.
+        let mut done: HashMap<&str, bool> = HashMap::new(); // false while on the stack.
+        for start in self.rules.keys() {
+            let mut stack: Vec<(&str, usize)> = vec![(start, 0)];
+            while let Some((tag, next)) = stack.pop() {
+                if next == 0 {
+                    match done.get(tag) {
+                        Some(true) => continue,
+                        Some(false) => {
+                            let mut cycle: Vec<String> = stack
+                                .iter()
+                                .map(|(t, _)| t.to_string())
+                                .skip_while(|t| t != tag)
+                                .collect();
+                            cycle.push(tag.to_string());
+                            return Err(Error::CyclicRules(cycle));
+                        }
+                        None => {
+                            done.insert(tag, false);
+                        }
+                    }
+                }
+                match self.rules.get(tag).and_then(|implied| implied.get(next)) {
+                    Some(child) => {
+                        stack.push((tag, next + 1));
+                        stack.push((child, 0));
+                    }
+                    None => {
+                        done.insert(tag, true);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get `tag`, followed by all the tags implied by the rules, directly or
+    /// transitively. The rules of the ancestors of every tag are applied too.
+    /// `tag` is expected to be canonical.
+    pub(crate) fn apply_rules<'a>(&'a self, tag: &'a str) -> SmallVec<[&'a str; 4]> {
+        let mut tags: SmallVec<[&'a str; 4]> = SmallVec::new();
+        tags.push(tag);
+        if self.rules.is_empty() {
+            return tags;
+        }
+        let mut i = 0;
+        while let Some(current) = tags.get(i).copied() {
+            for implied in implied_tags(current)
+                .filter_map(|t| self.rules.get(t))
+                .flatten()
+            {
+                if !tags.contains(&implied.as_str()) {
+                    tags.push(implied);
+                }
+            }
+            i += 1;
+        }
+        tags
+    }
+
+    /// Get the canonical name of `tag`, followed by all the tags implied by
+    /// the rules.
+    pub(crate) fn expand<'a>(&'a self, tag: &'a str) -> SmallVec<[&'a str; 4]> {
+        self.apply_rules(self.canonical(tag))
+    }
+
+    /// Explain which tags are implied by the rules for a file with `tags`. Get
+    /// a list of pairs of each implied tag, and the tag whose rule implied it,
+    /// in the order in which the rules are applied. `tags` are expected to be
+    /// canonical.
+    pub fn explain(&self, tags: &[String]) -> Vec<(String, String)> {
+        let mut all: Vec<&str> = tags.iter().map(|t| t.as_str()).collect();
+        let mut derived = Vec::new();
+        let mut i = 0;
+        while let Some(current) = all.get(i).copied() {
+            for (source, implied) in
+                implied_tags(current).filter_map(|t| self.rules.get_key_value(t))
+            {
+                for t in implied {
+                    if !all.contains(&t.as_str()) {
+                        all.push(t);
+                        derived.push((t.clone(), source.clone()));
+                    }
+                }
+            }
+            i += 1;
+        }
+        derived
+    }
+
+    /// Get the canonical name of `tag`.
+    pub fn canonical<'a>(&'a self, tag: &'a str) -> &'a str {
+        match self.aliases.get(tag) {
+            Some(canonical) => canonical,
+            None => tag,
+        }
+    }
+
+    /// Get the sorted aliases of the canonical tag `tag`.
+    pub fn aliases(&self, tag: &str) -> Vec<String> {
+        let mut aliases: Vec<String> = self
+            .aliases
+            .iter()
+            .filter(|(_a, c)| *c == tag)
+            .map(|(a, _c)| a.clone())
+            .collect();
+        aliases.sort_unstable();
+        aliases
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_aliases() {
+        let mut config = Config::default();
+        config
+            .parse("# Comment\n[aliases]\nphoto = photos pic picture\n\nvideo = clip\n")
+            .unwrap();
+        // A deeper config file.
+        config.parse("[aliases]\nimage = photo\n").unwrap();
+        config.resolve_aliases();
+        assert_eq!(config.canonical("pic"), "image");
+        assert_eq!(config.canonical("photo"), "image");
+        assert_eq!(config.canonical("clip"), "video");
+        assert_eq!(config.canonical("beach"), "beach");
+        assert_eq!(
+            config.aliases("image"),
+            ["photo", "photos", "pic", "picture"]
+        );
+        assert!(config.parse("photo = pic\n").is_err()); // No header.
+        assert!(config.parse("[aliases]\nphoto pic\n").is_err());
+        // Swap the canonical tag and its alias.
+        config.parse("[aliases]\npic = image\n").unwrap();
+        config.resolve_aliases();
+        assert_eq!(config.canonical("image"), "pic");
+        assert_eq!(config.canonical("photo"), "pic");
+        assert_eq!(config.canonical("pic"), "pic");
+    }
+
+    #[test]
+    fn t_rules() {
+        let mut config = Config::default();
+        config
+            .parse("[aliases]\ndog = doggo\n[rules]\ndoggo -> animal pet\nanimal -> living-thing\n")
+            .unwrap();
+        config.parse("[rules]\nplace/europe -> travel\n").unwrap();
+        config.resolve_aliases();
+        config.resolve_rules().unwrap();
+        assert_eq!(
+            config.expand("doggo").as_slice(),
+            ["dog", "animal", "pet", "living-thing"]
+        );
+        assert_eq!(
+            config.apply_rules("place/europe/paris").as_slice(),
+            ["place/europe/paris", "travel"]
+        );
+        assert_eq!(config.apply_rules("beach").as_slice(), ["beach"]);
+        assert_eq!(
+            config.explain(&["dog".to_string(), "pet".to_string()]),
+            [
+                ("animal".to_string(), "dog".to_string()),
+                ("living-thing".to_string(), "animal".to_string())
+            ]
+        );
+        config.parse("[rules]\nliving-thing -> dog\n").unwrap();
+        match config.resolve_rules() {
+            Err(Error::CyclicRules(cycle)) => {
+                assert_eq!(cycle.first(), cycle.last());
+                assert_eq!(cycle.len(), 4);
+            }
+            _ => panic!("Expected a cycle"),
+        }
+    }
+}
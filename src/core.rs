@@ -1,21 +1,30 @@
 use crate::{
+    config::Config,
     filter::FilterParseError,
+    fingerprint::move_fingerprint,
+    index::Index,
     load::{
-        get_filename_str, get_ftag_backup_path, get_ftag_path, infer_implicit_tags, DirData,
-        FileLoadingOptions, GlobMatches, Loader, LoaderOptions,
+        get_filename_lossy, get_filename_str, get_ftag_path, implied_tags, infer_implicit_tags,
+        DirData, FileLoadingOptions, GlobMatches, Loader, LoaderOptions, COMMENT_PREFIX,
     },
-    walk::{DirTree, MetaData, VisitedDir},
+    output::{Field, FileRecord, Record},
+    query::UnknownTag,
+    rewrite::{FtagDocument, TagOwner},
+    walk::{is_ftag_file, walk_parallel, Diagnostic, DirTree, MetaData, VisitedDir, WalkOptions},
 };
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
     fmt::Debug,
-    fs::OpenOptions,
-    io,
     path::{Path, PathBuf},
 };
 
 pub(crate) const FTAG_FILE: &str = ".ftag";
 pub(crate) const FTAG_BACKUP_FILE: &str = ".ftagbak";
+pub(crate) const FTAG_HASH_FILE: &str = ".ftaghash";
+pub(crate) const FTAG_INDEX_FILE: &str = ".ftagindex";
+pub(crate) const FTAG_IGNORE_FILE: &str = ".ftagignore";
+pub(crate) const FTAG_CONFIG_FILE: &str = ".ftagconfig";
 
 /// The data related to a glob in an ftag file. This is meant to be used in
 /// error reporting.
@@ -24,9 +33,17 @@ pub struct GlobInfo {
     dirpath: PathBuf, // The store file where the glob was found.
 }
 
+impl Record for GlobInfo {
+    const FIELDS: &'static [&'static str] = &["dir", "glob"];
+
+    fn fields(&self) -> Vec<Field<'_>> {
+        vec![Field::Path(&self.dirpath), Field::Str(&self.glob)]
+    }
+}
+
 pub enum Error {
     TUIFailure(String),
-    GUIFailure(eframe::Error),
+    GUIFailure(String),
     EditCommandFailed(String),
     UnmatchedGlobs(Vec<GlobInfo>),
     InvalidArgs,
@@ -35,8 +52,14 @@ pub enum Error {
     CannotReadStoreFile(PathBuf),
     CannotParseFtagFile(PathBuf, String),
     CannotWriteFile(PathBuf),
+    CannotMoveFile(PathBuf, PathBuf),
+    FileAlreadyExists(PathBuf),
     InvalidFilter(FilterParseError),
+    InvalidTag(String),
     DirectoryTraversalFailed,
+    WalkFailed(Diagnostic),
+    CyclicRules(Vec<String>),
+    UnknownTags(Vec<UnknownTag>),
 }
 
 impl Debug for Error {
@@ -70,7 +93,25 @@ impl Debug for Error {
                 write!(f, "{}", message)
             }
             Self::CannotWriteFile(path) => writeln!(f, "Cannot write to file {}", path.display()),
+            Self::CannotMoveFile(src, dst) => write!(
+                f,
+                "Unable to move '{}' to '{}'.",
+                src.display(),
+                dst.display()
+            ),
+            Self::FileAlreadyExists(path) => write!(f, "'{}' already exists.", path.display()),
             Self::InvalidFilter(err) => write!(f, "Unable to parse filter:\n{:?}", err),
+            Self::InvalidTag(tag) => write!(f, "'{}' is not a valid tag.", tag),
+            Self::CyclicRules(cycle) => {
+                write!(f, "The rules form a cycle: {}", cycle.join(" -> "))
+            }
+            Self::UnknownTags(unknown) => {
+                let lines: Vec<String> = unknown.iter().map(|u| u.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Self::WalkFailed(diagnostic) => {
+                write!(f, "Stopped walking the directories:\n{}", diagnostic)
+            }
             Self::DirectoryTraversalFailed => {
                 write!(f, "Something went wrong when traversing directories.")
             }
@@ -81,11 +122,20 @@ impl Debug for Error {
 /// Recursively check all directories. This will read all .ftag
 /// files, and make sure every listed glob / path matches at least one
 /// file on disk.
-pub fn check(path: PathBuf) -> Result<(), Error> {
-    let mut matcher = GlobMatches::new();
-    let mut missing = Vec::new();
-    let mut dir = DirTree::new(
-        path.clone(),
+pub fn check(path: PathBuf, walk: &WalkOptions) -> Result<(), Error> {
+    let missing = unmatched_globs(path, walk)?;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::UnmatchedGlobs(missing))
+    }
+}
+
+/// Recursively find all globs listed in .ftag files, that don't match any
+/// files on disk. The globs are sorted by directory.
+pub fn unmatched_globs(path: PathBuf, walk: &WalkOptions) -> Result<Vec<GlobInfo>, Error> {
+    let workers = walk_parallel(
+        &path,
         LoaderOptions::new(
             false,
             false,
@@ -94,96 +144,50 @@ pub fn check(path: PathBuf) -> Result<(), Error> {
                 file_desc: false,
             },
         ),
-    )?;
-    while let Some(VisitedDir {
-        rel_dir_path,
-        files,
-        metadata,
-        ..
-    }) = dir.walk()
-    {
-        match metadata {
-            MetaData::FailedToLoad(e) => return Err(e),
-            MetaData::NotFound => continue, // No metadata.
-            MetaData::Ok(DirData { globs, .. }) => {
-                matcher.find_matches(files, globs, true);
-                missing.extend(globs.iter().enumerate().filter_map(|(i, f)| {
-                    if !matcher.is_glob_matched(i) {
-                        Some(GlobInfo {
-                            glob: f.path.to_string(),
-                            dirpath: rel_dir_path.to_path_buf(),
-                        })
-                    } else {
-                        None
-                    }
-                }));
+        walk,
+        (),
+        || (GlobMatches::new(), Vec::new()),
+        |(matcher, missing),
+         _parent,
+         VisitedDir {
+             rel_dir_path,
+             files,
+             metadata,
+             ..
+         }| {
+            match metadata {
+                MetaData::FailedToLoad(e) => return Err(e),
+                MetaData::NotFound => {} // No metadata.
+                MetaData::Ok(DirData { globs, .. }) => {
+                    matcher.find_matches(files, globs, true);
+                    missing.extend(globs.iter().enumerate().filter_map(|(i, f)| {
+                        if !matcher.is_glob_matched(i) {
+                            Some(GlobInfo {
+                                glob: f.path.to_string(),
+                                dirpath: rel_dir_path.to_path_buf(),
+                            })
+                        } else {
+                            None
+                        }
+                    }));
+                }
             }
-        }
-    }
-    if missing.is_empty() {
-        Ok(())
-    } else {
-        Err(Error::UnmatchedGlobs(missing))
-    }
-}
-
-struct FileDataOwned {
-    glob: String,
-    tags: Vec<String>,
-    desc: Option<String>,
-}
-
-struct FileDataMultiple {
-    globs: Vec<String>,
-    tags: Vec<String>,
-    desc: Option<String>,
-}
-
-fn write_globs<T: AsRef<str>>(globs: &[T], w: &mut impl io::Write) -> Result<(), io::Error> {
-    if globs.is_empty() {
-        return Ok(());
-    }
-    writeln!(w, "\n[path]")?;
-    for glob in globs.iter().map(|g| g.as_ref()) {
-        writeln!(w, "{}", glob)?;
-    }
-    Ok(())
-}
-
-fn write_tags<T: AsRef<str>>(tags: &[T], w: &mut impl io::Write) -> Result<(), io::Error> {
-    if tags.is_empty() {
-        return Ok(());
-    }
-    writeln!(w, "[tags]")?;
-    if tags
-        .iter()
-        .try_fold(0usize, |len, tag| -> Result<usize, io::Error> {
-            let tag = tag.as_ref();
-            Ok(if len > 80 {
-                writeln!(w, "{}", tag)?;
-                0usize
-            } else {
-                write!(w, "{} ", tag)?;
-                len + tag.len() + 1
-            })
-        })?
-        > 0
-    {
-        writeln!(w)?;
-    }
-    Ok(())
-}
-
-fn write_desc<T: AsRef<str>>(desc: Option<&T>, w: &mut impl io::Write) -> Result<(), io::Error> {
-    match desc {
-        Some(desc) => writeln!(w, "[desc]\n{}", desc.as_ref()),
-        None => Ok(()),
-    }
+            Ok(())
+        },
+    )?;
+    let mut missing: Vec<GlobInfo> = workers.into_iter().flat_map(|(_m, g)| g).collect();
+    // The globs within a directory are already in the order of the file.
+    missing.sort_by(|a, b| a.dirpath.cmp(&b.dirpath));
+    Ok(missing)
 }
 
-pub fn clean(path: PathBuf) -> Result<(), Error> {
+/// Recursively clean all ftag files. Globs that don't match any files on disk
+/// are removed, along with the entries that are left without any globs. Entries
+/// that share the same tags and description are merged into the first such
+/// entry. Everything else in the files is left untouched.
+pub fn clean(path: PathBuf, walk: &WalkOptions) -> Result<(), Error> {
     let mut matcher = GlobMatches::new();
-    let mut valid: Vec<FileDataOwned> = Vec::new();
+    let mut matched = HashSet::<String>::new();
     let mut dir = DirTree::new(
         path,
         LoaderOptions::new(
@@ -194,6 +198,7 @@ pub fn clean(path: PathBuf) -> Result<(), Error> {
                 file_desc: true,
             },
         ),
+        walk,
     )?;
     while let Some(VisitedDir {
         abs_dir_path,
@@ -208,98 +213,382 @@ pub fn clean(path: PathBuf) -> Result<(), Error> {
             MetaData::FailedToLoad(e) => return Err(e),
         };
         matcher.find_matches(files, &data.globs, true);
-        valid.clear();
-        valid.extend(data.globs.iter().enumerate().filter_map(|(gi, g)| {
+        matched.clear();
+        matched.extend(data.globs.iter().enumerate().filter_map(|(gi, g)| {
             if matcher.is_glob_matched(gi) {
-                let mut tags: Vec<String> = g
-                    .tags(&data.alltags)
-                    .iter()
+                Some(g.path.to_string())
+            } else {
+                None
+            }
+        }));
+        let mut doc = FtagDocument::load(abs_dir_path)?;
+        // Entries are identified by their sorted tags and their description.
+        let mut seen = HashMap::<(Vec<String>, Option<String>), usize>::new();
+        let mut merged = BTreeMap::<usize, Vec<String>>::new();
+        for ei in 0..doc.num_entries() {
+            let (valid, invalid): (Vec<_>, Vec<_>) = doc
+                .globs(ei)
+                .map(|(span, g)| (span, g.to_string()))
+                .partition(|(_, g)| matched.contains(g));
+            if valid.is_empty() {
+                doc.remove_entry(ei);
+                continue;
+            }
+            let key = {
+                let mut tags: Vec<String> = doc
+                    .tags(TagOwner::Entry(ei))
                     .map(|t| t.to_string())
                     .collect();
                 tags.sort_unstable();
                 tags.dedup();
-                Some(FileDataOwned {
-                    glob: g.path.to_string(),
-                    tags,
-                    desc: g.desc.map(|d| d.to_string()),
-                })
-            } else {
-                None
+                (tags, doc.desc(TagOwner::Entry(ei)).map(|d| d.to_string()))
+            };
+            match seen.get(&key) {
+                Some(&first) => {
+                    let globs = merged.entry(first).or_default();
+                    for (_, glob) in valid {
+                        if !globs.contains(&glob) && !doc.globs(first).any(|(_, g)| g == glob) {
+                            globs.push(glob);
+                        }
+                    }
+                    doc.remove_entry(ei);
+                }
+                None => {
+                    seen.insert(key, ei);
+                    for (span, _) in invalid {
+                        doc.remove_glob(span);
+                    }
+                }
             }
-        }));
-        // This should group files that share the same tags and desc
-        valid.sort_unstable_by(|a, b| match a.tags.cmp(&b.tags) {
-            std::cmp::Ordering::Less => std::cmp::Ordering::Less,
-            std::cmp::Ordering::Equal => a.desc.cmp(&b.desc),
-            std::cmp::Ordering::Greater => std::cmp::Ordering::Greater,
-        });
-
-        let fpath = match get_ftag_path::<true>(abs_dir_path) {
-            Some(fpath) => fpath,
-            None => return Err(Error::CannotReadStoreFile(abs_dir_path.to_path_buf())),
+        }
+        for (ei, globs) in merged {
+            doc.add_globs(ei, &globs);
+        }
+        doc.apply()?;
+        doc.save()?;
+    }
+    dir.finish()?;
+    Ok(())
+}
+
+/// Whether tags are added to, or removed from files.
+#[derive(Clone, Copy)]
+pub enum TagEdit {
+    Add,
+    Remove,
+}
+
+/// The entry in an ftag file that a tag edit applies to.
+enum EditTarget {
+    Dir,
+    Glob(String),
+}
+
+/// Add or remove `tags` to / from the `glob` listed in `entry`. If the entry
+/// lists other globs, `glob` is split out into a new entry so that the other
+/// globs are not affected.
+fn edit_entry_tags(
+    doc: &mut FtagDocument,
+    entry: usize,
+    glob: &str,
+    tags: &[String],
+    edit: TagEdit,
+) -> Result<(), Error> {
+    let owner = TagOwner::Entry(entry);
+    let current: Vec<String> = doc.tags(owner).map(|t| t.to_string()).collect();
+    let changed = match edit {
+        TagEdit::Add => tags.iter().any(|t| !current.contains(t)),
+        TagEdit::Remove => current.iter().any(|t| tags.contains(t)),
+    };
+    if !changed {
+        return Ok(());
+    }
+    let shared = doc.globs(entry).any(|(_, g)| g != glob);
+    match shared {
+        false => match edit {
+            TagEdit::Add => doc.add_tags(owner, tags),
+            TagEdit::Remove => doc.remove_tags(owner, tags),
+        },
+        true => {
+            let newtags: Vec<String> = match edit {
+                TagEdit::Add => current
+                    .iter()
+                    .chain(tags.iter().filter(|t| !current.contains(t)))
+                    .cloned()
+                    .collect(),
+                TagEdit::Remove => current.into_iter().filter(|t| !tags.contains(t)).collect(),
+            };
+            let desc = doc.desc(owner).map(|d| d.to_string());
+            for span in doc
+                .globs(entry)
+                .filter_map(|(span, g)| if g == glob { Some(span) } else { None })
+                .collect::<Vec<_>>()
+            {
+                doc.remove_glob(span);
+            }
+            doc.insert_entry(Some(entry), &[glob], &newtags, desc.as_deref());
+        }
+    }
+    doc.apply()
+}
+
+/// Split the path of a file into the canonical path of its parent directory
+/// and its name. The file itself doesn't need to exist.
+fn split_file_path(path: &Path) -> Result<(PathBuf, &str), Error> {
+    let parent = match path.parent() {
+        Some(p) if p.as_os_str().is_empty() => Path::new("."),
+        Some(p) => p,
+        None => return Err(Error::InvalidPath(path.to_path_buf())),
+    };
+    let name = match path.file_name().map(|n| n.to_str()) {
+        Some(Some(name)) => name,
+        _ => return Err(Error::InvalidPath(path.to_path_buf())),
+    };
+    Ok((
+        parent
+            .canonicalize()
+            .map_err(|_| Error::InvalidPath(path.to_path_buf()))?,
+        name,
+    ))
+}
+
+/// Check if `tag` can be written to an ftag file, and read back as the same
+/// tag. Tags can't contain whitespace or headers, and can't start comments.
+fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && !tag.starts_with(COMMENT_PREFIX)
+        && !tag.contains(|c: char| c.is_whitespace() || c == '[')
+}
+
+/// Add or remove `tags` to / from each of the `paths`. A path can be a file, a
+/// glob or a directory. Files and globs are edited in the ftag file of their
+/// parent directory, and a new entry is created for them if one doesn't
+/// already exist. Directories are edited in their own ftag file.
+pub fn edit_tags(paths: &[PathBuf], tags: &[String], edit: TagEdit) -> Result<(), Error> {
+    if let Some(tag) = tags.iter().find(|t| !is_valid_tag(t)) {
+        return Err(Error::InvalidTag(tag.clone()));
+    }
+    // Group the edits by the directory whose ftag file they modify.
+    let mut edits = BTreeMap::<PathBuf, Vec<EditTarget>>::new();
+    for path in paths {
+        let (dirpath, target) = if path.is_dir() {
+            (
+                path.canonicalize()
+                    .map_err(|_| Error::InvalidPath(path.clone()))?,
+                EditTarget::Dir,
+            )
+        } else {
+            let (parent, name) = split_file_path(path)?;
+            (parent, EditTarget::Glob(name.to_string()))
         };
-        // Backup existing data.
-        std::fs::copy(&fpath, get_ftag_backup_path(abs_dir_path))
-            .map_err(|_| Error::CannotWriteFile(fpath.clone()))?;
-        let mut writer = io::BufWriter::new(
-            OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(&fpath)
-                .map_err(|_| Error::CannotWriteFile(fpath.clone()))?,
-        );
-        // Write directory data.
-        write_tags(data.tags(), &mut writer).map_err(|_| Error::CannotWriteFile(fpath.clone()))?;
-        write_desc(data.desc.as_ref(), &mut writer)
-            .map_err(|_| Error::CannotWriteFile(fpath.clone()))?;
-        // Write out the file data in groups that share the same tags and description.
-        if let Some(last) = valid
-            .drain(..)
-            .try_fold(
-                None,
-                |current: Option<FileDataMultiple>,
-                 file|
-                 -> Result<Option<FileDataMultiple>, io::Error> {
-                    Ok(match current {
-                        Some(mut current)
-                            if current.tags == file.tags && current.desc == file.desc =>
-                        {
-                            current.globs.push(file.glob);
-                            Some(current)
-                        }
-                        Some(current) => {
-                            write_globs(&current.globs, &mut writer)?;
-                            write_tags(&current.tags, &mut writer)?;
-                            write_desc(current.desc.as_ref(), &mut writer)?;
-                            Some(FileDataMultiple {
-                                globs: vec![file.glob],
-                                tags: file.tags,
-                                desc: file.desc,
-                            })
-                        }
-                        None => Some(FileDataMultiple {
-                            globs: vec![file.glob],
-                            tags: file.tags,
-                            desc: file.desc,
-                        }),
-                    })
+        edits.entry(dirpath).or_default().push(target);
+    }
+    for (dirpath, targets) in edits {
+        let mut doc = FtagDocument::load(&dirpath)?;
+        for target in targets {
+            match (target, edit) {
+                (EditTarget::Dir, TagEdit::Add) => doc.add_tags(TagOwner::Dir, tags),
+                (EditTarget::Dir, TagEdit::Remove) => doc.remove_tags(TagOwner::Dir, tags),
+                (EditTarget::Glob(glob), TagEdit::Add) => match doc.find_entry(&glob, 0) {
+                    Some(ei) => edit_entry_tags(&mut doc, ei, &glob, tags, edit)?,
+                    None => doc.insert_entry(None, &[&glob], tags, None),
                 },
-            )
-            .map_err(|_| Error::CannotWriteFile(fpath.clone()))?
-        {
-            // This is the last entry.
-            write_globs(&last.globs, &mut writer)
-                .map_err(|_| Error::CannotWriteFile(fpath.clone()))?;
-            write_tags(&last.tags, &mut writer)
-                .map_err(|_| Error::CannotWriteFile(fpath.clone()))?;
-            write_desc(last.desc.as_ref(), &mut writer)
-                .map_err(|_| Error::CannotWriteFile(fpath.clone()))?;
+                (EditTarget::Glob(glob), TagEdit::Remove) => {
+                    // The same glob can be listed in more than one entry.
+                    let mut from = 0;
+                    while let Some(ei) = doc.find_entry(&glob, from) {
+                        edit_entry_tags(&mut doc, ei, &glob, tags, edit)?;
+                        from = ei + 1;
+                    }
+                }
+            }
+            doc.apply()?;
         }
+        doc.save()?;
     }
     Ok(())
 }
 
+/// Move or rename the file at `src` to `dst`, and carry its metadata along. If
+/// `dst` is an existing directory, the file is moved into it. The explicit
+/// tags and description of the file, from all the entries that match it, are
+/// written to a new entry in the ftag file at the destination. The globs in the
+/// source ftag file that list the file by name, or won't match any other files
+/// after the move, are removed. The fingerprint of the file, if one was recorded,
+/// is moved along as well. Directories carry their own ftag files with them, so
+/// they are simply moved. The ftag files are written before the file is moved,
+/// and restored if writing them or moving the file fails.
+pub fn move_file(src: &Path, dst: &Path) -> Result<(), Error> {
+    if !src.exists() {
+        return Err(Error::InvalidPath(src.to_path_buf()));
+    }
+    let dst = if dst.is_dir() {
+        match src.file_name() {
+            Some(name) => dst.join(name),
+            None => return Err(Error::InvalidPath(src.to_path_buf())),
+        }
+    } else {
+        dst.to_path_buf()
+    };
+    if dst.exists() {
+        return Err(Error::FileAlreadyExists(dst));
+    }
+    if src.is_dir() {
+        return move_path(src, &dst);
+    }
+    let (srcdir, srcname) = split_file_path(src)?;
+    let (dstdir, dstname) = split_file_path(&dst)?;
+    let (srcdoc, dstdoc) = move_metadata(&srcdir, srcname, &dstdir, dstname)?;
+    let mut snapshots = vec![Snapshot::take(&srcdir)];
+    if dstdoc.is_some() {
+        snapshots.push(Snapshot::take(&dstdir));
+    }
+    let result = srcdoc
+        .save()
+        .and_then(|_| dstdoc.map_or(Ok(()), |doc| doc.save()))
+        .and_then(|_| move_path(src, &dst));
+    if let Err(e) = result {
+        for snapshot in snapshots {
+            snapshot.restore();
+        }
+        return Err(e);
+    }
+    move_fingerprint(&srcdir, srcname, &dstdir, dstname)
+}
+
+/// Contents of the ftag file of a directory before it is modified, to undo the
+/// changes if a later step fails.
+struct Snapshot {
+    filepath: PathBuf,
+    text: Option<String>,
+}
+
+impl Snapshot {
+    fn take(dirpath: &Path) -> Self {
+        let filepath = dirpath.join(FTAG_FILE);
+        Snapshot {
+            text: std::fs::read_to_string(&filepath).ok(),
+            filepath,
+        }
+    }
+
+    /// Best effort, because this only runs after something else failed.
+    fn restore(self) {
+        let _ = match self.text {
+            Some(text) => std::fs::write(&self.filepath, text),
+            None => std::fs::remove_file(&self.filepath),
+        };
+    }
+}
+
+/// Move the metadata of the file named `srcname` in `srcdir` to the file named
+/// `dstname` in `dstdir`, as described in `move_file`. The modified ftag files
+/// are returned without saving them. The second ftag file is only returned if
+/// the destination is in a different directory.
+pub(crate) fn move_metadata(
+    srcdir: &Path,
+    srcname: &str,
+    dstdir: &Path,
+    dstname: &str,
+) -> Result<(FtagDocument, Option<FtagDocument>), Error> {
+    use fast_glob::glob_match;
+    let mut srcdoc = FtagDocument::load(srcdir)?;
+    let mut tags: Vec<String> = Vec::new();
+    let mut descs: Vec<String> = Vec::new();
+    let mut tracked = false;
+    // Other files in the source directory, loaded only if needed.
+    let mut siblings: Option<Vec<OsString>> = None;
+    for ei in 0..srcdoc.num_entries() {
+        let mut stale = Vec::new();
+        let mut matched = false;
+        let mut others = false;
+        for (span, glob) in srcdoc.globs(ei) {
+            if glob == srcname {
+                stale.push(span);
+                matched = true;
+            } else if glob_match(glob, srcname) {
+                matched = true;
+                // Remove the glob if it won't match anything after the move.
+                let siblings = siblings.get_or_insert_with(|| {
+                    std::fs::read_dir(srcdir)
+                        .map(|entries| {
+                            entries
+                                .flatten()
+                                .filter(|e| e.path().is_file())
+                                .map(|e| e.file_name())
+                                .filter(|name| name != srcname && !is_ftag_file(name))
+                                .collect()
+                        })
+                        .unwrap_or_default()
+                });
+                if siblings
+                    .iter()
+                    .any(|name| glob_match(glob.as_bytes(), name.as_encoded_bytes()))
+                {
+                    others = true;
+                } else {
+                    stale.push(span);
+                }
+            } else {
+                others = true;
+            }
+        }
+        if !matched {
+            continue;
+        }
+        tracked = true;
+        let owner = TagOwner::Entry(ei);
+        for tag in srcdoc.tags(owner) {
+            if !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+        if let Some(desc) = srcdoc.desc(owner).filter(|d| !d.is_empty()) {
+            descs.push(desc.to_string());
+        }
+        if !others {
+            srcdoc.remove_entry(ei);
+        } else {
+            for span in stale {
+                srcdoc.remove_glob(span);
+            }
+        }
+    }
+    srcdoc.apply()?;
+    let desc = if descs.is_empty() {
+        None
+    } else {
+        Some(descs.join("\n"))
+    };
+    let dstdoc = if !tracked {
+        None // Nothing to carry over.
+    } else if srcdir == dstdir {
+        srcdoc.insert_entry(None, &[dstname], &tags, desc.as_deref());
+        srcdoc.apply()?;
+        None
+    } else {
+        let mut doc = FtagDocument::load(dstdir)?;
+        doc.insert_entry(None, &[dstname], &tags, desc.as_deref());
+        doc.apply()?;
+        Some(doc)
+    };
+    Ok((srcdoc, dstdoc))
+}
+
+/// Move `src` to `dst` on disk. If they're on different file systems, files are
+/// copied and the original is deleted.
+fn move_path(src: &Path, dst: &Path) -> Result<(), Error> {
+    std::fs::rename(src, dst)
+        .or_else(|e| {
+            if src.is_file() {
+                std::fs::copy(src, dst).and_then(|_| std::fs::remove_file(src))
+            } else {
+                Err(e)
+            }
+        })
+        .map_err(|_| Error::CannotMoveFile(src.to_path_buf(), dst.to_path_buf()))
+}
+
 /// Get a description string from the tags and description of a file.
 fn full_description(tags: Vec<String>, desc: String) -> String {
     let tagstr = {
@@ -320,18 +609,35 @@ fn full_description(tags: Vec<String>, desc: String) -> String {
 
 /// Get the description of a file or a directory.
 pub fn what_is(path: &Path) -> Result<String, Error> {
+    let record = describe(path)?;
+    Ok(full_description(
+        record.tags,
+        record.desc.unwrap_or_default(),
+    ))
+}
+
+/// Get the tags of a file or a directory that are implied by the rules in the
+/// config files, as pairs of each implied tag and the tag whose rule implied it.
+pub fn implied_by_rules(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let record = describe(path)?;
+    Ok(Config::load(path)?.explain(&record.tags))
+}
+
+/// Get the tags and the description of a file or a directory.
+pub fn describe(path: &Path) -> Result<FileRecord, Error> {
+    let config = Config::load(path)?;
     if path.is_file() {
-        what_is_file(path)
+        describe_file(path, &config)
     } else if path.is_dir() {
-        what_is_dir(path)
+        describe_dir(path, &config)
     } else {
         Err(Error::InvalidPath(path.to_path_buf()))
     }
 }
 
-/// Get a full description of the file that includes the tags and the
-/// description of said file.
-fn what_is_file(path: &Path) -> Result<String, Error> {
+/// Get the tags and the description of a file. The tags of the directory, and
+/// the tags inferred from the name of the directory, are reported as inherited.
+fn describe_file(path: &Path, config: &Config) -> Result<FileRecord, Error> {
     use fast_glob::glob_match;
     let mut loader = Loader::new(LoaderOptions::new(
         true,
@@ -346,13 +652,16 @@ fn what_is_file(path: &Path) -> Result<String, Error> {
         None => return Err(Error::InvalidPath(path.to_path_buf())),
     };
     let mut outdesc = data.desc.unwrap_or("").to_string();
-    let mut outtags = data
+    let mut inherited = data
         .tags()
         .iter()
-        .map(|t| t.to_string())
+        .map(|t| config.canonical(t).to_string())
         .collect::<Vec<_>>();
     if let Some(parent) = path.parent() {
-        outtags.extend(infer_implicit_tags(get_filename_str(parent)?).map(|t| t.to_string()));
+        inherited.extend(
+            infer_implicit_tags(get_filename_str(parent)?)
+                .map(|t| config.canonical(&t.to_string()).to_string()),
+        );
     }
     let filenamestr = match path.file_name() {
         Some(fname) => match fname.to_str() {
@@ -361,49 +670,64 @@ fn what_is_file(path: &Path) -> Result<String, Error> {
         },
         None => return Err(Error::InvalidPath(path.to_path_buf())),
     };
+    let mut explicit = Vec::new();
+    let mut implicit = Vec::new();
     for g in data.globs.iter() {
         if glob_match(g.path, filenamestr) {
-            outtags.extend(
+            explicit.extend(
                 g.tags(&data.alltags)
                     .iter()
-                    .map(|t| t.to_string())
-                    .chain(infer_implicit_tags(filenamestr).map(|t| t.to_string())),
+                    .map(|t| config.canonical(t).to_string()),
             );
+            if implicit.is_empty() {
+                implicit.extend(
+                    infer_implicit_tags(filenamestr)
+                        .map(|t| config.canonical(&t.to_string()).to_string()),
+                );
+            }
             if let Some(fdesc) = g.desc {
                 outdesc = format!("{}\n{}", fdesc, outdesc);
             }
         }
     }
     // Remove duplicate tags.
-    outtags.sort_unstable();
-    outtags.dedup();
-    Ok(full_description(outtags, outdesc))
+    explicit.sort_unstable();
+    explicit.dedup();
+    Ok(FileRecord::new(
+        path.to_path_buf(),
+        explicit,
+        inherited,
+        implicit,
+        Some(outdesc),
+    ))
 }
 
-/// Get the full description of a directory that includes it's tags and
-/// description.
-fn what_is_dir(path: &Path) -> Result<String, Error> {
+/// Get the tags and the description of a directory.
+fn describe_dir(path: &Path, config: &Config) -> Result<FileRecord, Error> {
     let mut loader = Loader::new(LoaderOptions::new(true, true, FileLoadingOptions::Skip));
     let data = match get_ftag_path::<true>(path) {
         Some(storepath) => loader.load(&storepath)?,
         None => return Err(Error::InvalidPath(path.to_path_buf())),
     };
-    let desc = data.desc.unwrap_or("").to_string();
-    let tags = data
-        .tags()
-        .iter()
-        .map(|t| t.to_string())
-        .chain(infer_implicit_tags(get_filename_str(path)?).map(|t| t.to_string()))
-        .collect::<Vec<_>>();
-    Ok(full_description(tags, desc))
+    Ok(FileRecord::new(
+        path.to_path_buf(),
+        data.tags()
+            .iter()
+            .map(|t| config.canonical(t).to_string())
+            .collect(),
+        Vec::new(),
+        infer_implicit_tags(get_filename_str(path)?)
+            .map(|t| config.canonical(&t.to_string()).to_string())
+            .collect(),
+        data.desc.map(|d| d.to_string()),
+    ))
 }
 
 /// Recursively traverse the directories starting from `root` and
-/// return all files that are not tracked.
-pub fn untracked_files(root: PathBuf) -> Result<Vec<PathBuf>, Error> {
-    let mut matcher = GlobMatches::new();
-    let mut dir = DirTree::new(
-        root.clone(),
+/// return all files that are not tracked, sorted by path.
+pub fn untracked_files(root: PathBuf, walk: &WalkOptions) -> Result<Vec<PathBuf>, Error> {
+    let workers = walk_parallel(
+        &root,
         LoaderOptions::new(
             false,
             false,
@@ -412,48 +736,70 @@ pub fn untracked_files(root: PathBuf) -> Result<Vec<PathBuf>, Error> {
                 file_desc: false,
             },
         ),
-    )?;
-    let mut untracked = Vec::new();
-    while let Some(VisitedDir {
-        rel_dir_path,
-        files,
-        metadata,
-        ..
-    }) = dir.walk()
-    {
-        match metadata {
-            MetaData::FailedToLoad(e) => return Err(e),
-            MetaData::Ok(DirData { globs, .. }) => {
-                matcher.find_matches(files, globs, false);
-                untracked.extend(files.iter().enumerate().filter_map(|(fi, file)| {
-                    // Skip the files that matched with at least one glob. Copy the
-                    // paths of files that didn't match with any glob.
-                    match matcher.is_file_matched(fi) {
-                        true => None,
-                        false => {
-                            let mut relpath = rel_dir_path.to_path_buf();
-                            relpath.push(file.name());
-                            Some(relpath)
+        walk,
+        (),
+        || (GlobMatches::new(), Vec::new()),
+        |(matcher, untracked),
+         _parent,
+         VisitedDir {
+             rel_dir_path,
+             files,
+             metadata,
+             ..
+         }| {
+            match metadata {
+                MetaData::FailedToLoad(e) => return Err(e),
+                MetaData::Ok(DirData { globs, .. }) => {
+                    matcher.find_matches(files, globs, false);
+                    untracked.extend(files.iter().enumerate().filter_map(|(fi, file)| {
+                        // Skip the files that matched with at least one glob. Copy the
+                        // paths of files that didn't match with any glob.
+                        match matcher.is_file_matched(fi) {
+                            true => None,
+                            false => {
+                                let mut relpath = rel_dir_path.to_path_buf();
+                                relpath.push(file.name());
+                                Some(relpath)
+                            }
                         }
-                    }
-                }));
-            }
-            MetaData::NotFound => {
-                // Metadata doesn't exist so everything is untracked.
-                untracked.extend(files.iter().map(|ch| {
-                    let mut relpath = rel_dir_path.to_path_buf();
-                    relpath.push(ch.name());
-                    relpath
-                }));
+                    }));
+                }
+                MetaData::NotFound => {
+                    // Metadata doesn't exist so everything is untracked.
+                    untracked.extend(files.iter().map(|ch| {
+                        let mut relpath = rel_dir_path.to_path_buf();
+                        relpath.push(ch.name());
+                        relpath
+                    }));
+                }
             }
-        }
-    }
+            Ok(())
+        },
+    )?;
+    let mut untracked: Vec<PathBuf> = workers.into_iter().flat_map(|(_m, u)| u).collect();
+    untracked.sort_unstable();
     Ok(untracked)
 }
 
-/// Recursively traverse the directories from `path` and get all tags.
-pub fn get_all_tags(path: PathBuf) -> Result<impl Iterator<Item = String>, Error> {
+/// Recursively traverse the directories from `path` and get all tags. Aliases
+/// are replaced with the canonical names of the tags.
+pub fn get_all_tags(
+    path: PathBuf,
+    walk: &WalkOptions,
+) -> Result<impl Iterator<Item = String>, Error> {
+    let config = Config::load(&path)?;
     let mut alltags = HashSet::new();
+    if let Some(index) = Index::open(&path, walk)? {
+        for dir in index.dirs.iter().filter(|d| d.has_metadata) {
+            alltags.extend(
+                dir.alltags
+                    .iter()
+                    .chain(dir.files.iter().flat_map(|f| f.tags.iter()))
+                    .map(|id| config.canonical(index.tag(*id)).to_string()),
+            );
+        }
+        return Ok(alltags.into_iter());
+    }
     let mut matcher = GlobMatches::new();
     let mut dir = DirTree::new(
         path,
@@ -465,6 +811,7 @@ pub fn get_all_tags(path: PathBuf) -> Result<impl Iterator<Item = String>, Error
                 file_desc: false,
             },
         ),
+        walk,
     )?;
     while let Some(VisitedDir {
         rel_dir_path,
@@ -481,7 +828,7 @@ pub fn get_all_tags(path: PathBuf) -> Result<impl Iterator<Item = String>, Error
                 ..
             }) => {
                 alltags.extend(tags.iter().map(|t| t.to_string()).chain(
-                    infer_implicit_tags(get_filename_str(rel_dir_path)?).map(|t| t.to_string()),
+                    infer_implicit_tags(&get_filename_lossy(rel_dir_path)).map(|t| t.to_string()),
                 ));
                 matcher.find_matches(files, globs, false);
                 alltags.extend(
@@ -489,67 +836,211 @@ pub fn get_all_tags(path: PathBuf) -> Result<impl Iterator<Item = String>, Error
                         .iter()
                         .enumerate()
                         .filter(|(fi, _f)| matcher.is_file_matched(*fi))
-                        .filter_map(|(_fi, f)| f.name().to_str())
-                        .flat_map(|t| infer_implicit_tags(t).map(|t| t.to_string())),
+                        .map(|(_fi, f)| f.name().to_string_lossy())
+                        .flat_map(|t| {
+                            infer_implicit_tags(&t)
+                                .map(|t| t.to_string())
+                                .collect::<Vec<_>>()
+                        }),
                 );
             }
             MetaData::NotFound => continue, // No metadata, just pass on the tags to the next dir.
         }
     }
+    dir.finish()?;
+    let alltags: HashSet<String> = alltags
+        .iter()
+        .map(|t| config.canonical(t).to_string())
+        .collect();
     Ok(alltags.into_iter())
 }
 
-fn match_desc(words: &[String], tags: &[&str], desc: Option<&str>) -> bool {
-    tags.iter().any(|tag| {
-        // Check if tag matches
-        let lower = tag.to_lowercase();
-        words
+/// Arrange hierarchical tags into a tree. Returns the nodes of the tree in
+/// depth-first order, as pairs of the depth of the node and its tag. Ancestors
+/// that are not used as tags on their own are included, so that every node has
+/// a parent.
+pub fn tag_tree(tags: &[String]) -> Vec<(usize, &str)> {
+    let mut sorted: Vec<(Vec<&str>, &str)> = tags
+        .iter()
+        .map(|t| (t.split('/').collect(), t.as_str()))
+        .collect();
+    // Sorting the levels instead of the strings keeps the children of a tag
+    // right after it, even if another tag starts with the same characters.
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut nodes = Vec::new();
+    let mut prev: &[&str] = &[];
+    for (levels, tag) in sorted.iter() {
+        let common = prev
             .iter()
-            .any(|word| lower.matches(word).next().is_some())
-    }) || match desc {
-        // Check if description matches.
-        Some(desc) => {
-            let desc = desc.to_lowercase();
-            words.iter().any(|word| desc.matches(word).next().is_some())
-        }
-        None => false,
+            .zip(levels.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        nodes.extend(implied_tags(tag).enumerate().skip(common));
+        prev = levels;
     }
+    nodes
 }
 
-pub fn search(path: PathBuf, needle: &str) -> Result<(), Error> {
-    let words: Vec<_> = needle
-        .trim()
-        .split(|c: char| !c.is_alphanumeric())
-        .map(|word| word.trim().to_lowercase())
-        .collect();
-    let mut dir = DirTree::new(
-        path,
-        LoaderOptions::new(
-            true,
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Create an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Get the globs in the ftag file of `dir`, with their tags.
+    fn load_globs(dir: &Path) -> Vec<(String, Vec<String>)> {
+        let mut loader = Loader::new(LoaderOptions::new(
             true,
+            false,
             FileLoadingOptions::Load {
                 file_tags: true,
-                file_desc: true,
+                file_desc: false,
             },
-        ),
-    )?;
-    while let Some(VisitedDir { metadata, .. }) = dir.walk() {
-        match metadata {
-            MetaData::FailedToLoad(e) => return Err(e),
-            MetaData::Ok(data) => {
-                let dirmatch = match_desc(&words, data.tags(), data.desc);
-                for filepath in data.globs.iter().filter_map(|g| {
-                    if dirmatch || match_desc(&words, g.tags(&data.alltags), g.desc) {
-                        Some(g.path)
-                    } else {
-                        None
-                    }
-                }) {
-                    println!("{}", filepath);
-                }
+        ));
+        match get_ftag_path::<true>(dir) {
+            Some(path) => {
+                let data = loader.load(&path).unwrap();
+                data.globs
+                    .iter()
+                    .map(|g| {
+                        let tags = g.tags(&data.alltags).iter().map(|t| t.to_string());
+                        (g.path.to_string(), tags.collect())
+                    })
+                    .collect()
             }
-            MetaData::NotFound => continue, // No metadata, just keep going.
+            None => Vec::new(),
         }
     }
-    Ok(())
+
+    #[test]
+    fn t_move_file() {
+        let root = test_dir("ftag_t_move_file");
+        let sub = root.join("sub");
+        let album = root.join("album");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::create_dir_all(&album).unwrap();
+        for name in ["a.jpg", "b.jpg", "notes.txt"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        std::fs::write(
+            root.join(FTAG_FILE),
+            "[path]\na.jpg\n[tags]\nbeach\n[path]\n*t*\n[tags]\nnotes\n",
+        )
+        .unwrap();
+        std::fs::write(sub.join(FTAG_FILE), "[tags]\nsub\n").unwrap();
+        std::fs::write(album.join(FTAG_FILE), "[tags]\nalbum\n").unwrap();
+        let globs = |pairs: &[(&str, &[&str])]| -> Vec<(String, Vec<String>)> {
+            pairs
+                .iter()
+                .map(|(g, tags)| (g.to_string(), tags.iter().map(|t| t.to_string()).collect()))
+                .collect()
+        };
+        // Rename within the same directory.
+        move_file(&root.join("a.jpg"), &root.join("c.jpg")).unwrap();
+        assert!(root.join("c.jpg").is_file() && !root.join("a.jpg").exists());
+        assert_eq!(
+            load_globs(&root),
+            globs(&[("*t*", &["notes"]), ("c.jpg", &["beach"])])
+        );
+        // Move into another directory with its own ftag file. The glob only
+        // matched the moved file, because ftag's own files don't count.
+        move_file(&root.join("notes.txt"), &sub).unwrap();
+        assert!(sub.join("notes.txt").is_file());
+        assert_eq!(load_globs(&root), globs(&[("c.jpg", &["beach"])]));
+        assert_eq!(load_globs(&sub), globs(&[("notes.txt", &["notes"])]));
+        // Untracked files are moved without touching the ftag files.
+        move_file(&root.join("b.jpg"), &sub.join("d.jpg")).unwrap();
+        assert!(sub.join("d.jpg").is_file());
+        assert_eq!(load_globs(&sub), globs(&[("notes.txt", &["notes"])]));
+        // Directories carry their ftag files with them.
+        move_file(&album, &sub.join("album2")).unwrap();
+        assert!(!album.exists());
+        assert!(sub.join("album2").join(FTAG_FILE).is_file());
+        // Existing destinations are not overwritten.
+        assert!(matches!(
+            move_file(&root.join("c.jpg"), &sub.join("d.jpg")),
+            Err(Error::FileAlreadyExists(_))
+        ));
+        assert_eq!(load_globs(&root), globs(&[("c.jpg", &["beach"])]));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn t_edit_tags() {
+        let root = test_dir("ftag_t_edit_tags");
+        for name in ["a.jpg", "b.jpg"] {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+        std::fs::write(
+            root.join(FTAG_FILE),
+            "[tags]\nphotos\n\n[path]\na.jpg\n[tags]\nsunset\n",
+        )
+        .unwrap();
+        let tags = |tags: &[&str]| -> Vec<String> { tags.iter().map(|t| t.to_string()).collect() };
+        let entries = |entries: &[(&str, &[&str])]| -> Vec<(String, Vec<String>)> {
+            entries
+                .iter()
+                .map(|(glob, t)| (glob.to_string(), tags(t)))
+                .collect()
+        };
+        // Existing entry, and a new entry.
+        let files = [root.join("a.jpg"), root.join("b.jpg")];
+        edit_tags(&files, &tags(&["beach", "sunset"]), TagEdit::Add).unwrap();
+        assert_eq!(
+            load_globs(&root),
+            entries(&[
+                ("a.jpg", &["sunset", "beach"]),
+                ("b.jpg", &["beach", "sunset"])
+            ])
+        );
+        assert!(root.join(FTAG_BACKUP_FILE).exists());
+        // Untag down to an empty entry, which keeps the file tracked.
+        edit_tags(&files[1..], &tags(&["beach", "sunset"]), TagEdit::Remove).unwrap();
+        edit_tags(&files[..1], &tags(&["beach"]), TagEdit::Remove).unwrap();
+        assert_eq!(
+            load_globs(&root),
+            entries(&[("a.jpg", &["sunset"]), ("b.jpg", &[])])
+        );
+        // Directories are edited in their own ftag file.
+        edit_tags(std::slice::from_ref(&root), &tags(&["album"]), TagEdit::Add).unwrap();
+        let mut loader = Loader::new(LoaderOptions::new(true, false, FileLoadingOptions::Skip));
+        let data = loader.load(&root.join(FTAG_FILE)).unwrap();
+        assert_eq!(data.tags(), ["photos", "album"]);
+        assert!(matches!(
+            edit_tags(&files, &tags(&["a b"]), TagEdit::Add),
+            Err(Error::InvalidTag(_))
+        ));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn t_invalid_tags() {
+        let root = test_dir("ftag_t_invalid_tags");
+        let file = root.join("photo.jpg");
+        std::fs::write(&file, "").unwrap();
+        let files = std::slice::from_ref(&file);
+        edit_tags(files, &["todo#1".to_string()], TagEdit::Add).unwrap();
+        for tag in ["#todo", "a b", "[tags]", ""] {
+            assert!(
+                matches!(
+                    edit_tags(files, &[tag.to_string()], TagEdit::Add),
+                    Err(Error::InvalidTag(_))
+                ),
+                "{}",
+                tag
+            );
+        }
+        assert_eq!(
+            load_globs(&root),
+            [("photo.jpg".to_string(), vec!["todo#1".to_string()])]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
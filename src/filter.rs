@@ -1,29 +1,381 @@
-use std::fmt::{Debug, Display};
+use fast_glob::glob_match;
+use regex_lite::Regex;
+use std::{
+    fmt::{Debug, Display},
+    iter::Peekable,
+    ops::{Range, RangeInclusive},
+    path::Path,
+    time::UNIX_EPOCH,
+};
 
-pub enum FilterParseError {
+/// What went wrong while parsing a filter.
+pub enum FilterErrorKind {
     EmptyQuery,
     MalformedParens,
     ExpectedBinaryOperator,
     UnexpectedBinaryOperator(String),
     EndOfTokens,
+    InvalidRegex(String),
+    InvalidPredicate(String),
+    UnterminatedQuote,
+}
+
+impl Debug for FilterErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterErrorKind::EmptyQuery => write!(f, "The filter string is empty."),
+            FilterErrorKind::MalformedParens => write!(f, "Parentheses are unbalanced."),
+            FilterErrorKind::ExpectedBinaryOperator => write!(f, "A binary operator is missing."),
+            FilterErrorKind::UnexpectedBinaryOperator(t) => write!(f, "'{}' was not expected.", t),
+            FilterErrorKind::EndOfTokens => write!(f, "Unexpected end of tokens."),
+            FilterErrorKind::InvalidRegex(message) => write!(f, "Invalid regex: {}", message),
+            FilterErrorKind::InvalidPredicate(word) => {
+                write!(f, "'{}' is not a valid file predicate.", word)
+            }
+            FilterErrorKind::UnterminatedQuote => write!(f, "A closing quote is missing."),
+        }
+    }
+}
+
+/// Error from parsing a filter, with the byte span of the offending token in
+/// the filter string.
+pub struct FilterParseError {
+    pub kind: FilterErrorKind,
+    pub span: Range<usize>,
+    input: String,
+}
+
+impl FilterParseError {
+    fn new(kind: FilterErrorKind, span: Range<usize>, input: &str) -> Self {
+        FilterParseError {
+            kind,
+            span,
+            input: input.to_string(),
+        }
+    }
+
+    /// Render the filter string, with a line of carets under the offending
+    /// token.
+    pub fn render(&self) -> String {
+        let col = self.input[..self.span.start].chars().count();
+        let width = self.input[self.span.clone()].chars().count().max(1);
+        format!("{}\n{}{}", self.input, " ".repeat(col), "^".repeat(width))
+    }
 }
 
 impl Debug for FilterParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}\n{}", self.kind, self.render())
+    }
+}
+
+/// Pattern that matches many tags. Words in a filter that contain glob wildcards,
+/// such as `trip-*`, are glob patterns. Words wrapped in slashes, such as
+/// `/^20[01]\d$/`, are regular expressions. Comparisons of years, such as
+/// `year>=2015` or `year:2015..2019`, match year tags.
+pub enum TagPattern {
+    Glob(String),
+    Regex(Regex),
+    Years(RangeInclusive<u16>),
+}
+
+impl TagPattern {
+    /// Get the pattern represented by a word of a filter, if it is one.
+    pub fn parse(word: &str) -> Option<TagPattern> {
+        if let Some(years) = word.strip_prefix("year").and_then(parse_years) {
+            return Some(TagPattern::Years(years));
+        }
+        match regex_body(word) {
+            Some(body) => Regex::new(&body).ok().map(TagPattern::Regex),
+            None if word.contains(['*', '?', '[']) => Some(TagPattern::Glob(word.to_string())),
+            None => None,
+        }
+    }
+
+    pub fn is_match(&self, tag: &str) -> bool {
+        match self {
+            TagPattern::Glob(glob) => glob_match(glob, tag),
+            TagPattern::Regex(regex) => regex.is_match(tag),
+            TagPattern::Years(years) => parse_year(tag).is_some_and(|y| years.contains(&y)),
+        }
+    }
+
+    /// Check if the pattern matches the year tag `year`.
+    pub fn is_year_match(&self, year: u16) -> bool {
+        match self {
+            TagPattern::Years(years) => years.contains(&year),
+            TagPattern::Glob(_) | TagPattern::Regex(_) => self.is_match(&year.to_string()),
+        }
+    }
+}
+
+/// Get the year represented by a tag. Years are made of 4 digits, the same
+/// way they are inferred from the names of files.
+fn parse_year(tag: &str) -> Option<u16> {
+    match tag.len() == 4 && tag.bytes().all(|b| b.is_ascii_digit()) {
+        true => tag.parse().ok(),
+        false => None,
+    }
+}
+
+/// Parse the comparison that follows `year` in a filter, as an inclusive range
+/// of years.
+fn parse_years(cmp: &str) -> Option<RangeInclusive<u16>> {
+    if let Some(range) = cmp.strip_prefix(':') {
+        return match range.split_once("..") {
+            Some((first, last)) => Some(parse_year(first)?..=parse_year(last)?),
+            None => parse_year(range).map(|y| y..=y),
+        };
+    }
+    let (op, year) = split_cmp(cmp)?;
+    let year = parse_year(year)? as i64;
+    let range = cmp_range(op, year, year)?;
+    if *range.end() < 0 {
+        return None;
+    }
+    let clamp = |y: i64| y.clamp(0, u16::MAX as i64) as u16;
+    Some(clamp(*range.start())..=clamp(*range.end()))
+}
+
+/// Split a comparison such as `>=10MB` into the operator and the value.
+fn split_cmp(cmp: &str) -> Option<(&str, &str)> {
+    match cmp.get(..2) {
+        Some(op @ (">=" | "<=")) => Some((op, &cmp[2..])),
+        _ => match cmp.split_at_checked(1)? {
+            (op @ (">" | "<" | "="), value) => Some((op, value)),
+            _ => None,
+        },
+    }
+}
+
+/// Get the range of values that satisfy the comparison `op`, with a value that
+/// spans `first..=last`. For example, a date spans all the seconds of the day.
+fn cmp_range(op: &str, first: i64, last: i64) -> Option<RangeInclusive<i64>> {
+    match op {
+        ">=" => Some(first..=i64::MAX),
+        "<=" => Some(i64::MIN..=last),
+        ">" => Some(last.checked_add(1)?..=i64::MAX),
+        "<" => Some(i64::MIN..=first.checked_sub(1)?),
+        "=" => Some(first..=last),
+        _ => None,
+    }
+}
+
+/// Parse a size such as `10MB`. Units are powers of 1024 bytes.
+fn parse_size(size: &str) -> Option<i64> {
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let scale: i64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    Some((number * scale as f64) as i64)
+}
+
+/// Parse a date in the format `YYYY-MM-DD`, as the range of seconds since the
+/// unix epoch that spans the whole day in UTC.
+fn parse_date(date: &str) -> Option<(i64, i64)> {
+    let mut parts = date.splitn(3, '-');
+    let mut next = |len: usize| {
+        parts
+            .next()
+            .filter(|p| p.len() == len && p.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|p| p.parse::<i64>().ok())
+    };
+    let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let ndays = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=ndays).contains(&day) {
+        return None;
+    }
+    // Count the days since the epoch, with years starting in March, so that
+    // the leap day is at the end of the year.
+    let (y, m) = match month > 2 {
+        true => (year, month - 3),
+        false => (year - 1, month + 9),
+    };
+    let days = y * 365 + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719468;
+    let start = days * 86400;
+    Some((start, start + 86399))
+}
+
+/// Attributes of a file that can only be known by reading its metadata.
+#[derive(Clone, Copy)]
+pub struct FileStat {
+    size: i64,
+    /// Modification time, in seconds since the unix epoch.
+    mtime: i64,
+}
+
+impl FileStat {
+    pub fn read(path: &Path) -> Option<FileStat> {
+        let meta = std::fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?;
+        Some(FileStat {
+            size: meta.len() as i64,
+            mtime: match mtime.duration_since(UNIX_EPOCH) {
+                Ok(d) => d.as_secs() as i64,
+                Err(e) => -(e.duration().as_secs() as i64),
+            },
+        })
+    }
+}
+
+/// Predicate on the attributes of a file rather than its tags, such as
+/// `ext:pdf`, `size>10MB`, `mtime<2020-01-01` or `name~invoice`.
+pub enum FilePredicate {
+    /// Extension, in lower case.
+    Ext(String),
+    /// Regular expression that matches the name of the file.
+    Name(Regex),
+    /// Range of sizes in bytes.
+    Size(RangeInclusive<i64>),
+    /// Range of modification times, in seconds since the unix epoch.
+    Mtime(RangeInclusive<i64>),
+}
+
+impl FilePredicate {
+    /// Get the predicate represented by a word of a filter, if it is one. Words
+    /// that look like predicates but can't be parsed are errors.
+    pub fn parse(word: &str) -> Result<Option<FilePredicate>, FilterErrorKind> {
+        let invalid = || FilterErrorKind::InvalidPredicate(word.to_string());
+        if let Some(ext) = word.strip_prefix("ext:") {
+            let ext = ext.strip_prefix('.').unwrap_or(ext);
+            return match ext.is_empty() {
+                true => Err(invalid()),
+                false => Ok(Some(FilePredicate::Ext(ext.to_lowercase()))),
+            };
+        }
+        if let Some(name) = word.strip_prefix("name~") {
+            let body = regex_body(name).unwrap_or_else(|| name.to_string());
+            if body.is_empty() {
+                return Err(invalid());
+            }
+            return Regex::new(&body)
+                .map(|r| Some(FilePredicate::Name(r)))
+                .map_err(|e| FilterErrorKind::InvalidRegex(e.to_string()));
+        }
+        if let Some((op, size)) = word.strip_prefix("size").and_then(split_cmp) {
+            let size = parse_size(size).ok_or_else(invalid)?;
+            return Ok(Some(FilePredicate::Size(
+                cmp_range(op, size, size).ok_or_else(invalid)?,
+            )));
+        }
+        if let Some((op, date)) = word.strip_prefix("mtime").and_then(split_cmp) {
+            let (first, last) = parse_date(date).ok_or_else(invalid)?;
+            return Ok(Some(FilePredicate::Mtime(
+                cmp_range(op, first, last).ok_or_else(invalid)?,
+            )));
+        }
+        Ok(None)
+    }
+
+    /// Check if the file called `name` satisfies the predicate. The metadata
+    /// of the file is only read with `stat` if the predicate needs it.
+    pub fn is_match(&self, name: &str, stat: impl FnOnce() -> Option<FileStat>) -> bool {
         match self {
-            FilterParseError::EmptyQuery => write!(f, "The filter string is empty."),
-            FilterParseError::MalformedParens => write!(f, "Parentheses are unbalanced."),
-            FilterParseError::ExpectedBinaryOperator => write!(f, "A binary operator is missing."),
-            FilterParseError::UnexpectedBinaryOperator(t) => write!(f, "'{}' was not expected.", t),
-            FilterParseError::EndOfTokens => write!(f, "Unexpected end of tokens."),
+            FilePredicate::Ext(ext) => Path::new(name)
+                .extension()
+                .is_some_and(|e| e.to_string_lossy().to_lowercase() == *ext),
+            FilePredicate::Name(regex) => regex.is_match(name),
+            FilePredicate::Size(range) => stat().is_some_and(|s| range.contains(&s.size)),
+            FilePredicate::Mtime(range) => stat().is_some_and(|s| range.contains(&s.mtime)),
         }
     }
 }
 
+/// Predicate on the description of a file. `desc:"some words"` matches the
+/// descriptions that contain the words, ignoring case, and `desc~regex` matches
+/// the descriptions that contain a match of the regular expression.
+pub enum DescPredicate {
+    /// Words, in lower case.
+    Words(String),
+    Regex(Regex),
+}
+
+impl DescPredicate {
+    /// Get the predicate represented by a word of a filter, if it is one. Words
+    /// that look like predicates but can't be parsed are errors.
+    pub fn parse(word: &str) -> Result<Option<DescPredicate>, FilterErrorKind> {
+        let invalid = || FilterErrorKind::InvalidPredicate(word.to_string());
+        if let Some(words) = word.strip_prefix("desc:") {
+            let words = match words.strip_prefix('"') {
+                Some(quoted) => quoted.strip_suffix('"').ok_or_else(invalid)?,
+                None => words,
+            };
+            return match words.trim().is_empty() {
+                true => Err(invalid()),
+                false => Ok(Some(DescPredicate::Words(words.to_lowercase()))),
+            };
+        }
+        if let Some(regex) = word.strip_prefix("desc~") {
+            let body = regex_body(regex).unwrap_or_else(|| regex.to_string());
+            if body.is_empty() {
+                return Err(invalid());
+            }
+            return Regex::new(&body)
+                .map(|r| Some(DescPredicate::Regex(r)))
+                .map_err(|e| FilterErrorKind::InvalidRegex(e.to_string()));
+        }
+        Ok(None)
+    }
+
+    pub fn is_match(&self, desc: &str) -> bool {
+        match self {
+            DescPredicate::Words(words) => desc.to_lowercase().contains(words.as_str()),
+            DescPredicate::Regex(regex) => regex.is_match(desc),
+        }
+    }
+}
+
+/// If `word` is wrapped in slashes, get the regular expression between them.
+/// Slashes inside the regular expression are escaped with a backslash.
+fn regex_body(word: &str) -> Option<String> {
+    let body = word.strip_prefix('/')?.strip_suffix('/')?;
+    (!body.is_empty()).then(|| body.replace("\\/", "/"))
+}
+
+/// Get the length of the regular expression wrapped in slashes at the start of
+/// `input`, including the slashes, if there is one.
+fn regex_len(input: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in input.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '/' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Words that stand for the constant filters, so that the text of a filter
+/// can be parsed again.
+const FALSE_WORD: &str = "NOT_A_TAG";
+const TRUE_WORD: &str = "ALL_TAGS";
+
+#[derive(PartialEq)]
 pub enum Filter {
     Tag(usize),
+    /// A pattern as it was written, and the indices of the tags it matches.
+    Match(String, Vec<usize>),
     And(Box<Filter>, Box<Filter>),
     Or(Box<Filter>, Box<Filter>),
+    Xor(Box<Filter>, Box<Filter>),
     Not(Box<Filter>),
     FalseTag, // always false.
     TrueTag,  // Always true.
@@ -36,8 +388,10 @@ where
 {
     match filter {
         Tag(ti) => checker(*ti),
+        Match(_, tags) => tags.iter().any(|ti| checker(*ti)),
         And(lhs, rhs) => eval_impl(lhs, checker) && eval_impl(rhs, checker),
         Or(lhs, rhs) => eval_impl(lhs, checker) || eval_impl(rhs, checker),
+        Xor(lhs, rhs) => eval_impl(lhs, checker) != eval_impl(rhs, checker),
         Not(input) => !eval_impl(input, checker),
         FalseTag => false,
         TrueTag => true,
@@ -54,7 +408,14 @@ impl Filter {
 
     fn maybe_parens(parent: &Filter, child: &Filter, childstr: String) -> String {
         match (child, parent) {
-            (Tag(_), _) | (Not(_), _) | (And(_, _), And(_, _)) | (Or(_, _), Or(_, _)) => childstr,
+            (Tag(_), _)
+            | (Match(_, _), _)
+            | (FalseTag, _)
+            | (TrueTag, _)
+            | (Not(_), _)
+            | (And(_, _), And(_, _))
+            | (Or(_, _), Or(_, _))
+            | (Xor(_, _), Xor(_, _)) => childstr,
             _ => format!("({})", childstr),
         }
     }
@@ -72,6 +433,7 @@ impl Filter {
     {
         match self {
             Tag(i) => tagnames[*i].to_string(),
+            Match(pattern, _) => pattern.clone(),
             And(lhs, rhs) => format!(
                 "{} & {}",
                 Self::maybe_parens(self, lhs, lhs.text(tagnames)),
@@ -82,12 +444,39 @@ impl Filter {
                 Self::maybe_parens(self, lhs, lhs.text(tagnames)),
                 Self::maybe_parens(self, rhs, rhs.text(tagnames))
             ),
+            Xor(lhs, rhs) => format!(
+                "{} ^ {}",
+                Self::maybe_parens(self, lhs, lhs.text(tagnames)),
+                Self::maybe_parens(self, rhs, rhs.text(tagnames))
+            ),
             Not(filter) => format!(
                 "!{}",
                 Self::maybe_parens(self, filter, filter.text(tagnames))
             ),
-            FalseTag => String::from("NOT_A_TAG"),
-            TrueTag => String::from("ALL_TAGS"),
+            FalseTag => String::from(FALSE_WORD),
+            TrueTag => String::from(TRUE_WORD),
+        }
+    }
+
+    /// Get an equivalent filter that is cheaper to evaluate and shorter to
+    /// print. Constants are folded, negations are pushed down to the tags
+    /// using De Morgan's laws, chains of AND and OR are flattened, and
+    /// repeated or absorbed terms are removed, such as `b` in `a | b | a & b`.
+    /// The terms keep the order in which they were written.
+    pub fn simplify(self) -> Filter {
+        match self {
+            Tag(_) | Match(_, _) | FalseTag | TrueTag => self,
+            Not(inner) => negate(inner.simplify()),
+            And(_, _) => simplify_chain(self, true),
+            Or(_, _) => simplify_chain(self, false),
+            Xor(lhs, rhs) => match (lhs.simplify(), rhs.simplify()) {
+                (FalseTag, other) | (other, FalseTag) => other,
+                (TrueTag, other) | (other, TrueTag) => negate(other),
+                (Not(lhs), Not(rhs)) => Xor(lhs, rhs),
+                (lhs, rhs) if lhs == rhs => FalseTag,
+                (lhs, rhs) if is_complement(&lhs, &rhs) => TrueTag,
+                (lhs, rhs) => Xor(Box::new(lhs), Box::new(rhs)),
+            },
         }
     }
 }
@@ -96,6 +485,7 @@ impl Display for Filter {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Tag(tag) => write!(f, "{}", tag),
+            Match(pattern, _) => write!(f, "{}", pattern),
             And(lhs, rhs) => write!(
                 f,
                 "{} & {}",
@@ -108,6 +498,12 @@ impl Display for Filter {
                 Self::maybe_parens(self, lhs, lhs.to_string()),
                 Self::maybe_parens(self, rhs, rhs.to_string()),
             ),
+            Xor(lhs, rhs) => write!(
+                f,
+                "{} ^ {}",
+                Self::maybe_parens(self, lhs, lhs.to_string()),
+                Self::maybe_parens(self, rhs, rhs.to_string()),
+            ),
             Not(filter) => write!(
                 f,
                 "!{}",
@@ -122,106 +518,194 @@ impl Display for Filter {
 enum Token {
     And,
     Or,
+    Xor,
     Not,
     Parsed(Filter),
 }
 
-impl Display for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Token::And => write!(f, "&"),
-            Token::Or => write!(f, "|"),
-            Token::Not => write!(f, "!"),
-            Token::Parsed(p) => write!(f, "{}", p),
-        }
-    }
-}
-
 /// Parse filter from a string. The tagmaker is used to create tag-data from
-/// strings corresponding to the tags.
+/// strings corresponding to the tags. Every token is kept together with its
+/// byte span in the input, to report errors.
 fn parse_filter<F>(input: &str, tagmaker: &mut F) -> Result<Filter, FilterParseError>
 where
     F: FnMut(&str) -> Filter,
 {
+    let error = |kind, span| FilterParseError::new(kind, span, input);
     if input.is_empty() {
-        return Err(FilterParseError::EmptyQuery);
+        return Err(error(FilterErrorKind::EmptyQuery, 0..0));
     }
-    let mut stack: Vec<Token> = Vec::new();
-    let mut parens: Vec<usize> = Vec::new();
+    let mut stack: Vec<(Token, Range<usize>)> = Vec::new();
+    // Positions of the open parentheses in the stack, and in the input.
+    let mut parens: Vec<(usize, usize)> = Vec::new();
     let mut begin: usize = 0;
     let mut end = 0;
+    let mut skip_until = 0;
     for (i, c) in input.char_indices() {
         end = i;
+        if i < skip_until {
+            continue; // Inside a regular expression or quotes.
+        }
         match c {
+            '"' if i == begin || input[begin..i].ends_with(':') => {
+                // Quoted words are not split.
+                match input[(i + 1)..].find('"') {
+                    Some(len) => skip_until = i + len + 2,
+                    None => return Err(error(FilterErrorKind::UnterminatedQuote, i..input.len())),
+                }
+            }
+            '/' if i == begin || input[begin..i].ends_with('~') => {
+                // Regular expressions can contain the characters used by
+                // operators, so they are not split.
+                if let Some(len) = regex_len(&input[i..]) {
+                    let word = &input[i..(i + len)];
+                    if let Some(body) = regex_body(word) {
+                        Regex::new(&body).map_err(|e| {
+                            error(FilterErrorKind::InvalidRegex(e.to_string()), i..(i + len))
+                        })?;
+                    }
+                    skip_until = i + len;
+                }
+            }
             '(' => {
-                parens.push(stack.len());
+                parens.push((stack.len(), i));
                 begin = i + 1;
                 continue;
             }
             ')' => {
-                push_tag(input, begin, i, &mut stack, tagmaker);
+                push_tag(input, begin, i, &mut stack, tagmaker)?;
                 begin = i + 1;
-                let last = parens.pop().ok_or(FilterParseError::MalformedParens)?;
-                if last >= stack.len() - 1 {
-                    continue;
+                let (last, open) = parens
+                    .pop()
+                    .ok_or_else(|| error(FilterErrorKind::MalformedParens, i..(i + 1)))?;
+                let span = open..(i + 1);
+                match stack.len() - last {
+                    0 => return Err(error(FilterErrorKind::EndOfTokens, i..(i + 1))),
+                    1 => {
+                        // A single token only needs its span extended.
+                        if let Some(top) = stack.last_mut() {
+                            top.1 = span;
+                        }
+                    }
+                    _ => {
+                        let filter = parse_tokens(stack.drain(last..), i, input)?;
+                        stack.push((Token::Parsed(filter), span));
+                    }
                 }
-                let filter = parse_tokens(stack.drain(last..))?;
-                stack.truncate(last);
-                stack.push(Token::Parsed(filter));
             }
-            '!' => {
-                push_tag(input, begin, i, &mut stack, tagmaker);
+            '!' | '&' | '|' | '^' => {
+                push_tag(input, begin, i, &mut stack, tagmaker)?;
                 begin = i + 1;
-                stack.push(Token::Not);
-            }
-            '&' => {
-                push_tag(input, begin, i, &mut stack, tagmaker);
-                begin = i + 1;
-                stack.push(Token::And);
-            }
-            '|' => {
-                push_tag(input, begin, i, &mut stack, tagmaker);
-                begin = i + 1;
-                stack.push(Token::Or);
+                stack.push((
+                    match c {
+                        '!' => Token::Not,
+                        '&' => Token::And,
+                        '|' => Token::Or,
+                        _ => Token::Xor,
+                    },
+                    i..(i + 1),
+                ));
             }
             _ if c.is_whitespace() => {
-                push_tag(input, begin, i, &mut stack, tagmaker);
+                push_tag(input, begin, i, &mut stack, tagmaker)?;
                 begin = i + 1;
             }
             _ => {}
         };
     }
-    if !parens.is_empty() {
-        return Err(FilterParseError::MalformedParens);
+    if let Some((_, open)) = parens.last() {
+        return Err(error(FilterErrorKind::MalformedParens, *open..(*open + 1)));
     }
-    push_tag(input, begin, end + 1, &mut stack, tagmaker);
-    parse_tokens(stack.into_iter())
+    push_tag(input, begin, end + 1, &mut stack, tagmaker)?;
+    parse_tokens(stack.into_iter(), input.len(), input)
 }
 
-/// Reduce the iterator of tokens into a filter.
-fn parse_tokens<I: Iterator<Item = Token>>(mut iter: I) -> Result<Filter, FilterParseError> {
-    let mut filter = next_filter(&mut iter)?;
-    while let Some(t) = iter.next() {
-        filter = match t {
-            Token::And => Filter::And(Box::new(filter), Box::new(next_filter(&mut iter)?)),
-            Token::Or => Filter::Or(Box::new(filter), Box::new(next_filter(&mut iter)?)),
-            Token::Not | Token::Parsed(_) => return Err(FilterParseError::ExpectedBinaryOperator),
-        };
+/// Reduce the iterator of tokens into a filter. NOT binds the tightest,
+/// followed by AND, XOR and OR. Operators of the same precedence are applied
+/// from left to right. `end` is the position in the input after the last
+/// token.
+fn parse_tokens<I>(iter: I, end: usize, input: &str) -> Result<Filter, FilterParseError>
+where
+    I: Iterator<Item = (Token, Range<usize>)>,
+{
+    let mut tokens = Tokens {
+        iter: iter.peekable(),
+        end,
+        input,
+    };
+    let filter = tokens.parse_or()?;
+    match tokens.iter.next() {
+        Some((Token::Not | Token::Parsed(_), span)) => Err(FilterParseError::new(
+            FilterErrorKind::ExpectedBinaryOperator,
+            span,
+            input,
+        )),
+        Some((_, span)) => Err(FilterParseError::new(
+            FilterErrorKind::UnexpectedBinaryOperator(input[span.clone()].to_string()),
+            span,
+            input,
+        )),
+        None => Ok(filter),
     }
-    Ok(filter)
 }
 
-/// Get the next filter from a list of tokens.
-fn next_filter<I: Iterator<Item = Token>>(iter: &mut I) -> Result<Filter, FilterParseError> {
-    match iter.next() {
-        Some(t) => match t {
-            Token::And | Token::Or => {
-                Err(FilterParseError::UnexpectedBinaryOperator(t.to_string()))
-            }
-            Token::Not => Ok(not_filter(next_filter(iter)?)),
-            Token::Parsed(filter) => Ok(filter),
-        },
-        None => Err(FilterParseError::EndOfTokens),
+/// Tokens being reduced into a filter.
+struct Tokens<'a, I: Iterator<Item = (Token, Range<usize>)>> {
+    iter: Peekable<I>,
+    end: usize,
+    input: &'a str,
+}
+
+impl<I: Iterator<Item = (Token, Range<usize>)>> Tokens<'_, I> {
+    fn parse_or(&mut self) -> Result<Filter, FilterParseError> {
+        let mut filter = self.parse_xor()?;
+        while self.iter.next_if(|(t, _)| matches!(t, Token::Or)).is_some() {
+            filter = Filter::Or(Box::new(filter), Box::new(self.parse_xor()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_xor(&mut self) -> Result<Filter, FilterParseError> {
+        let mut filter = self.parse_and()?;
+        while self
+            .iter
+            .next_if(|(t, _)| matches!(t, Token::Xor))
+            .is_some()
+        {
+            filter = Filter::Xor(Box::new(filter), Box::new(self.parse_and()?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, FilterParseError> {
+        let mut filter = self.next_filter()?;
+        while self
+            .iter
+            .next_if(|(t, _)| matches!(t, Token::And))
+            .is_some()
+        {
+            filter = Filter::And(Box::new(filter), Box::new(self.next_filter()?));
+        }
+        Ok(filter)
+    }
+
+    /// Get the next filter from the tokens.
+    fn next_filter(&mut self) -> Result<Filter, FilterParseError> {
+        match self.iter.next() {
+            Some((t, span)) => match t {
+                Token::And | Token::Or | Token::Xor => Err(FilterParseError::new(
+                    FilterErrorKind::UnexpectedBinaryOperator(self.input[span.clone()].to_string()),
+                    span,
+                    self.input,
+                )),
+                Token::Not => Ok(not_filter(self.next_filter()?)),
+                Token::Parsed(filter) => Ok(filter),
+            },
+            None => Err(FilterParseError::new(
+                FilterErrorKind::EndOfTokens,
+                self.end..self.end,
+                self.input,
+            )),
+        }
     }
 }
 
@@ -230,22 +714,146 @@ fn next_filter<I: Iterator<Item = Token>>(iter: &mut I) -> Result<Filter, Filter
 /// `!!something` into `something`.
 fn not_filter(filter: Filter) -> Filter {
     match filter {
-        Tag(_) | And(_, _) | Or(_, _) => Filter::Not(Box::new(filter)),
+        Tag(_) | Match(_, _) | And(_, _) | Or(_, _) | Xor(_, _) => Filter::Not(Box::new(filter)),
         Not(inner) => *inner,
         FalseTag => TrueTag,
         TrueTag => FalseTag,
     }
 }
 
+/// Negate a simplified filter, and push the negation down to the tags.
+fn negate(filter: Filter) -> Filter {
+    match filter {
+        And(lhs, rhs) => Or(Box::new(Not(lhs)), Box::new(Not(rhs))).simplify(),
+        Or(lhs, rhs) => And(Box::new(Not(lhs)), Box::new(Not(rhs))).simplify(),
+        Xor(lhs, rhs) => Xor(Box::new(Not(lhs)), rhs).simplify(),
+        _ => not_filter(filter),
+    }
+}
+
+fn is_complement(a: &Filter, b: &Filter) -> bool {
+    matches!(a, Not(inner) if **inner == *b) || matches!(b, Not(inner) if **inner == *a)
+}
+
+/// Push the operands of the chain of AND (or OR, if `is_and` is false)
+/// filters into `out`.
+fn flatten(filter: Filter, is_and: bool, out: &mut Vec<Filter>) {
+    match (filter, is_and) {
+        (And(lhs, rhs), true) | (Or(lhs, rhs), false) => {
+            flatten(*lhs, is_and, out);
+            flatten(*rhs, is_and, out);
+        }
+        (filter, _) => out.push(filter),
+    }
+}
+
+/// Get the operands of the chain of AND (or OR, if `is_and` is false)
+/// filters, without taking ownership.
+fn chain_terms(filter: &Filter, is_and: bool) -> Vec<&Filter> {
+    match (filter, is_and) {
+        (And(lhs, rhs), true) | (Or(lhs, rhs), false) => {
+            let mut terms = chain_terms(lhs, is_and);
+            terms.extend(chain_terms(rhs, is_and));
+            terms
+        }
+        (filter, _) => vec![filter],
+    }
+}
+
+/// Simplify a chain of AND (or OR, if `is_and` is false) filters.
+fn simplify_chain(filter: Filter, is_and: bool) -> Filter {
+    let (identity, absorbing) = match is_and {
+        true => (TrueTag, FalseTag),
+        false => (FalseTag, TrueTag),
+    };
+    let mut operands = Vec::new();
+    flatten(filter, is_and, &mut operands);
+    let mut terms: Vec<Filter> = Vec::with_capacity(operands.len());
+    for operand in operands {
+        // Simplifying an operand can produce another chain of the same kind.
+        let mut flat = Vec::new();
+        flatten(operand.simplify(), is_and, &mut flat);
+        for term in flat {
+            if term == absorbing {
+                return absorbing;
+            } else if term != identity && !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+    }
+    if terms
+        .iter()
+        .any(|a| terms.iter().any(|b| is_complement(a, b)))
+    {
+        return absorbing; // Such as `a & !a`.
+    }
+    // Absorption: `a | a & b` is `a`, and `a & (a | b)` is `a`. A term is
+    // dropped if the operands of another term are a subset of its own
+    // operands. Of two terms with the same operands, the first one is kept.
+    let inner: Vec<Vec<&Filter>> = terms.iter().map(|t| chain_terms(t, !is_and)).collect();
+    let subset = |i: usize, j: usize| inner[i].iter().all(|t| inner[j].contains(t));
+    let keep: Vec<bool> = (0..terms.len())
+        .map(|i| !(0..terms.len()).any(|j| j != i && subset(j, i) && (j < i || !subset(i, j))))
+        .collect();
+    terms
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(term, keep)| keep.then_some(term))
+        .reduce(|lhs, rhs| match is_and {
+            true => And(Box::new(lhs), Box::new(rhs)),
+            false => Or(Box::new(lhs), Box::new(rhs)),
+        })
+        .unwrap_or(identity)
+}
+
+/// Get the text of a word of a filter that is wrapped in double quotes. Quoted
+/// words are always tags, and can contain the characters used by the
+/// operators.
+pub fn unquote(word: &str) -> Option<&str> {
+    word.strip_prefix('"')?.strip_suffix('"')
+}
+
 /// Push the tag into the vector of tokens. The tag-data is created using the
-/// tag maker.
-fn push_tag<F>(input: &str, from: usize, to: usize, tokens: &mut Vec<Token>, tagmaker: &mut F)
+/// tag maker, which gets quoted words with their quotes. The words `and`, `or`,
+/// `xor` and `not` are operators, and `NOT_A_TAG` and `ALL_TAGS` are
+/// constants. Malformed file predicates are reported as errors.
+fn push_tag<F>(
+    input: &str,
+    from: usize,
+    to: usize,
+    tokens: &mut Vec<(Token, Range<usize>)>,
+    tagmaker: &mut F,
+) -> Result<(), FilterParseError>
 where
     F: FnMut(&str) -> Filter,
 {
-    if to > from {
-        tokens.push(Token::Parsed(tagmaker(&input[from..to])));
+    if to <= from {
+        return Ok(());
     }
+    let word = &input[from..to];
+    let keyword = |k: &str| word.eq_ignore_ascii_case(k);
+    let token = if keyword("and") {
+        Token::And
+    } else if keyword("or") {
+        Token::Or
+    } else if keyword("xor") {
+        Token::Xor
+    } else if keyword("not") {
+        Token::Not
+    } else if word == FALSE_WORD {
+        Token::Parsed(FalseTag)
+    } else if word == TRUE_WORD {
+        Token::Parsed(TrueTag)
+    } else {
+        if unquote(word).is_none() {
+            FilePredicate::parse(word)
+                .and(DescPredicate::parse(word))
+                .map_err(|kind| FilterParseError::new(kind, from..to, input))?;
+        }
+        Token::Parsed(tagmaker(word))
+    };
+    tokens.push((token, from..to));
+    Ok(())
 }
 
 #[cfg(test)]
@@ -260,6 +868,11 @@ mod test {
             "(apple & mango) | banana",
             "(apple & mango) | !banana",
             "(apple & pear) | !(banana & !pear) | (fig & grape)",
+            "trip-* & !/^20(1|2)\\d$/",
+            "(/a b\\/c/ | fig) & grape",
+            "(apple ^ mango) & !banana",
+            "\"tag with spaces\" | \"a&b\" | (fig ^ \"(x)\")",
+            "(NOT_A_TAG | apple) & ALL_TAGS",
         ] {
             let mut map = BTreeMap::<String, usize>::new();
             let filter = Filter::parse(fstr, |tag| {
@@ -270,7 +883,7 @@ mod test {
             .unwrap();
             let tagnames: Box<[_]> = {
                 let mut pairs: Vec<_> = map.into_iter().collect();
-                pairs.sort_by(|(_ta, ia), (_tb, ib)| ia.cmp(ib));
+                pairs.sort_by_key(|(_t, i)| *i);
                 pairs.into_iter().map(|(t, _i)| t).collect()
             };
             assert_eq!(filter.text(&tagnames), fstr);
@@ -296,10 +909,235 @@ mod test {
             .unwrap();
             let tagnames: Box<[_]> = {
                 let mut pairs: Vec<_> = map.into_iter().collect();
-                pairs.sort_by(|(_ta, ia), (_tb, ib)| ia.cmp(ib));
+                pairs.sort_by_key(|(_t, i)| *i);
+                pairs.into_iter().map(|(t, _i)| t).collect()
+            };
+            assert_eq!(filter.text(&tagnames), after);
+        }
+    }
+
+    #[test]
+    fn t_tag_patterns() {
+        let glob = TagPattern::parse("trip-*").unwrap();
+        assert!(glob.is_match("trip-paris"));
+        assert!(!glob.is_match("paris-trip"));
+        let regex = TagPattern::parse("/^20[01]\\d$/").unwrap();
+        assert!(regex.is_match("2019"));
+        assert!(!regex.is_match("2021"));
+        let slash = TagPattern::parse("/^place\\/e/").unwrap();
+        assert!(slash.is_match("place/europe"));
+        let years = TagPattern::parse("year>=2015").unwrap();
+        assert!(years.is_year_match(2015));
+        assert!(years.is_match("2020"));
+        assert!(!years.is_year_match(2014));
+        assert!(!years.is_match("beach"));
+        let years = TagPattern::parse("year:2015..2019").unwrap();
+        assert!(years.is_year_match(2019));
+        assert!(!years.is_year_match(2020));
+        assert!(TagPattern::parse("year<2000").unwrap().is_year_match(1999));
+        assert!(!TagPattern::parse("year>2000").unwrap().is_year_match(2000));
+        assert!(TagPattern::parse("year=2001").unwrap().is_year_match(2001));
+        assert!(TagPattern::parse("year>=20").is_none());
+        assert!(TagPattern::parse("beach").is_none());
+        assert!(TagPattern::parse("place/").is_none());
+        assert!(matches!(
+            Filter::parse("a & /(/", |_| Filter::TrueTag),
+            Err(FilterParseError {
+                kind: FilterErrorKind::InvalidRegex(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn t_file_predicates() {
+        let stat = |size, mtime| move || Some(FileStat { size, mtime });
+        let parse = |word| FilePredicate::parse(word).unwrap().unwrap();
+        assert!(parse("ext:pdf").is_match("report.PDF", || None));
+        assert!(parse("ext:.pdf").is_match("report.pdf", || None));
+        assert!(!parse("ext:pdf").is_match("pdf", || None));
+        assert!(parse("name~invoice").is_match("2020-invoice.pdf", || None));
+        assert!(parse("name~/^a|b$/").is_match("ab", || None));
+        assert!(!parse("name~^inv").is_match("my-invoice", || None));
+        assert!(parse("size>10MB").is_match("a", stat(10 * 1024 * 1024 + 1, 0)));
+        assert!(!parse("size>10MB").is_match("a", stat(10 * 1024 * 1024, 0)));
+        assert!(parse("size<=1.5k").is_match("a", stat(1536, 0)));
+        assert!(!parse("size>0").is_match("a", || None));
+        // 2020-01-01T00:00:00Z is 1577836800.
+        assert!(parse("mtime<2020-01-01").is_match("a", stat(0, 1577836799)));
+        assert!(!parse("mtime<2020-01-01").is_match("a", stat(0, 1577836800)));
+        assert!(parse("mtime=2020-01-01").is_match("a", stat(0, 1577836800 + 86399)));
+        assert!(parse("mtime>=2000-02-29").is_match("a", stat(0, 951782400)));
+        for word in [
+            "ext:",
+            "name~",
+            "size>10XB",
+            "mtime<2019-02-29",
+            "mtime>2020-1-1",
+        ] {
+            assert!(FilePredicate::parse(word).is_err(), "{}", word);
+        }
+        assert!(FilePredicate::parse("sizeable").unwrap().is_none());
+        assert!(matches!(
+            Filter::parse("a & mtime<2020-13-01", |_| Filter::TrueTag),
+            Err(FilterParseError {
+                kind: FilterErrorKind::InvalidPredicate(_),
+                ..
+            })
+        ));
+        assert!(Filter::parse("a & name~/x|y/", |_| Filter::TrueTag).is_ok());
+    }
+
+    #[test]
+    fn t_desc_predicates() {
+        let parse = |word| DescPredicate::parse(word).unwrap().unwrap();
+        let desc = "Electricity bill for March.\nPaid in full.";
+        assert!(parse("desc:electricity").is_match(desc));
+        assert!(parse("desc:\"bill for march\"").is_match(desc));
+        assert!(!parse("desc:\"march bill\"").is_match(desc));
+        assert!(parse("desc~/^Paid|unpaid/").is_match("Paid"));
+        assert!(!parse("desc~^Paid").is_match(desc));
+        assert!(parse("desc~(?m)^Paid").is_match(desc));
+        for word in ["desc:", "desc:\"\"", "desc:\"open", "desc~"] {
+            assert!(DescPredicate::parse(word).is_err(), "{}", word);
+        }
+        assert!(DescPredicate::parse("description").unwrap().is_none());
+        let mut words = Vec::new();
+        Filter::parse("invoice & desc:\"a (b) | c\" & !paid", |word| {
+            words.push(word.to_string());
+            Filter::TrueTag
+        })
+        .unwrap();
+        assert_eq!(words, ["invoice", "desc:\"a (b) | c\"", "paid"]);
+    }
+
+    #[test]
+    fn t_filter_precedence() {
+        for (before, after) in [
+            ("a | b & c", "a | (b & c)"),
+            ("a & b | c", "(a & b) | c"),
+            ("a | b ^ c & d", "a | (b ^ (c & d))"),
+            ("!a & b", "!a & b"),
+            ("!(a & b) | c", "!(a & b) | c"),
+            ("a and not b or c", "(a & !b) | c"),
+            ("a XOR b Or c", "(a ^ b) | c"),
+            ("(a | b) and c", "(a | b) & c"),
+            ("\"and\" & b", "\"and\" & b"),
+        ] {
+            let mut map = BTreeMap::<String, usize>::new();
+            let filter = Filter::parse(before, |tag| {
+                let size = map.len();
+                Filter::Tag(*map.entry(tag.to_string()).or_insert(size))
+            })
+            .unwrap();
+            let tagnames: Box<[_]> = {
+                let mut pairs: Vec<_> = map.into_iter().collect();
+                pairs.sort_by_key(|(_t, i)| *i);
                 pairs.into_iter().map(|(t, _i)| t).collect()
             };
             assert_eq!(filter.text(&tagnames), after);
         }
+        let eval = |input: &str, values: [bool; 2]| {
+            Filter::parse(input, |tag| Filter::Tag(if tag == "a" { 0 } else { 1 }))
+                .unwrap()
+                .eval(|ti| values[ti])
+        };
+        assert!(eval("a ^ b", [true, false]));
+        assert!(!eval("a ^ b", [true, true]));
+        assert!(eval("a | a & b", [true, false]));
+        for input in ["a & \"b", "a or", "a ^ ^ b", "()"] {
+            assert!(
+                Filter::parse(input, |_| Filter::TrueTag).is_err(),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(unquote("\"a b\""), Some("a b"));
+        assert_eq!(unquote("a"), None);
+    }
+
+    #[test]
+    fn t_filter_error_spans() {
+        for (input, span) in [
+            ("", 0..0),
+            ("a & b c", 6..7),
+            ("a & (b | c) d", 12..13),
+            ("a & (b | c", 4..5),
+            ("a ) b", 2..3),
+            ("a & (b | )", 9..9),
+            ("a &", 3..3),
+            ("a and or b", 6..8),
+            ("a & size>1Q | b", 4..11),
+            ("a | /(/", 4..7),
+            ("a | \"b", 4..6),
+        ] {
+            match Filter::parse(input, |_| Filter::TrueTag) {
+                Ok(_) => panic!("Expected an error for '{}'", input),
+                Err(e) => assert_eq!(e.span, span, "{}", input),
+            }
+        }
+        let error = |input| match Filter::parse(input, |_| Filter::TrueTag) {
+            Ok(_) => panic!("Expected an error for '{}'", input),
+            Err(e) => e,
+        };
+        let err = error("é & b c");
+        assert_eq!(err.render(), "é & b c\n      ^");
+        let err = error("a & (b | c) d");
+        assert!(matches!(err.kind, FilterErrorKind::ExpectedBinaryOperator));
+        assert_eq!(
+            format!("{:?}", err),
+            "A binary operator is missing.\na & (b | c) d\n            ^"
+        );
+    }
+
+    #[test]
+    fn t_simplify_filter() {
+        for (before, after) in [
+            ("a & yes", "a"),
+            ("a & no", "NOT_A_TAG"),
+            ("a | yes", "ALL_TAGS"),
+            ("!yes | a", "a"),
+            ("a ^ yes", "!a"),
+            ("a ^ no", "a"),
+            ("a & (b & (c & a))", "a & b & c"),
+            ("(a | b) | (b | a)", "a | b"),
+            ("!(a & b)", "!a | !b"),
+            ("!(a | !b)", "!a & b"),
+            ("!(a ^ b)", "!a ^ b"),
+            ("!a ^ !b", "a ^ b"),
+            ("a ^ a", "NOT_A_TAG"),
+            ("a ^ !a", "ALL_TAGS"),
+            ("a & !a & b", "NOT_A_TAG"),
+            ("a | b | !a", "ALL_TAGS"),
+            ("a | a & b", "a"),
+            ("a & b | b & a | c", "(a & b) | c"),
+            ("a & (b | a)", "a"),
+            ("(a | b) & (a | b | c) & d", "(a | b) & d"),
+            ("!(!a & (b | !c)) | c", "a | c"),
+            ("(a | b & c) & !d", "(a | (b & c)) & !d"),
+        ] {
+            let mut map = BTreeMap::<String, usize>::new();
+            let mut tagmaker = |tag: &str| match tag {
+                "yes" => Filter::TrueTag,
+                "no" => Filter::FalseTag,
+                _ => {
+                    let size = map.len();
+                    Filter::Tag(*map.entry(tag.to_string()).or_insert(size))
+                }
+            };
+            let filter = Filter::parse(before, &mut tagmaker).unwrap();
+            let simple = Filter::parse(before, &mut tagmaker).unwrap().simplify();
+            let tagnames: Box<[_]> = {
+                let mut pairs: Vec<_> = map.into_iter().collect();
+                pairs.sort_by_key(|(_t, i)| *i);
+                pairs.into_iter().map(|(t, _i)| t).collect()
+            };
+            assert_eq!(simple.text(&tagnames), after, "{}", before);
+            // Both filters agree for every combination of tags.
+            for bits in 0..(1usize << tagnames.len()) {
+                let check = |ti: usize| bits & (1 << ti) != 0;
+                assert_eq!(filter.eval(check), simple.eval(check), "{}", before);
+            }
+        }
     }
 }
@@ -0,0 +1,493 @@
+use crate::{
+    core::{move_metadata, Error, FTAG_HASH_FILE},
+    load::{FileLoadingOptions, GlobMatches, LoaderOptions},
+    rewrite::FtagDocument,
+    walk::{DirTree, MetaData, VisitedDir, WalkOptions},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Size and hash of the contents of a file.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Fingerprint {
+    size: u64,
+    hash: u64,
+}
+
+/// Fingerprint of a file, along with the modification time of the file when
+/// the fingerprint was computed. The modification time is used to avoid
+/// hashing files that haven't changed.
+#[derive(Clone, Copy)]
+struct Record {
+    fingerprint: Fingerprint,
+    mtime: u64,
+}
+
+/// Fingerprints of the files in a directory. These are stored in a sidecar
+/// file next to the ftag file, with one line per file containing the size, the
+/// modification time, the hash and the name of the file.
+struct Fingerprints {
+    records: BTreeMap<String, Record>,
+}
+
+impl Fingerprints {
+    fn load(dirpath: &Path) -> Result<Self, Error> {
+        let path = dirpath.join(FTAG_HASH_FILE);
+        let mut records = BTreeMap::new();
+        if !path.exists() {
+            return Ok(Fingerprints { records });
+        }
+        let mut text = String::new();
+        File::open(&path)
+            .map_err(|_| Error::CannotReadStoreFile(path.clone()))?
+            .read_to_string(&mut text)
+            .map_err(|_| Error::CannotReadStoreFile(path.clone()))?;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let mut words = line.splitn(4, ' ');
+            match (
+                words.next().and_then(|w| w.parse().ok()),
+                words.next().and_then(|w| w.parse().ok()),
+                words.next().and_then(|w| u64::from_str_radix(w, 16).ok()),
+                words.next(),
+            ) {
+                (Some(size), Some(mtime), Some(hash), Some(name)) => {
+                    records.insert(
+                        name.to_string(),
+                        Record {
+                            fingerprint: Fingerprint { size, hash },
+                            mtime,
+                        },
+                    );
+                }
+                _ => {
+                    return Err(Error::CannotParseFtagFile(
+                        path,
+                        format!("Invalid fingerprint: '{}'", line),
+                    ))
+                }
+            }
+        }
+        Ok(Fingerprints { records })
+    }
+
+    fn save(&self, dirpath: &Path) -> Result<(), Error> {
+        let path = dirpath.join(FTAG_HASH_FILE);
+        if self.records.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(&path).map_err(|_| Error::CannotWriteFile(path.clone()))?;
+            }
+            return Ok(());
+        }
+        let mut writer = io::BufWriter::new(
+            File::create(&path).map_err(|_| Error::CannotWriteFile(path.clone()))?,
+        );
+        for (name, record) in self.records.iter() {
+            writeln!(
+                writer,
+                "{} {} {:016x} {}",
+                record.fingerprint.size, record.mtime, record.fingerprint.hash, name
+            )
+            .map_err(|_| Error::CannotWriteFile(path.clone()))?;
+        }
+        writer
+            .flush()
+            .map_err(|_| Error::CannotWriteFile(path.clone()))
+    }
+}
+
+/// Get the size and modification time of a file.
+fn file_stat(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let mtime = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some((meta.len(), mtime))
+}
+
+/// Compute the 64 bit FNV-1a hash of the contents of a file. This is not a
+/// cryptographic hash, but combined with the size of the file, it is good
+/// enough to recognize a file after it was renamed.
+fn hash_file(path: &Path) -> Result<u64, io::Error> {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = [0u8; 1 << 16];
+    let mut hash = OFFSET;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for byte in &buf[..n] {
+            hash = (hash ^ *byte as u64).wrapping_mul(PRIME);
+        }
+    }
+    Ok(hash)
+}
+
+fn compute_record(path: &Path) -> Option<Record> {
+    let (size, mtime) = file_stat(path)?;
+    let hash = hash_file(path).ok()?;
+    Some(Record {
+        fingerprint: Fingerprint { size, hash },
+        mtime,
+    })
+}
+
+/// Recursively record the fingerprints of all files that are listed by name in
+/// an ftag file. Files whose size and modification time haven't changed since
+/// their fingerprint was recorded are not hashed again. Fingerprints of files
+/// that are still listed in the ftag file, but are missing on disk, are kept
+/// so that they can be repaired later.
+pub fn update_fingerprints(root: PathBuf, walk: &WalkOptions) -> Result<(), Error> {
+    let mut dir = DirTree::new(
+        root,
+        LoaderOptions::new(
+            false,
+            false,
+            FileLoadingOptions::Load {
+                file_tags: false,
+                file_desc: false,
+            },
+        ),
+        walk,
+    )?;
+    while let Some(VisitedDir {
+        abs_dir_path,
+        files,
+        metadata,
+        ..
+    }) = dir.walk()
+    {
+        let data = match metadata {
+            MetaData::Ok(data) => data,
+            MetaData::NotFound => continue,
+            MetaData::FailedToLoad(e) => return Err(e),
+        };
+        let old = Fingerprints::load(abs_dir_path)?;
+        let mut new = Fingerprints {
+            records: BTreeMap::new(),
+        };
+        for glob in data.globs.iter().map(|g| g.path) {
+            let record = match files.binary_search_by(|f| f.name().cmp(OsStr::new(glob))) {
+                Ok(_) => {
+                    let path = abs_dir_path.join(glob);
+                    match (old.records.get(glob), file_stat(&path)) {
+                        (Some(record), Some((size, mtime)))
+                            if record.fingerprint.size == size && record.mtime == mtime =>
+                        {
+                            Some(*record)
+                        }
+                        _ => compute_record(&path),
+                    }
+                }
+                // Missing file, keep the fingerprint for repairing later.
+                Err(_) => old.records.get(glob).copied(),
+            };
+            if let Some(record) = record {
+                new.records.insert(glob.to_string(), record);
+            }
+        }
+        new.save(abs_dir_path)?;
+    }
+    dir.finish()?;
+    Ok(())
+}
+
+/// Move the fingerprint of the file named `srcname` in `srcdir`, if one was
+/// recorded, to the file named `dstname` in `dstdir`.
+pub(crate) fn move_fingerprint(
+    srcdir: &Path,
+    srcname: &str,
+    dstdir: &Path,
+    dstname: &str,
+) -> Result<(), Error> {
+    if !srcdir.join(FTAG_HASH_FILE).exists() {
+        return Ok(());
+    }
+    let mut src = Fingerprints::load(srcdir)?;
+    let record = match src.records.remove(srcname) {
+        Some(record) => record,
+        None => return Ok(()),
+    };
+    if srcdir == dstdir {
+        src.records.insert(dstname.to_string(), record);
+    } else {
+        let mut dst = Fingerprints::load(dstdir)?;
+        dst.records.insert(dstname.to_string(), record);
+        dst.save(dstdir)?;
+    }
+    src.save(srcdir)
+}
+
+/// A file listed in an ftag file, that is missing on disk.
+struct Missing {
+    rel_dir_path: PathBuf,
+    abs_dir_path: PathBuf,
+    name: String,
+}
+
+/// A file found on disk, that is not listed by name in any ftag file.
+struct Candidate {
+    rel_dir_path: PathBuf,
+    abs_dir_path: PathBuf,
+    name: String,
+}
+
+/// Recursively find files that are listed in ftag files but missing on disk,
+/// and use their recorded fingerprints to find them among the files that are
+/// not listed by name in any ftag file. Each file that is found is printed
+/// along with its new path. If `apply` is true, the ftag files are also updated
+/// to use the new paths. Files whose fingerprints match more than one file are
+/// reported, but never updated.
+pub fn repair(root: PathBuf, apply: bool, walk: &WalkOptions) -> Result<(), Error> {
+    let options = || {
+        LoaderOptions::new(
+            false,
+            false,
+            FileLoadingOptions::Load {
+                file_tags: false,
+                file_desc: false,
+            },
+        )
+    };
+    let mut matcher = GlobMatches::new();
+    // Find the fingerprints of the missing files.
+    let mut missing = HashMap::<Fingerprint, Vec<Missing>>::new();
+    let mut dir = DirTree::new(root.clone(), options(), walk)?;
+    while let Some(VisitedDir {
+        abs_dir_path,
+        rel_dir_path,
+        files,
+        metadata,
+        ..
+    }) = dir.walk()
+    {
+        let data = match metadata {
+            MetaData::Ok(data) => data,
+            MetaData::NotFound => continue,
+            MetaData::FailedToLoad(e) => return Err(e),
+        };
+        matcher.find_matches(files, &data.globs, true);
+        let mut fingerprints = None;
+        for (gi, g) in data.globs.iter().enumerate() {
+            if matcher.is_glob_matched(gi) {
+                continue;
+            }
+            let fingerprints = match &mut fingerprints {
+                Some(f) => f,
+                None => fingerprints.insert(Fingerprints::load(abs_dir_path)?),
+            };
+            if let Some(record) = fingerprints.records.get(g.path) {
+                missing
+                    .entry(record.fingerprint)
+                    .or_default()
+                    .push(Missing {
+                        rel_dir_path: rel_dir_path.to_path_buf(),
+                        abs_dir_path: abs_dir_path.to_path_buf(),
+                        name: g.path.to_string(),
+                    });
+            }
+        }
+    }
+    dir.finish()?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    // Look for the missing files among the untracked files.
+    let sizes: HashSet<u64> = missing.keys().map(|f| f.size).collect();
+    let mut candidates = HashMap::<Fingerprint, Vec<Candidate>>::new();
+    let mut dir = DirTree::new(root, options(), walk)?;
+    while let Some(VisitedDir {
+        abs_dir_path,
+        rel_dir_path,
+        files,
+        metadata,
+        ..
+    }) = dir.walk()
+    {
+        let tracked = match metadata {
+            MetaData::Ok(data) => {
+                matcher.find_matches(files, &data.globs, false);
+                true
+            }
+            MetaData::NotFound => false,
+            MetaData::FailedToLoad(e) => return Err(e),
+        };
+        for (fi, file) in files.iter().enumerate() {
+            let name = match file.name().to_str() {
+                Some(name) if !(tracked && matcher.is_file_matched(fi)) => name,
+                _ => continue,
+            };
+            let path = abs_dir_path.join(name);
+            match file_stat(&path) {
+                Some((size, _)) if sizes.contains(&size) => {}
+                _ => continue,
+            }
+            if let Some(record) = compute_record(&path) {
+                if missing.contains_key(&record.fingerprint) {
+                    candidates
+                        .entry(record.fingerprint)
+                        .or_default()
+                        .push(Candidate {
+                            rel_dir_path: rel_dir_path.to_path_buf(),
+                            abs_dir_path: abs_dir_path.to_path_buf(),
+                            name: name.to_string(),
+                        });
+                }
+            }
+        }
+    }
+    dir.finish()?;
+    let mut found: Vec<_> = missing
+        .into_iter()
+        .filter_map(|(fp, m)| candidates.remove(&fp).map(|c| (m, c)))
+        .collect();
+    found.sort_unstable_by(|(a, _), (b, _)| {
+        (&a[0].rel_dir_path, &a[0].name).cmp(&(&b[0].rel_dir_path, &b[0].name))
+    });
+    for (mut missing, mut candidates) in found {
+        if missing.len() > 1 || candidates.len() > 1 {
+            println!("Ambiguous matches, skipping:");
+            for m in missing.iter() {
+                println!("  {}", m.rel_dir_path.join(&m.name).display());
+            }
+            for c in candidates.iter() {
+                println!("  -> {}", c.rel_dir_path.join(&c.name).display());
+            }
+            continue;
+        }
+        let (m, c) = match (missing.pop(), candidates.pop()) {
+            (Some(m), Some(c)) => (m, c),
+            _ => continue,
+        };
+        println!(
+            "{} -> {}",
+            m.rel_dir_path.join(&m.name).display(),
+            c.rel_dir_path.join(&c.name).display()
+        );
+        if !apply {
+            continue;
+        }
+        if m.abs_dir_path == c.abs_dir_path {
+            // Renamed within the same directory.
+            let mut doc = FtagDocument::load(&m.abs_dir_path)?;
+            for ei in 0..doc.num_entries() {
+                let spans: Vec<_> = doc
+                    .globs(ei)
+                    .filter_map(|(span, g)| if g == m.name { Some(span) } else { None })
+                    .collect();
+                for span in spans {
+                    doc.replace_glob(span, &c.name);
+                }
+            }
+            doc.apply()?;
+            doc.save()?;
+        } else {
+            let (srcdoc, dstdoc) =
+                move_metadata(&m.abs_dir_path, &m.name, &c.abs_dir_path, &c.name)?;
+            srcdoc.save()?;
+            if let Some(doc) = dstdoc {
+                doc.save()?;
+            }
+        }
+        move_fingerprint(&m.abs_dir_path, &m.name, &c.abs_dir_path, &c.name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core::FTAG_FILE, rewrite::TagOwner};
+
+    #[test]
+    fn t_fnv_hash() {
+        let path = std::env::temp_dir().join("ftag_t_fnv_hash.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let hash = hash_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Reference value of FNV-1a 64 for "hello".
+        assert_eq!(hash, 0xa430d84680aabd0b);
+    }
+
+    /// Get the globs of every entry in the ftag file of `dir`, with their tags.
+    fn entries(dir: &Path) -> Vec<(Vec<String>, Vec<String>)> {
+        let doc = FtagDocument::load(dir).unwrap();
+        (0..doc.num_entries())
+            .map(|ei| {
+                (
+                    doc.globs(ei).map(|(_, g)| g.to_string()).collect(),
+                    doc.tags(TagOwner::Entry(ei))
+                        .map(|t| t.to_string())
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn t_repair() {
+        let root = std::env::temp_dir().join("ftag_t_repair");
+        let _ = std::fs::remove_dir_all(&root);
+        let sub = root.join("sub");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(root.join("a.jpg"), "apple").unwrap();
+        std::fs::write(root.join("x.jpg"), "twin").unwrap();
+        std::fs::write(root.join("m.png"), "mango").unwrap();
+        std::fs::write(
+            root.join(FTAG_FILE),
+            "[path]\na.jpg\n[tags]\nbeach\n[path]\nx.jpg\n[tags]\ntwin\n\
+             [path]\nm.png\n[tags]\nmango\n[path]\n*.png\n[tags]\nart\n",
+        )
+        .unwrap();
+        let walk = WalkOptions::default();
+        update_fingerprints(root.clone(), &walk).unwrap();
+        let before = entries(&root);
+        // Rename the files outside of ftag. Two files now have the contents
+        // of `x.jpg`, so it can't be repaired.
+        std::fs::rename(root.join("a.jpg"), sub.join("renamed.jpg")).unwrap();
+        std::fs::rename(root.join("x.jpg"), root.join("y.jpg")).unwrap();
+        std::fs::write(root.join("z.jpg"), "twin").unwrap();
+        // The only file with the contents of `m.png` is already tracked by a
+        // glob, so it isn't the renamed file.
+        std::fs::remove_file(root.join("m.png")).unwrap();
+        std::fs::write(root.join("n.png"), "mango").unwrap();
+        repair(root.clone(), false, &walk).unwrap();
+        assert_eq!(entries(&root), before); // Dry run.
+        assert!(!sub.join(FTAG_FILE).exists());
+        repair(root.clone(), true, &walk).unwrap();
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            entries(&root),
+            [
+                (strings(&["x.jpg"]), strings(&["twin"])),
+                (strings(&["m.png"]), strings(&["mango"])),
+                (strings(&["*.png"]), strings(&["art"]))
+            ]
+        );
+        assert_eq!(
+            entries(&sub),
+            [(strings(&["renamed.jpg"]), strings(&["beach"]))]
+        );
+        // The fingerprint moved along with the tags.
+        assert!(Fingerprints::load(&sub)
+            .unwrap()
+            .records
+            .contains_key("renamed.jpg"));
+        assert!(!Fingerprints::load(&root)
+            .unwrap()
+            .records
+            .contains_key("a.jpg"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -4,6 +4,7 @@ use ftag::{
     core::Error,
     interactive::{InteractiveSession, State},
     query::TagTable,
+    walk::WalkOptions,
 };
 use std::path::{Path, PathBuf};
 
@@ -16,6 +17,13 @@ fn main() -> Result<(), Error> {
                 .required(false)
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .short('j')
+                .required(false)
+                .value_parser(value_parser!(usize)),
+        )
         .get_matches();
     let current_dir = if let Some(rootdir) = matches.get_one::<PathBuf>("path") {
         rootdir
@@ -24,7 +32,11 @@ fn main() -> Result<(), Error> {
     } else {
         std::env::current_dir().map_err(|_| Error::InvalidWorkingDirectory)?
     };
-    let table = TagTable::from_dir(current_dir)?;
+    let walk = WalkOptions {
+        threads: matches.get_one::<usize>("threads").copied().unwrap_or(1),
+        ..Default::default()
+    };
+    let table = TagTable::from_dir(current_dir, &walk)?;
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_maximized(true)
@@ -45,7 +57,7 @@ fn main() -> Result<(), Error> {
             }))
         }),
     )
-    .map_err(Error::GUIFailure)
+    .map_err(|e| Error::GUIFailure(e.to_string()))
 }
 
 struct GuiApp {
@@ -246,13 +258,19 @@ impl GuiApp {
                 ui.add(right);
             }
             None => {
+                // Errors in filters are underlined with carets on the lines
+                // below them, so they must not be truncated to one line.
+                let echo = self.session.echo();
                 ui.add(
                     egui::Label::new(
-                        egui::widget_text::RichText::new(self.session.echo())
+                        egui::widget_text::RichText::new(echo)
                             .text_style(egui::TextStyle::Monospace),
                     )
                     .selectable(false)
-                    .wrap_mode(egui::TextWrapMode::Truncate),
+                    .wrap_mode(match echo.contains('\n') {
+                        true => egui::TextWrapMode::Extend,
+                        false => egui::TextWrapMode::Truncate,
+                    }),
                 );
             }
         }
@@ -0,0 +1,172 @@
+use crate::core::FTAG_IGNORE_FILE;
+use fast_glob::glob_match;
+use std::{
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+/*
+Ignore files use the same patterns as .gitignore files. Each line is a glob
+pattern. Blank lines and lines starting with '#' are skipped. A pattern that
+starts with '!' re-includes what an earlier pattern ignored, and a pattern that
+ends with '/' only matches directories. A pattern that contains a '/' anywhere
+else is matched against the path relative to the directory containing the
+ignore file, otherwise it is matched against the name of the file or directory,
+at any depth. The patterns of an ignore file apply to the whole directory tree
+below it, the same way tags are inherited. Patterns of deeper ignore files take
+precedence over the ones above them, and within an ignore file, later patterns
+take precedence over earlier ones.
+ */
+
+struct Pattern {
+    glob: String,
+    negate: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+fn parse_pattern(line: &str) -> Option<Pattern> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negate, line) = match line.strip_prefix('\\') {
+        Some(escaped) => (false, escaped), // Literal '#' or '!'.
+        None => match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        },
+    };
+    let (dir_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let anchored = line.contains('/');
+    let line = line.strip_prefix('/').unwrap_or(line);
+    if line.is_empty() {
+        return None;
+    }
+    Some(Pattern {
+        glob: line.to_string(),
+        negate,
+        dir_only,
+        anchored,
+    })
+}
+
+/// Ignore patterns that apply to the contents of a directory: the patterns of
+/// the nearest ignore file, followed by the patterns inherited from the ignore
+/// files above it.
+pub(crate) struct Ignores {
+    /// Directory containing the ignore file, relative to the root of the walk.
+    dir: PathBuf,
+    patterns: Vec<Pattern>,
+    parent: Option<Arc<Ignores>>,
+}
+
+impl Ignores {
+    /// Get the ignore patterns that apply to the contents of the directory at
+    /// `abs_dir_path`, given the patterns that apply to its parent directory.
+    /// `rel_dir_path` is the path of the directory relative to the root of the
+    /// walk.
+    pub(crate) fn load(
+        parent: Option<&Arc<Ignores>>,
+        abs_dir_path: &Path,
+        rel_dir_path: &Path,
+    ) -> Option<Arc<Ignores>> {
+        let patterns: Vec<Pattern> =
+            match std::fs::read_to_string(abs_dir_path.join(FTAG_IGNORE_FILE)) {
+                Ok(text) => text.lines().filter_map(parse_pattern).collect(),
+                Err(_) => Vec::new(),
+            };
+        if patterns.is_empty() {
+            return parent.cloned();
+        }
+        Some(Arc::new(Ignores {
+            dir: rel_dir_path.to_path_buf(),
+            patterns,
+            parent: parent.cloned(),
+        }))
+    }
+
+    /// Check if the file or directory `name`, inside the directory at
+    /// `rel_dir_path` relative to the root of the walk, is ignored.
+    pub(crate) fn is_ignored(&self, rel_dir_path: &Path, name: &OsStr, is_dir: bool) -> bool {
+        let mut current = Some(self);
+        let mut relpath = Vec::new();
+        while let Some(ignores) = current {
+            // Path relative to the directory of the ignore file, always
+            // separated by '/' to match the patterns.
+            relpath.clear();
+            for comp in rel_dir_path
+                .strip_prefix(&ignores.dir)
+                .unwrap_or(rel_dir_path)
+                .components()
+            {
+                if let Component::Normal(comp) = comp {
+                    relpath.extend_from_slice(comp.as_encoded_bytes());
+                    relpath.push(b'/');
+                }
+            }
+            relpath.extend_from_slice(name.as_encoded_bytes());
+            for pattern in ignores.patterns.iter().rev() {
+                if pattern.dir_only && !is_dir {
+                    continue;
+                }
+                let target = match pattern.anchored {
+                    true => &relpath[..],
+                    false => name.as_encoded_bytes(),
+                };
+                if glob_match(&pattern.glob, target) {
+                    return !pattern.negate;
+                }
+            }
+            current = ignores.parent.as_deref();
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ignores(dir: &str, text: &str, parent: Option<Arc<Ignores>>) -> Arc<Ignores> {
+        Arc::new(Ignores {
+            dir: PathBuf::from(dir),
+            patterns: text.lines().filter_map(parse_pattern).collect(),
+            parent,
+        })
+    }
+
+    #[test]
+    fn t_ignore_patterns() {
+        let root = ignores(
+            "",
+            "# Comment\n\n.git/\nnode_modules\n*.tmp\n!keep.tmp\n/build\ndocs/*.pdf\n\\#hash\n",
+            None,
+        );
+        let check = |ign: &Ignores, dir: &str, name: &str, is_dir: bool| {
+            ign.is_ignored(Path::new(dir), OsStr::new(name), is_dir)
+        };
+        assert!(check(&root, "", ".git", true));
+        assert!(!check(&root, "", ".git", false)); // Only directories.
+        assert!(check(&root, "a/b", "node_modules", true));
+        assert!(check(&root, "a", "x.tmp", false));
+        assert!(!check(&root, "a", "keep.tmp", false));
+        assert!(check(&root, "", "build", true));
+        assert!(!check(&root, "a", "build", true)); // Anchored to the root.
+        assert!(check(&root, "docs", "x.pdf", false));
+        assert!(!check(&root, "a/docs", "x.pdf", false));
+        assert!(check(&root, "", "#hash", false));
+        assert!(!check(&root, "", "photo.jpg", false));
+        // Deeper ignore files take precedence.
+        let sub = ignores("a", "!*.tmp\nlocal/\n/docs/*.pdf\n", Some(root));
+        assert!(!check(&sub, "a/b", "x.tmp", false));
+        assert!(check(&sub, "a", "node_modules", true));
+        assert!(check(&sub, "a/b", "local", true));
+        assert!(check(&sub, "a/docs", "x.pdf", false));
+        assert!(!check(&sub, "a/b/docs", "x.pdf", false));
+    }
+}
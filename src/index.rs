@@ -0,0 +1,625 @@
+use crate::{
+    core::{Error, FTAG_FILE, FTAG_IGNORE_FILE, FTAG_INDEX_FILE},
+    ignore::Ignores,
+    load::{
+        get_filename_lossy, infer_implicit_tags, FileLoadingOptions, GlobMatches, Loader,
+        LoaderOptions,
+    },
+    query::InheritedTags,
+    walk::{report, DirEntry, DirEntryType, WalkOptions},
+};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/*
+The index stores the resolved tags of every tracked file, so that queries don't
+have to walk the directories and parse all the ftag files. For each directory,
+the index stores the modification times of the directory and its ftag file,
+the tags inherited by its contents, its subdirectories, and its tracked files
+along with their own tags. Before the index is used, it is refreshed by
+comparing these modification times with the ones on disk. Only the directories
+that changed are listed and parsed again. Subdirectories of unchanged
+directories are taken from the index, so an unchanged tree is refreshed with
+three `stat` calls per directory.
+
+Files and directories that match the patterns in ignore files are left out of
+the index. Because the patterns of an ignore file apply to the whole tree below
+it, all the directories below a changed ignore file are refreshed.
+
+A directory, ftag file or ignore file modified within a couple of seconds of
+refreshing the index might be modified again without changing its modification
+time, so such directories are always refreshed the next time.
+ */
+const MAGIC: &[u8] = b"FTAGIDX2";
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+pub(crate) struct IndexedFile {
+    pub(crate) name: OsString,
+    /// Explicit and implicit tags of this file, not including inherited tags.
+    pub(crate) tags: Box<[u32]>,
+}
+
+pub(crate) struct IndexedDir {
+    /// Path relative to the root of the index.
+    pub(crate) path: PathBuf,
+    pub(crate) depth: usize,
+    /// Modification time in nanoseconds. Zero means the record must be
+    /// refreshed.
+    dir_mtime: u64,
+    /// Modification time of the ftag file in nanoseconds, zero if there is no
+    /// ftag file.
+    ftag_mtime: u64,
+    /// Modification time of the ignore file in nanoseconds, zero if there is
+    /// no ignore file.
+    ignore_mtime: u64,
+    pub(crate) has_metadata: bool,
+    /// Tags inherited by the contents of this directory.
+    pub(crate) tags: Box<[u32]>,
+    /// All tags mentioned in the ftag file, including the tags of globs that
+    /// don't match any files.
+    pub(crate) alltags: Box<[u32]>,
+    subdirs: Box<[OsString]>,
+    pub(crate) files: Box<[IndexedFile]>,
+}
+
+/// Persistent index of all tracked files and their tags, stored in the root
+/// directory of the index.
+pub struct Index {
+    tags: Vec<String>,
+    tag_ids: HashMap<String, u32>,
+    /// Directories in depth first order, same as the order of traversal of
+    /// `DirTree`.
+    pub(crate) dirs: Vec<IndexedDir>,
+}
+
+fn mtime(path: &Path) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl Index {
+    fn empty() -> Index {
+        Index {
+            tags: Vec::new(),
+            tag_ids: HashMap::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Name of the tag with `id`.
+    pub(crate) fn tag(&self, id: u32) -> &str {
+        &self.tags[id as usize]
+    }
+
+    pub(crate) fn num_tags(&self) -> usize {
+        self.tags.len()
+    }
+
+    fn intern(&mut self, tag: &str) -> u32 {
+        if let Some(id) = self.tag_ids.get(tag) {
+            return *id;
+        }
+        let id = self.tags.len() as u32;
+        self.tags.push(tag.to_string());
+        self.tag_ids.insert(tag.to_string(), id);
+        id
+    }
+
+    /// Open the index in `root`, if one exists, and bring it up to date with
+    /// the files on disk. If the index was modified, it is saved. Failing to
+    /// save it only produces a warning, because the refreshed index can still
+    /// be used, e.g. when querying a read-only archive. The index is always
+    /// built with the default traversal options, so it is not used if `walk`
+    /// asks for anything else.
+    pub(crate) fn open(root: &Path, walk: &WalkOptions) -> Result<Option<Index>, Error> {
+        let path = root.join(FTAG_INDEX_FILE);
+        let default = WalkOptions {
+            threads: walk.threads, // Doesn't affect the results.
+            ..Default::default()
+        };
+        if *walk != default || !path.is_file() {
+            return Ok(None);
+        }
+        let (index, changed) = Self::load(root, &path)?;
+        if changed && index.save(&path).is_err() {
+            eprintln!(
+                "Warning: Cannot save the updated index to {}. Run 'ftag index' when it is writable.",
+                path.display()
+            );
+        }
+        Ok(Some(index))
+    }
+
+    /// Create the index in `root` if it doesn't exist, or bring it up to date
+    /// with the files on disk, and save it.
+    pub fn update(root: &Path) -> Result<Index, Error> {
+        let path = root.join(FTAG_INDEX_FILE);
+        let (index, changed) = Self::load(root, &path)?;
+        if changed || !path.exists() {
+            index.save(&path)?;
+        }
+        Ok(index)
+    }
+
+    /// Read the index from `path` and bring it up to date with the files in
+    /// `root`. Returns the index and whether anything changed.
+    fn load(root: &Path, path: &Path) -> Result<(Index, bool), Error> {
+        // A corrupt or outdated index is rebuilt from scratch.
+        let old = std::fs::read(path)
+            .ok()
+            .and_then(|bytes| Self::decode(&bytes))
+            .unwrap_or_else(Self::empty);
+        Self::refresh(root, old)
+    }
+
+    /// Bring the index up to date with the files on disk. Returns the new
+    /// index and whether anything changed.
+    fn refresh(root: &Path, mut old: Index) -> Result<(Index, bool), Error> {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(RACY_WINDOW)
+            .as_nanos() as u64;
+        let olddirs: HashMap<PathBuf, IndexedDir> = std::mem::take(&mut old.dirs)
+            .into_iter()
+            .map(|d| (d.path.clone(), d))
+            .collect();
+        let mut changed = olddirs.is_empty();
+        let mut index = old; // Reuse the tag ids.
+        let mut loader = Loader::new(LoaderOptions::new(
+            true,
+            false,
+            FileLoadingOptions::Load {
+                file_tags: true,
+                file_desc: false,
+            },
+        ));
+        let mut matcher = GlobMatches::new();
+        // Each directory is pushed with the ignore patterns of its parent, and
+        // whether those patterns changed since the last refresh.
+        let mut stack: Vec<(PathBuf, usize, Option<Arc<Ignores>>, bool)> =
+            vec![(PathBuf::new(), 1usize, None, false)];
+        let mut entries = Vec::new();
+        let mut diagnostics = Vec::new();
+        while let Some((relpath, depth, ignores, ignores_changed)) = stack.pop() {
+            let abspath = root.join(&relpath);
+            let dir_mtime = mtime(&abspath);
+            let ftag_path = abspath.join(FTAG_FILE);
+            let ftag_mtime = mtime(&ftag_path);
+            let ignore_mtime = mtime(&abspath.join(FTAG_IGNORE_FILE));
+            let ignores_changed = ignores_changed
+                || olddirs
+                    .get(&relpath)
+                    .is_none_or(|old| old.ignore_mtime != ignore_mtime);
+            let ignores = match ignore_mtime {
+                0 => ignores,
+                _ => Ignores::load(ignores.as_ref(), &abspath, &relpath),
+            };
+            let mut record = match olddirs.get(&relpath) {
+                Some(old)
+                    if !ignores_changed
+                        && old.dir_mtime != 0
+                        && old.dir_mtime == dir_mtime
+                        && old.ftag_mtime == ftag_mtime =>
+                {
+                    IndexedDir {
+                        path: relpath,
+                        depth,
+                        dir_mtime,
+                        ftag_mtime,
+                        ignore_mtime,
+                        has_metadata: old.has_metadata,
+                        tags: old.tags.clone(),
+                        alltags: old.alltags.clone(),
+                        subdirs: old.subdirs.clone(),
+                        files: old
+                            .files
+                            .iter()
+                            .map(|f| IndexedFile {
+                                name: f.name.clone(),
+                                tags: f.tags.clone(),
+                            })
+                            .collect(),
+                    }
+                }
+                _ => {
+                    changed = true;
+                    entries.clear();
+                    WalkOptions::default().read_entries(
+                        &abspath,
+                        &relpath,
+                        depth,
+                        ignores.as_deref(),
+                        &mut diagnostics,
+                        |name, entry_type| entries.push(DirEntry::new(name, entry_type)),
+                    );
+                    entries.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+                    let subdirs: Box<[OsString]> = entries
+                        .iter()
+                        .filter(|e| e.entry_type() == DirEntryType::Dir)
+                        .map(|e| e.name().to_os_string())
+                        .collect();
+                    entries.retain(|e| e.entry_type() == DirEntryType::File);
+                    let mut record = IndexedDir {
+                        path: relpath,
+                        depth,
+                        dir_mtime,
+                        ftag_mtime,
+                        ignore_mtime,
+                        has_metadata: false,
+                        tags: Box::new([]),
+                        alltags: Box::new([]),
+                        subdirs,
+                        files: Box::new([]),
+                    };
+                    if ftag_mtime != 0 {
+                        let data = loader.load(&ftag_path)?;
+                        let dirname = get_filename_lossy(&record.path);
+                        let dirname: &str = &dirname;
+                        let dirtags: Vec<String> = infer_implicit_tags(dirname)
+                            .map(|t| t.to_string())
+                            .collect();
+                        record.has_metadata = true;
+                        record.tags = data
+                            .tags()
+                            .iter()
+                            .copied()
+                            .chain(dirtags.iter().map(|t| t.as_str()))
+                            .map(|t| index.intern(t))
+                            .collect();
+                        record.alltags = data
+                            .alltags
+                            .iter()
+                            .copied()
+                            .chain(dirtags.iter().map(|t| t.as_str()))
+                            .map(|t| index.intern(t))
+                            .collect();
+                        matcher.find_matches(&entries, &data.globs, false);
+                        let mut files = Vec::new();
+                        for (fi, file) in entries.iter().enumerate() {
+                            if !matcher.is_file_matched(fi) {
+                                continue;
+                            }
+                            let mut tags: Vec<u32> = matcher
+                                .matched_globs(fi)
+                                .flat_map(|gi| data.globs[gi].tags(&data.alltags).iter())
+                                .map(|t| index.intern(t))
+                                .collect();
+                            tags.extend(
+                                infer_implicit_tags(&file.name().to_string_lossy())
+                                    .map(|t| t.to_string())
+                                    .map(|t| index.intern(&t)),
+                            );
+                            tags.sort_unstable();
+                            tags.dedup();
+                            files.push(IndexedFile {
+                                name: file.name().to_os_string(),
+                                tags: tags.into_boxed_slice(),
+                            });
+                        }
+                        record.files = files.into_boxed_slice();
+                    }
+                    record
+                }
+            };
+            // Push the subdirectories in reverse order, so they're visited in order.
+            for name in record.subdirs.iter().rev() {
+                stack.push((
+                    record.path.join(name),
+                    depth + 1,
+                    ignores.clone(),
+                    ignores_changed,
+                ));
+            }
+            if record.dir_mtime >= start || record.ftag_mtime >= start {
+                record.dir_mtime = 0; // Not safe to reuse next time.
+            }
+            if record.ignore_mtime >= start {
+                // Never matches next time, so the whole tree below is refreshed.
+                record.ignore_mtime = u64::MAX;
+            }
+            index.dirs.push(record);
+        }
+        changed |= index.dirs.len() != olddirs.len();
+        report(diagnostics);
+        Ok((index, changed))
+    }
+
+    /// Visit all tracked files in the index, along with their own tags and the
+    /// tags they inherit from the directories containing them.
+    pub(crate) fn visit_files(
+        &self,
+        mut visit: impl FnMut(&IndexedDir, &IndexedFile, &[u32]),
+    ) -> Result<(), Error> {
+        let mut inherited = InheritedTags::<u32>::new();
+        for dir in self.dirs.iter() {
+            inherited.update(dir.depth)?;
+            if !dir.has_metadata {
+                continue;
+            }
+            inherited.tags.extend(dir.tags.iter().copied());
+            for file in dir.files.iter() {
+                visit(dir, file, &inherited.tags);
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        std::fs::write(path, self.encode()).map_err(|_| Error::CannotWriteFile(path.to_path_buf()))
+    }
+
+    /// Serialize the index. Tags that are no longer used are dropped.
+    fn encode(&self) -> Vec<u8> {
+        let mut remap: Vec<Option<u32>> = vec![None; self.tags.len()];
+        let mut tags: Vec<&str> = Vec::new();
+        let mut map = |id: &u32| -> u32 {
+            *remap[*id as usize].get_or_insert_with(|| {
+                tags.push(&self.tags[*id as usize]);
+                (tags.len() - 1) as u32
+            })
+        };
+        let mut body = Vec::new();
+        put_u32(&mut body, self.dirs.len() as u32);
+        for dir in self.dirs.iter() {
+            put_bytes(&mut body, dir.path.as_os_str().as_encoded_bytes());
+            put_u32(&mut body, dir.depth as u32);
+            put_u64(&mut body, dir.dir_mtime);
+            put_u64(&mut body, dir.ftag_mtime);
+            put_u64(&mut body, dir.ignore_mtime);
+            body.push(dir.has_metadata as u8);
+            put_ids(&mut body, dir.tags.iter().map(&mut map), dir.tags.len());
+            put_ids(
+                &mut body,
+                dir.alltags.iter().map(&mut map),
+                dir.alltags.len(),
+            );
+            put_u32(&mut body, dir.subdirs.len() as u32);
+            for name in dir.subdirs.iter() {
+                put_bytes(&mut body, name.as_encoded_bytes());
+            }
+            put_u32(&mut body, dir.files.len() as u32);
+            for file in dir.files.iter() {
+                put_bytes(&mut body, file.name.as_encoded_bytes());
+                put_ids(&mut body, file.tags.iter().map(&mut map), file.tags.len());
+            }
+        }
+        let mut out = Vec::with_capacity(body.len() + 1024);
+        out.extend_from_slice(MAGIC);
+        put_u32(&mut out, tags.len() as u32);
+        for tag in tags {
+            put_bytes(&mut out, tag.as_bytes());
+        }
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Index> {
+        let mut reader = Reader {
+            data: bytes.strip_prefix(MAGIC)?,
+        };
+        let ntags = reader.u32()? as usize;
+        let mut index = Index::empty();
+        for _ in 0..ntags {
+            let tag = std::str::from_utf8(reader.bytes()?).ok()?;
+            index.intern(tag);
+        }
+        let ids = |reader: &mut Reader| -> Option<Box<[u32]>> {
+            let n = reader.u32()? as usize;
+            (0..n)
+                .map(|_| reader.u32().filter(|id| (*id as usize) < ntags))
+                .collect()
+        };
+        let ndirs = reader.u32()? as usize;
+        for _ in 0..ndirs {
+            let path = PathBuf::from(os_string(reader.bytes()?));
+            let depth = reader.u32()? as usize;
+            let dir_mtime = reader.u64()?;
+            let ftag_mtime = reader.u64()?;
+            let ignore_mtime = reader.u64()?;
+            let has_metadata = reader.u8()? != 0;
+            let tags = ids(&mut reader)?;
+            let alltags = ids(&mut reader)?;
+            let nsubdirs = reader.u32()? as usize;
+            let subdirs = (0..nsubdirs)
+                .map(|_| reader.bytes().map(os_string))
+                .collect::<Option<_>>()?;
+            let nfiles = reader.u32()? as usize;
+            let files = (0..nfiles)
+                .map(|_| {
+                    Some(IndexedFile {
+                        name: os_string(reader.bytes()?),
+                        tags: ids(&mut reader)?,
+                    })
+                })
+                .collect::<Option<_>>()?;
+            index.dirs.push(IndexedDir {
+                path,
+                depth,
+                dir_mtime,
+                ftag_mtime,
+                ignore_mtime,
+                has_metadata,
+                tags,
+                alltags,
+                subdirs,
+                files,
+            });
+        }
+        reader.data.is_empty().then_some(index)
+    }
+}
+
+#[cfg(unix)]
+fn os_string(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes).to_os_string()
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: &[u8]) -> OsString {
+    OsString::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn put_u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, val: u64) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn put_ids(out: &mut Vec<u8>, ids: impl Iterator<Item = u32>, len: usize) {
+    put_u32(out, len as u32);
+    for id in ids {
+        put_u32(out, id);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .and_then(|b| b.try_into().ok())
+            .map(u64::from_le_bytes)
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_encode_decode() {
+        let mut index = Index::empty();
+        let (a, b, c) = (index.intern("a"), index.intern("b"), index.intern("c"));
+        index.dirs.push(IndexedDir {
+            path: PathBuf::new(),
+            depth: 1,
+            dir_mtime: 42,
+            ftag_mtime: 43,
+            ignore_mtime: 0,
+            has_metadata: true,
+            tags: Box::new([c]),
+            alltags: Box::new([a, c]),
+            subdirs: Box::new([OsString::from("sub dir\n")]),
+            files: Box::new([IndexedFile {
+                name: OsString::from("x.jpg"),
+                tags: Box::new([a, c]),
+            }]),
+        });
+        let _ = b; // Unused tags are dropped when encoding.
+        let bytes = index.encode();
+        let decoded = Index::decode(&bytes).unwrap();
+        assert_eq!(decoded.tags, ["c", "a"]);
+        assert_eq!(decoded.dirs.len(), 1);
+        let dir = &decoded.dirs[0];
+        assert_eq!((dir.dir_mtime, dir.ftag_mtime, dir.depth), (42, 43, 1));
+        assert_eq!(dir.subdirs[0], OsString::from("sub dir\n"));
+        assert_eq!(dir.files[0].name, OsString::from("x.jpg"));
+        let tags: Vec<_> = dir.files[0].tags.iter().map(|t| decoded.tag(*t)).collect();
+        assert_eq!(tags, ["a", "c"]);
+        // Truncated data is rejected.
+        assert!(Index::decode(&bytes[..(bytes.len() - 1)]).is_none());
+    }
+
+    /// Get the name and own tags of every file in the index.
+    fn file_tags(index: &Index) -> Vec<(String, Vec<String>)> {
+        let mut out = Vec::new();
+        index
+            .visit_files(|_dir, file, _inherited| {
+                out.push((
+                    file.name.to_string_lossy().to_string(),
+                    file.tags
+                        .iter()
+                        .map(|t| index.tag(*t).to_string())
+                        .collect(),
+                ))
+            })
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn t_refresh() {
+        let root = std::env::temp_dir().join("ftag_t_index_refresh");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "").unwrap();
+        let ftag = root.join(FTAG_FILE);
+        let set_mtime = |path: &Path, time: SystemTime| {
+            std::fs::File::open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        // Write the ftag file with the modification time `time`, and query the
+        // index. Saving the index touches the directory, so its modification
+        // time is reset afterwards to only observe the effect of the edit.
+        let query = |tag: &str, time: SystemTime| {
+            std::fs::write(&ftag, format!("[path]\na.txt\n[tags]\n{}\n", tag)).unwrap();
+            set_mtime(&ftag, time);
+            set_mtime(&root, time);
+            let index = Index::open(&root, &WalkOptions::default()).unwrap();
+            set_mtime(&root, time);
+            file_tags(&index.unwrap())
+        };
+        let expected = |tag: &str| vec![("a.txt".to_string(), vec![tag.to_string()])];
+        Index::update(&root).unwrap();
+        let now = SystemTime::now();
+        assert_eq!(query("beach", now), expected("beach"));
+        // Edited within the same second, without changing the modification
+        // time. The ftag file was modified too recently to trust it.
+        assert_eq!(query("sunny", now), expected("sunny"));
+        // Outside of the window, unchanged modification times are trusted, and
+        // new ones are noticed.
+        let old = now - Duration::from_secs(3600);
+        assert_eq!(query("cloud", old), expected("cloud"));
+        assert_eq!(query("storm", old), expected("cloud"));
+        assert_eq!(
+            query("storm", old + Duration::from_secs(1)),
+            expected("storm")
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
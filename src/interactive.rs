@@ -1,7 +1,7 @@
 use crate::{
     core::what_is,
     filter::{Filter, FilterParseError},
-    query::TagTable,
+    query::{TableTerms, TagTable},
 };
 use std::{fmt::Debug, path::PathBuf};
 
@@ -16,7 +16,7 @@ pub enum State {
 enum Command {
     Exit,
     Reset,
-    Filter(Filter),
+    Filter(Filter, TableTerms),
     WhatIs(PathBuf),
     Open(PathBuf),
 }
@@ -122,13 +122,21 @@ impl InteractiveSession {
                 Some(("open", numstr)) => Ok(Command::Open(self.parse_index_to_filepath(numstr)?)),
                 _ => Err(Error::InvalidCommand(cmd.to_string())),
             },
-            None => Ok(Command::Filter(
-                Filter::parse(
-                    &format!("{} {cmd}", self.filter_str),
-                    self.table.tag_parse_fn(),
+            None => {
+                let mut terms = TableTerms::default();
+                // The new filter is appended to the current one as a whole, and
+                // simplified so the filter doesn't keep growing deeper.
+                let filter = Filter::parse(
+                    &match self.filter_str.is_empty() {
+                        true => cmd.to_string(),
+                        false => format!("({}) {cmd}", self.filter_str),
+                    },
+                    self.table.tag_parse_fn(&mut terms),
                 )
-                .map_err(Error::InvalidFilter)?,
-            )),
+                .map_err(Error::InvalidFilter)?
+                .simplify();
+                Ok(Command::Filter(filter, terms))
+            }
         }
     }
 
@@ -182,7 +190,7 @@ impl InteractiveSession {
     }
 
     fn last_word_start(&self) -> usize {
-        const DELIMS: &str = " ()&|!/";
+        const DELIMS: &str = " ()&|!^/";
         DELIMS
             .chars()
             .map(|ch| match self.command.rfind(ch) {
@@ -246,14 +254,33 @@ impl InteractiveSession {
                                 ))
                                 .to_string();
                         }
-                        Command::Filter(filter) => {
+                        Command::Filter(filter, terms) => {
                             self.filtered_indices.clear();
                             self.filtered_indices.extend(
-                                (0..self.num_files())
-                                    .filter(|fi| filter.eval(|ti| self.table.flags(*fi)[ti])),
+                                (0..self.num_files()).filter(|fi| {
+                                    filter.eval(|ti| self.table.check(*fi, ti, &terms))
+                                }),
                             );
                             self.update_lists();
                             self.filter_str = filter.text(self.table.tags());
+                            // Explain why the results might be empty.
+                            let warnings: Vec<String> = terms
+                                .unsupported
+                                .iter()
+                                .map(|w| {
+                                    format!(
+                                        "'{}' never matches, because descriptions can't be queried here.",
+                                        w
+                                    )
+                                })
+                                .chain(
+                                    terms
+                                        .unknown
+                                        .iter()
+                                        .map(|t| self.table.unknown_tag(t).to_string()),
+                                )
+                                .collect();
+                            self.echo = warnings.join("\n");
                             self.state = State::ListsUpdated;
                         }
                         Command::Reset => self.reset(),
@@ -356,3 +383,67 @@ impl InteractiveSession {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core::FTAG_FILE, walk::WalkOptions};
+
+    fn session(name: &str) -> InteractiveSession {
+        let root = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for file in ["a.txt", "b.txt"] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        std::fs::write(
+            root.join(FTAG_FILE),
+            "[path]\na.txt\n[tags]\nbeach\n[desc]\nSunset\n[path]\nb.txt\n[tags]\nbench\n",
+        )
+        .unwrap();
+        let table = TagTable::from_dir(root.clone(), &WalkOptions::default()).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        InteractiveSession::init(table)
+    }
+
+    fn run(session: &mut InteractiveSession, command: &str) {
+        *session.command_mut() = command.to_string();
+        session.process_input();
+    }
+
+    #[test]
+    fn t_desc_predicates() {
+        let mut session = session("ftag_t_interactive_desc");
+        run(&mut session, "beach | desc:sunset");
+        assert_eq!(session.filelist().len(), 1);
+        assert_eq!(
+            session.echo(),
+            "'desc:sunset' never matches, because descriptions can't be queried here."
+        );
+        assert_eq!(session.filter_str(), "beach | desc:sunset");
+    }
+
+    #[test]
+    fn t_compose_filters() {
+        let mut session = session("ftag_t_interactive_compose");
+        run(&mut session, "beach & beahc");
+        assert!(session.filelist().is_empty());
+        assert_eq!(
+            session.echo(),
+            "'beahc' is not a known tag. Did you mean 'beach'?"
+        );
+        // Unknown tags are kept in the filter.
+        assert_eq!(session.filter_str(), "beach & beahc");
+        run(&mut session, "| bench");
+        assert_eq!(session.filter_str(), "(beach & beahc) | bench");
+        assert_eq!(session.filelist().len(), 1);
+        // Constants can be parsed again, without warnings.
+        run(&mut session, "/reset");
+        run(&mut session, "beach & !beach");
+        assert_eq!(session.filter_str(), "NOT_A_TAG");
+        run(&mut session, "| bench");
+        assert_eq!(session.filter_str(), "bench");
+        assert_eq!(session.echo(), "");
+        assert_eq!(session.filelist().len(), 1);
+    }
+}
@@ -1,8 +1,15 @@
+pub mod config;
 pub mod core;
 pub mod filter;
+pub mod fingerprint;
+pub mod index;
 pub mod interactive;
 pub mod load;
+pub mod output;
 pub mod query;
+pub mod search;
 pub mod tui;
+pub mod walk;
 
-mod walk;
+mod ignore;
+mod rewrite;
@@ -6,6 +6,7 @@ use aho_corasick::{AhoCorasick, Match};
 use fast_glob::glob_match;
 use smallvec::SmallVec;
 use std::{
+    borrow::Cow,
     ffi::OsStr,
     fmt::Display,
     fs::File,
@@ -36,7 +37,7 @@ fn infer_year_range(mut input: &str) -> Option<Range<u16>> {
         return None;
     }
     let first: u16 = {
-        let word = &input[..4];
+        let word = input.get(..4)?;
         if word.chars().all(|b| b.is_ascii_digit()) {
             word.parse().ok()?
         } else {
@@ -48,16 +49,16 @@ fn infer_year_range(mut input: &str) -> Option<Range<u16>> {
         if input.len() < 4 {
             return Some(first..(first + 1));
         }
-        let word = &input[..4];
-        if word.chars().all(|b| b.is_ascii_digit()) {
+        let word = input.get(..4).unwrap_or_default();
+        if word.len() == 4 && word.chars().all(|b| b.is_ascii_digit()) {
             let second = word.parse().unwrap_or(first);
             return Some(first..(second + 1));
         } else if let Some(input) = input.strip_prefix("to_") {
             if input.len() < 4 {
                 return Some(first..(first + 1));
             }
-            let word = &input[..4];
-            if word.chars().all(|b| b.is_ascii_digit()) {
+            let word = input.get(..4).unwrap_or_default();
+            if word.len() == 4 && word.chars().all(|b| b.is_ascii_digit()) {
                 let second = word.parse().unwrap_or(first);
                 return Some(first..(second + 1));
             }
@@ -68,16 +69,17 @@ fn infer_year_range(mut input: &str) -> Option<Range<u16>> {
 
 /// Get an iterator over tags inferred from the format of the file. The input is
 /// expected to be the path / name of the file.
-fn infer_format_tag(input: &str) -> impl Iterator<Item = Tag> + use<'_> {
+fn infer_format_tag(input: &str) -> impl Iterator<Item = Tag<'_>> + use<'_> {
     const EXT_TAG_MAP: &[(&[&str], &str)] = &[
         (&[".mov", ".flv", ".mp4", ".3gp"], "video"),
         (&[".png", ".jpg", ".jpeg", ".bmp", ".webp", ".gif"], "image"),
     ];
     EXT_TAG_MAP.iter().filter_map(|(exts, tag)| {
-        if exts
-            .iter()
-            .any(|ext| input[input.len().saturating_sub(ext.len())..].eq_ignore_ascii_case(ext))
-        {
+        if exts.iter().any(|ext| {
+            input
+                .get(input.len().saturating_sub(ext.len())..)
+                .is_some_and(|end| end.eq_ignore_ascii_case(ext))
+        }) {
             Some(Tag::Format(tag))
         } else {
             None
@@ -87,7 +89,7 @@ fn infer_format_tag(input: &str) -> impl Iterator<Item = Tag> + use<'_> {
 
 /// Get an iterator over all the implicit tags that can be inferred
 /// from the name of the file or directory.
-pub(crate) fn infer_implicit_tags(name: &str) -> impl Iterator<Item = Tag> + use<'_> {
+pub(crate) fn infer_implicit_tags(name: &str) -> impl Iterator<Item = Tag<'_>> + use<'_> {
     infer_year_range(name)
         .into_iter()
         .flatten()
@@ -95,6 +97,26 @@ pub(crate) fn infer_implicit_tags(name: &str) -> impl Iterator<Item = Tag> + use
         .chain(infer_format_tag(name))
 }
 
+/// Tags are hierarchical, with the levels separated by '/'. A file tagged with
+/// `place/europe/paris` also has the tags `place/europe` and `place`. Get an
+/// iterator over the ancestors of `tag`, from the outermost, followed by `tag`
+/// itself.
+pub(crate) fn implied_tags(tag: &str) -> impl Iterator<Item = &str> {
+    tag.match_indices('/')
+        .map(|(i, _)| &tag[..i])
+        .chain(std::iter::once(tag))
+}
+
+/// Get an iterator over the terms of a query that are satisfied by a file that
+/// has `tag`. These are the tags implied by `tag`, and the prefix form of each
+/// ancestor, e.g. `place/`, which matches any tag below `place`, but not
+/// `place` itself.
+pub(crate) fn tag_query_terms(tag: &str) -> impl Iterator<Item = &str> {
+    tag.match_indices('/')
+        .flat_map(|(i, _)| [&tag[..i], &tag[..=i]])
+        .chain(std::iter::once(tag))
+}
+
 /// Get the filename from the path as a string. If the path cannot be a valid
 /// string, an error is returned. If the path doesn't exist, an empty string is
 /// returned.
@@ -108,9 +130,20 @@ pub(crate) fn get_filename_str(path: &Path) -> Result<&str, Error> {
     }
 }
 
+/// Same as `get_filename_str`, except that invalid UTF-8 is replaced instead
+/// of returning an error. Used while walking directories, where the walker
+/// reports such names.
+pub(crate) fn get_filename_lossy(path: &Path) -> Cow<'_, str> {
+    match path.file_name() {
+        Some(fname) => fname.to_string_lossy(),
+        None => Cow::Borrowed(""),
+    }
+}
+
 /// This datastructure is responsible for finding matches between the
 /// files on disk, and globs listed in the ftag file. This can be
 /// reused for multiple folders to avoid reallocations.
+#[derive(Default)]
 pub(crate) struct GlobMatches {
     file_matches: Vec<SmallVec<[usize; 4]>>,
     glob_matches: Vec<bool>,
@@ -268,6 +301,7 @@ impl<'a> DirData<'a> {
 }
 
 /// Options for loading the file data from an ftag file.
+#[derive(Clone, Copy)]
 pub(crate) enum FileLoadingOptions {
     /// Skip loading the file data altogether.
     Skip,
@@ -278,6 +312,7 @@ pub(crate) enum FileLoadingOptions {
 }
 
 /// Options for loading data from an ftag file.
+#[derive(Clone, Copy)]
 pub(crate) struct LoaderOptions {
     /// Load tags of the directory.
     dir_tags: bool,
@@ -324,7 +359,8 @@ static AC_PARSER: LazyLock<AhoCorasick> = LazyLock::new(|| {
     AhoCorasick::new(HEADER_STR).expect("FATAL: Unable to initialize the parser")
 });
 
-enum HeaderType {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HeaderType {
     Path,
     Tags,
     Desc,
@@ -357,6 +393,125 @@ impl Header {
     }
 }
 
+/// Lines that start with this character, ignoring leading whitespace, are
+/// comments.
+pub(crate) const COMMENT_PREFIX: char = '#';
+
+/// Lines that start with this character followed by `COMMENT_PREFIX` are not
+/// comments. Only the first instance of this character is removed when reading
+/// such a line, so paths and descriptions can start with `#`.
+const ESCAPE: char = '\\';
+
+/// Check if `line` is a comment.
+pub(crate) fn is_comment(line: &str) -> bool {
+    line.trim_start().starts_with(COMMENT_PREFIX)
+}
+
+/// Check if `line` starts with `COMMENT_PREFIX` after any number of escapes.
+fn needs_escape(line: &str) -> bool {
+    line.trim_start_matches(ESCAPE).starts_with(COMMENT_PREFIX)
+}
+
+/// Remove the escape from the start of `line`, if it has one. The line is
+/// expected to not have leading whitespace.
+pub(crate) fn unescape(line: &str) -> &str {
+    match line.strip_prefix(ESCAPE) {
+        Some(rest) if needs_escape(rest) => rest,
+        _ => line,
+    }
+}
+
+/// Escape `line` so that it is not read back as a comment.
+pub(crate) fn escape(line: &str) -> Cow<'_, str> {
+    if needs_escape(line) {
+        Cow::Owned(format!("{}{}", ESCAPE, line))
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+/// Find the headers in `input`, skipping the ones inside comments.
+fn find_headers(input: &str) -> impl Iterator<Item = Match> + '_ {
+    AC_PARSER.find_iter(input).filter(|mat| {
+        let line_start = input[..mat.start()].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        !is_comment(&input[line_start..mat.start()])
+    })
+}
+
+/// Get the tags listed in the content of a `tags` header.
+pub(crate) fn split_tags(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .filter(|line| !is_comment(line))
+        .flat_map(|line| unescape(line.trim_start()).split_whitespace())
+}
+
+/// Remove the comment lines from the content of every header in `text`, and
+/// the escapes from the lines that remain. The remaining lines of each block
+/// are moved up and the freed space is filled with line breaks, so the headers
+/// don't move, and their content is the same after trimming.
+fn strip_comments(text: &mut String) {
+    if !text.contains(COMMENT_PREFIX) {
+        return; // Avoid allocating.
+    }
+    let headers: Vec<Range<usize>> = find_headers(text).map(|m| m.start()..m.end()).collect();
+    let blocks: Vec<Range<usize>> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, h)| h.end..headers.get(i + 1).map_or(text.len(), |next| next.start))
+        .collect();
+    let mut stripped = String::new();
+    for block in blocks {
+        let content = &text[block.clone()];
+        if !content.contains(COMMENT_PREFIX) {
+            continue;
+        }
+        stripped.clear();
+        for line in content.split_inclusive('\n').filter(|l| !is_comment(l)) {
+            let indent = line.len() - line.trim_start().len();
+            stripped.push_str(&line[..indent]);
+            stripped.push_str(unescape(&line[indent..]));
+        }
+        let freed = content.len() - stripped.len();
+        stripped.extend(std::iter::repeat_n('\n', freed));
+        text.replace_range(block, &stripped);
+    }
+}
+
+/// Byte spans of a header and its content in the text of an ftag file. The
+/// content spans everything between the end of the header and the start of the
+/// next header, including whitespace.
+#[derive(Clone)]
+pub(crate) struct BlockSpan {
+    pub kind: HeaderType,
+    pub header: Range<usize>,
+    pub content: Range<usize>,
+}
+
+/// Find the spans of all the headers and their contents in `input`. Unlike
+/// `load_impl`, this doesn't trim or interpret anything, so the spans can be
+/// used to modify the text while leaving everything else byte-identical.
+pub(crate) fn parse_blocks(input: &str, filepath: &Path) -> Result<Vec<BlockSpan>, Error> {
+    let mut blocks: Vec<BlockSpan> = Vec::new();
+    for mat in find_headers(input) {
+        let header = Header::from_match(mat).ok_or_else(|| {
+            Error::CannotParseFtagFile(
+                filepath.to_path_buf(),
+                "FATAL: Error when searching for headers in the file.".into(),
+            )
+        })?;
+        if let Some(last) = blocks.last_mut() {
+            last.content.end = header.start;
+        }
+        blocks.push(BlockSpan {
+            kind: header.kind,
+            header: header.start..header.end,
+            content: header.end..input.len(),
+        });
+    }
+    Ok(blocks)
+}
+
 fn load_impl<'text>(
     input: &'text str,
     filepath: &Path,
@@ -369,7 +524,7 @@ fn load_impl<'text>(
         tags: dirtags,
         globs: files,
     } = dst;
-    let mut headers = AC_PARSER.find_iter(input);
+    let mut headers = find_headers(input);
     // We store the data of the file we're currently parsing as:
     // (text containing a list of globs, list of tags, optional description).
     let mut current_unit: Option<(&str, Range<usize>, Option<&str>)> = None;
@@ -437,7 +592,7 @@ fn load_impl<'text>(
                         if tags.start == tags.end {
                             // No tags found for the current unit.
                             let before = alltags.len();
-                            alltags.extend(content.split_whitespace());
+                            alltags.extend(split_tags(content));
                             *tags = before..alltags.len();
                         } else {
                             return Err(Error::CannotParseFtagFile(
@@ -453,7 +608,7 @@ fn load_impl<'text>(
                     if dirtags.start == dirtags.end {
                         // No directory tags found.
                         let before = alltags.len();
-                        alltags.extend(content.split_whitespace());
+                        alltags.extend(split_tags(content));
                         *dirtags = before..alltags.len();
                     } else {
                         return Err(Error::CannotParseFtagFile(
@@ -540,6 +695,7 @@ impl Loader {
             .map_err(|_| Error::CannotReadStoreFile(filepath.to_path_buf()))?
             .read_to_string(&mut self.raw_text)
             .map_err(|_| Error::CannotReadStoreFile(filepath.to_path_buf()))?;
+        strip_comments(&mut self.raw_text);
         self.parsed.reset();
         let borrowed = unsafe {
             /*
@@ -566,12 +722,73 @@ mod test {
             let actual: Vec<_> = infer_implicit_tags(input).map(|t| t.to_string()).collect();
             assert_eq!(actual, expected);
         }
-        let inputs = vec!["1998_MyDirectory", "1998_MyFile.pdf"];
+        let inputs = vec!["1998_MyDirectory", "1998_MyFile.pdf", "1998_ñandú"];
         let expected = vec!["1998"];
         for input in inputs {
             let actual: Vec<_> = infer_implicit_tags(input).map(|t| t.to_string()).collect();
             assert_eq!(actual, expected);
         }
+        // Multi-byte characters must not cause a panic.
+        for input in ["abc\u{fffd}.jpg", "\u{fffd}", "ñandú"] {
+            assert!(infer_year_range(input).is_none());
+        }
+    }
+
+    #[test]
+    fn t_comments() {
+        let mut text = String::from(
+            "# Comment at the start
+[tags]
+dirtag # not a comment
+  # indented comment
+[path]
+a.jpg
+# old.jpg
+\\#draft.txt
+\\\\#odd.txt
+[tags]
+tag1
+# tag2
+[desc]
+\\#1 favourite shot.
+# [tags] Hidden line.
+Line two.",
+        );
+        let headers: Vec<_> = find_headers(&text).map(|m| m.start()).collect();
+        strip_comments(&mut text);
+        // The headers don't move.
+        assert_eq!(
+            find_headers(&text).map(|m| m.start()).collect::<Vec<_>>(),
+            headers
+        );
+        let mut data = DirData::default();
+        load_impl(
+            text.trim(),
+            Path::new(".ftag"),
+            &LoaderOptions::new(
+                true,
+                true,
+                FileLoadingOptions::Load {
+                    file_tags: true,
+                    file_desc: true,
+                },
+            ),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(data.tags(), ["dirtag", "#", "not", "a", "comment"]);
+        // Escaped lines can start with the comment prefix.
+        let globs: Vec<_> = data.globs.iter().map(|g| g.path).collect();
+        assert_eq!(globs, ["a.jpg", "#draft.txt", "\\#odd.txt"]);
+        assert_eq!(data.globs[1].tags(&data.alltags), ["tag1"]);
+        assert_eq!(data.globs[1].desc, Some("#1 favourite shot.\nLine two."));
+        for line in ["#a", "\\#a", "\\\\#a", "a#", "\\a"] {
+            assert_eq!(unescape(&escape(line)), line);
+        }
+        // Headers inside comments are skipped.
+        let blocks = parse_blocks("# [path]\n[tags]\na\n", Path::new(".ftag")).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].header, 9..15);
     }
 
     #[test]
@@ -583,4 +800,23 @@ mod test {
             assert_eq!(&actual, expected);
         }
     }
+
+    #[test]
+    fn t_hierarchical_tags() {
+        assert_eq!(
+            implied_tags("place/europe/paris").collect::<Vec<_>>(),
+            ["place", "place/europe", "place/europe/paris"]
+        );
+        assert_eq!(implied_tags("beach").collect::<Vec<_>>(), ["beach"]);
+        assert_eq!(
+            tag_query_terms("place/europe/paris").collect::<Vec<_>>(),
+            [
+                "place",
+                "place/",
+                "place/europe",
+                "place/europe/",
+                "place/europe/paris"
+            ]
+        );
+    }
 }
@@ -0,0 +1,421 @@
+use std::{
+    borrow::Cow,
+    fmt::Write as _,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Format in which the results of a command are printed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// Human readable text.
+    Text,
+    /// A single JSON array containing all the records.
+    Json,
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma separated values, with a header row.
+    Csv,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 4] = ["text", "json", "jsonl", "csv"];
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "jsonl" => Some(Format::JsonLines),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// Value of a field of a record.
+pub enum Field<'a> {
+    Str(&'a str),
+    Path(&'a Path),
+    Optional(Option<&'a str>),
+    List(&'a [String]),
+    Number(usize),
+    Float(f64),
+}
+
+/// Something that can be printed as a record with named fields.
+pub trait Record {
+    /// Names of the fields, in the same order as `fields`.
+    const FIELDS: &'static [&'static str];
+
+    fn fields(&self) -> Vec<Field<'_>>;
+}
+
+/// A file or a directory, along with all its tags and its description.
+pub struct FileRecord {
+    pub path: PathBuf,
+    /// Tags listed for this file in the ftag file.
+    pub explicit: Vec<String>,
+    /// Tags inherited from the directories containing this file.
+    pub inherited: Vec<String>,
+    /// Tags inferred from the name of this file.
+    pub implicit: Vec<String>,
+    pub desc: Option<String>,
+    /// All of the above tags, sorted and without duplicates.
+    pub tags: Vec<String>,
+}
+
+impl FileRecord {
+    pub fn new(
+        path: PathBuf,
+        explicit: Vec<String>,
+        inherited: Vec<String>,
+        implicit: Vec<String>,
+        desc: Option<String>,
+    ) -> FileRecord {
+        let mut tags: Vec<String> = explicit
+            .iter()
+            .chain(inherited.iter())
+            .chain(implicit.iter())
+            .cloned()
+            .collect();
+        tags.sort_unstable();
+        tags.dedup();
+        FileRecord {
+            path,
+            explicit,
+            inherited,
+            implicit,
+            desc: desc
+                .map(|d| d.trim_end().to_string())
+                .filter(|d| !d.is_empty()),
+            tags,
+        }
+    }
+}
+
+impl Record for FileRecord {
+    const FIELDS: &'static [&'static str] =
+        &["path", "tags", "explicit", "inherited", "implicit", "desc"];
+
+    fn fields(&self) -> Vec<Field<'_>> {
+        vec![
+            Field::Path(&self.path),
+            Field::List(&self.tags),
+            Field::List(&self.explicit),
+            Field::List(&self.inherited),
+            Field::List(&self.implicit),
+            Field::Optional(self.desc.as_deref()),
+        ]
+    }
+}
+
+pub struct TagRecord<'a> {
+    pub tag: &'a str,
+    /// Other names of this tag, declared in the config files.
+    pub aliases: &'a [String],
+}
+
+impl Record for TagRecord<'_> {
+    const FIELDS: &'static [&'static str] = &["tag", "aliases"];
+
+    fn fields(&self) -> Vec<Field<'_>> {
+        vec![Field::Str(self.tag), Field::List(self.aliases)]
+    }
+}
+
+pub struct PathRecord<'a>(pub &'a Path);
+
+impl Record for PathRecord<'_> {
+    const FIELDS: &'static [&'static str] = &["path"];
+
+    fn fields(&self) -> Vec<Field<'_>> {
+        vec![Field::Path(self.0)]
+    }
+}
+
+pub struct CountRecord {
+    pub files: usize,
+    pub tags: usize,
+}
+
+impl Record for CountRecord {
+    const FIELDS: &'static [&'static str] = &["files", "tags"];
+
+    fn fields(&self) -> Vec<Field<'_>> {
+        vec![Field::Number(self.files), Field::Number(self.tags)]
+    }
+}
+
+/// Options that control how the results of a command are printed.
+#[derive(Clone, Copy)]
+pub struct OutputOptions {
+    pub format: Format,
+    /// Separate the results with NUL instead of newlines in text format.
+    pub print0: bool,
+    /// Print absolute paths instead of paths relative to the root of the walk.
+    pub absolute: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: Format::Text,
+            print0: false,
+            absolute: false,
+        }
+    }
+}
+
+/// Prints records to stdout in the chosen format. JSON arrays are opened
+/// when the printer is created and closed by `finish`, and CSV headers are
+/// printed when the printer is created. In text format, only the first field
+/// of each record is printed, and paths are written as raw bytes, so that
+/// names that are not valid UTF-8 are printed as they are.
+pub struct Printer {
+    format: Format,
+    separator: u8,
+    root: Option<PathBuf>,
+    count: usize,
+    buf: String,
+}
+
+impl Printer {
+    /// Create a printer for records of type `R`. The paths in the records are
+    /// expected to be relative to `root`.
+    pub fn new<R: Record>(options: &OutputOptions, root: &Path) -> Printer {
+        match options.format {
+            Format::Json => print!("["),
+            Format::Csv => {
+                let mut header = String::new();
+                for (i, name) in R::FIELDS.iter().enumerate() {
+                    if i > 0 {
+                        header.push(',');
+                    }
+                    write_csv_str(&mut header, name);
+                }
+                println!("{}", header);
+            }
+            Format::Text | Format::JsonLines => {}
+        }
+        Printer {
+            format: options.format,
+            separator: if options.print0 { b'\0' } else { b'\n' },
+            root: options.absolute.then(|| root.to_path_buf()),
+            count: 0,
+            buf: String::new(),
+        }
+    }
+
+    pub fn print<R: Record>(&mut self, record: &R) {
+        self.buf.clear();
+        let fields = record.fields();
+        let root = self.root.as_deref();
+        match self.format {
+            Format::Text => match fields.first() {
+                Some(Field::Path(path)) => return self.print_path(path),
+                Some(field) => write_text_field(&mut self.buf, field),
+                None => {}
+            },
+            Format::Json => {
+                if self.count > 0 {
+                    self.buf.push(',');
+                }
+                self.buf.push_str("\n  ");
+                write_json_object(&mut self.buf, R::FIELDS, &fields, root);
+            }
+            Format::JsonLines => write_json_object(&mut self.buf, R::FIELDS, &fields, root),
+            Format::Csv => {
+                for (i, field) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.buf.push(',');
+                    }
+                    write_csv_field(&mut self.buf, field, root);
+                }
+            }
+        }
+        self.count += 1;
+        let mut out = std::io::stdout().lock();
+        let _ = out.write_all(self.buf.as_bytes());
+        if self.format == Format::Text {
+            let _ = out.write_all(&[self.separator]);
+        } else if self.format != Format::Json {
+            let _ = out.write_all(b"\n");
+        }
+    }
+
+    /// Print a path in text format.
+    pub fn print_path(&mut self, path: &Path) {
+        self.print_prefixed_path("", path);
+    }
+
+    /// Print a path in text format, preceded by `prefix`.
+    pub fn print_prefixed_path(&mut self, prefix: &str, path: &Path) {
+        self.count += 1;
+        let path = match &self.root {
+            Some(root) => Cow::Owned(root.join(path)),
+            None => Cow::Borrowed(path),
+        };
+        let mut out = std::io::stdout().lock();
+        let _ = out.write_all(prefix.as_bytes());
+        let _ = out.write_all(path.as_os_str().as_encoded_bytes());
+        let _ = out.write_all(&[self.separator]);
+    }
+
+    pub fn finish(self) {
+        if self.format == Format::Json {
+            if self.count > 0 {
+                println!("\n]");
+            } else {
+                println!("]");
+            }
+        }
+    }
+}
+
+fn resolve<'a>(path: &'a Path, root: Option<&Path>) -> Cow<'a, str> {
+    match root {
+        Some(root) => Cow::Owned(root.join(path).to_string_lossy().into_owned()),
+        None => path.to_string_lossy(),
+    }
+}
+
+fn write_text_field(out: &mut String, field: &Field) {
+    match field {
+        Field::Str(s) => out.push_str(s),
+        Field::Path(p) => out.push_str(&p.to_string_lossy()),
+        Field::Optional(s) => out.push_str(s.unwrap_or_default()),
+        Field::List(items) => write_joined(out, items),
+        Field::Number(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        Field::Float(x) => {
+            let _ = write!(out, "{:.4}", x);
+        }
+    }
+}
+
+fn write_joined(out: &mut String, items: &[String]) {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        out.push_str(item);
+    }
+}
+
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_json_object(out: &mut String, names: &[&str], fields: &[Field], root: Option<&Path>) {
+    out.push('{');
+    for (i, (name, field)) in names.iter().zip(fields.iter()).enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_json_str(out, name);
+        out.push(':');
+        match field {
+            Field::Str(s) => write_json_str(out, s),
+            Field::Path(p) => write_json_str(out, &resolve(p, root)),
+            Field::Optional(Some(s)) => write_json_str(out, s),
+            Field::Optional(None) => out.push_str("null"),
+            Field::List(items) => {
+                out.push('[');
+                for (j, item) in items.iter().enumerate() {
+                    if j > 0 {
+                        out.push(',');
+                    }
+                    write_json_str(out, item);
+                }
+                out.push(']');
+            }
+            Field::Number(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            Field::Float(x) => {
+                let _ = write!(out, "{:.4}", x);
+            }
+        }
+    }
+    out.push('}');
+}
+
+fn write_csv_str(out: &mut String, s: &str) {
+    if s.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&s.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(s);
+    }
+}
+
+/// Lists are written as a single space separated value. This is unambiguous,
+/// because tags cannot contain whitespace.
+fn write_csv_field(out: &mut String, field: &Field, root: Option<&Path>) {
+    match field {
+        Field::Str(s) | Field::Optional(Some(s)) => write_csv_str(out, s),
+        Field::Path(p) => write_csv_str(out, &resolve(p, root)),
+        Field::Optional(None) => {}
+        Field::List(items) => {
+            let mut joined = String::new();
+            write_joined(&mut joined, items);
+            write_csv_str(out, &joined);
+        }
+        Field::Number(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        Field::Float(x) => {
+            let _ = write!(out, "{:.4}", x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn t_json_escape() {
+        let mut out = String::new();
+        write_json_str(&mut out, "a \"quoted\"\nname\\with\u{1}control.jpg");
+        assert_eq!(out, "\"a \\\"quoted\\\"\\nname\\\\with\\u0001control.jpg\"");
+    }
+
+    #[test]
+    fn t_csv_escape() {
+        let record = FileRecord::new(
+            PathBuf::from("dir/a, \"b\".jpg"),
+            vec!["beach".to_string()],
+            vec!["2019".to_string(), "beach".to_string()],
+            vec!["jpg".to_string()],
+            Some("Line one\nline two".to_string()),
+        );
+        let mut out = String::new();
+        for (i, field) in record.fields().iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write_csv_field(&mut out, field, None);
+        }
+        assert_eq!(
+            out,
+            "\"dir/a, \"\"b\"\".jpg\",2019 beach jpg,beach,2019 beach,jpg,\"Line one\nline two\""
+        );
+    }
+}
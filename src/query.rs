@@ -1,14 +1,21 @@
 use crate::{
+    config::Config,
     core::Error,
-    filter::Filter,
+    filter::{unquote, DescPredicate, FilePredicate, FileStat, Filter, TagPattern},
+    index::Index,
     load::{
-        get_filename_str, infer_implicit_tags, FileLoadingOptions, GlobMatches, LoaderOptions, Tag,
+        get_filename_lossy, implied_tags, infer_implicit_tags, tag_query_terms, DirData,
+        FileLoadingOptions, GlobMatches, LoaderOptions, Tag,
     },
-    walk::{DirTree, MetaData, VisitedDir},
+    output::{FileRecord, Format, OutputOptions, Printer},
+    walk::{lock, walk_parallel, DirTree, MetaData, VisitedDir, WalkOptions},
 };
 use std::{
+    cell::OnceCell,
     collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
     path::{Path, PathBuf},
+    sync::{Mutex, OnceLock, PoisonError},
 };
 
 /*
@@ -19,30 +26,38 @@ track of inherited tags. We do this by pushing all the tags into a vector, and
 by storing the offsets that separate contiguous chunks of this vector across the
 depth-first chain of directories currently being traversed.
  */
-struct InheritedTags {
-    /// Indices of currently loaded tags.
-    tag_indices: Vec<usize>,
+pub(crate) struct InheritedTags<T> {
+    /// Currently loaded tags.
+    pub(crate) tags: Vec<T>,
     /// Offsets that separate the tags across the depth-first chain of directories currently being traversed.
     offsets: Vec<usize>,
     /// Current depth of the traversal.
     depth: usize,
 }
 
-impl InheritedTags {
+impl<T> InheritedTags<T> {
+    pub(crate) fn new() -> Self {
+        InheritedTags {
+            tags: Vec::new(),
+            offsets: Vec::new(),
+            depth: 0,
+        }
+    }
+
     /// Update the inherited tags for the specified `newdepth`. A new depth that
     /// is 1 more than the current depth implies traversing deeper into the
     /// directory tree. A new depth that is smaller than the current depth
     /// implies popping all the tags inherited from folders deeper than the new
     /// depth.
-    fn update(&mut self, newdepth: usize) -> Result<(), Error> {
+    pub(crate) fn update(&mut self, newdepth: usize) -> Result<(), Error> {
         if self.depth + 1 == newdepth {
-            self.offsets.push(self.tag_indices.len());
+            self.offsets.push(self.tags.len());
         } else if self.depth >= newdepth {
-            let mut marker = self.tag_indices.len();
+            let mut marker = self.tags.len();
             for _ in 0..(self.depth + 1 - newdepth) {
                 marker = self.offsets.pop().ok_or(Error::DirectoryTraversalFailed)?;
             }
-            self.tag_indices.truncate(marker);
+            self.tags.truncate(marker);
             self.offsets.push(marker);
         } else {
             return Err(Error::DirectoryTraversalFailed);
@@ -52,8 +67,32 @@ impl InheritedTags {
     }
 }
 
-/// Returns the number of files and the number of tags.
-pub fn count_files_tags(path: PathBuf) -> Result<(usize, usize), Error> {
+/// Returns the number of files and the number of tags. Aliases of a tag are
+/// not counted separately.
+pub fn count_files_tags(path: PathBuf, walk: &WalkOptions) -> Result<(usize, usize), Error> {
+    let config = Config::load(&path)?;
+    if let Some(index) = Index::open(&path, walk)? {
+        let mut used = vec![false; index.num_tags()];
+        let mut numfiles = 0usize;
+        for dir in index.dirs.iter().filter(|d| d.has_metadata) {
+            numfiles += dir.files.len();
+            for id in dir
+                .alltags
+                .iter()
+                .chain(dir.files.iter().flat_map(|f| f.tags.iter()))
+            {
+                used[*id as usize] = true;
+            }
+        }
+        let numtags = used
+            .iter()
+            .enumerate()
+            .filter(|(_id, u)| **u)
+            .map(|(id, _u)| config.canonical(index.tag(id as u32)))
+            .collect::<HashSet<_>>()
+            .len();
+        return Ok((numfiles, numtags));
+    }
     let mut matcher = GlobMatches::new();
     let mut alltags = HashSet::new();
     let mut numfiles = 0usize;
@@ -67,6 +106,7 @@ pub fn count_files_tags(path: PathBuf) -> Result<(usize, usize), Error> {
                 file_desc: false,
             },
         ),
+        walk,
     )?;
     while let Some(VisitedDir {
         rel_dir_path,
@@ -81,16 +121,17 @@ pub fn count_files_tags(path: PathBuf) -> Result<(usize, usize), Error> {
             MetaData::Ok(data) => {
                 // Collect all tags.
                 alltags.extend(data.alltags.iter().map(|t| t.to_string()).chain(
-                    infer_implicit_tags(get_filename_str(rel_dir_path)?).map(|t| t.to_string()),
+                    infer_implicit_tags(&get_filename_lossy(rel_dir_path)).map(|t| t.to_string()),
                 ));
                 // Collect all tracked files.
                 matcher.find_matches(files, &data.globs, false);
                 files.iter().enumerate().fold(0usize, |numfiles, (fi, f)| {
                     match matcher.is_file_matched(fi) {
                         true => {
-                            if let Some(name) = f.name().to_str() {
-                                alltags.extend(infer_implicit_tags(name).map(|t| t.to_string()));
-                            }
+                            alltags.extend(
+                                infer_implicit_tags(&f.name().to_string_lossy())
+                                    .map(|t| t.to_string()),
+                            );
                             numfiles + 1
                         }
                         false => numfiles,
@@ -104,98 +145,657 @@ pub fn count_files_tags(path: PathBuf) -> Result<(usize, usize), Error> {
             }
         }
     }
-    Ok((numfiles, alltags.len()))
+    dir.finish()?;
+    let numtags = alltags
+        .iter()
+        .map(|t| config.canonical(t))
+        .collect::<HashSet<_>>()
+        .len();
+    Ok((numfiles, numtags))
 }
 
-pub fn run_query(dirpath: PathBuf, filter: &str) -> Result<(), Error> {
-    let mut tag_index = BTreeMap::<String, usize>::new();
-    let filter = Filter::parse(filter, |tag| {
-        let size = tag_index.len();
-        let index = *tag_index.entry(tag.to_string()).or_insert(size);
-        Filter::Tag(index)
-    })
-    .map_err(Error::InvalidFilter)?;
-    let tag_index = tag_index; // Immutable.
-    let mut inherited = InheritedTags {
-        tag_indices: Vec::new(),
-        offsets: Vec::new(),
-        depth: 0,
-    };
-    let mut matcher = GlobMatches::new();
-    let mut dir = DirTree::new(
+/// Build the record of a file that matched the globs with indices `globs` in
+/// `data`. The description of the file is made of the descriptions of the
+/// matching globs, followed by the description of the directory. All tags are
+/// replaced with their canonical names.
+pub(crate) fn file_record(
+    path: PathBuf,
+    data: &DirData,
+    globs: impl Iterator<Item = usize>,
+    filename: &str,
+    inherited: &[String],
+    config: &Config,
+) -> FileRecord {
+    let mut explicit = Vec::new();
+    let mut desc = Vec::new();
+    for g in globs.map(|gi| &data.globs[gi]) {
+        explicit.extend(
+            g.tags(&data.alltags)
+                .iter()
+                .map(|t| config.canonical(t).to_string()),
+        );
+        desc.extend(g.desc);
+    }
+    explicit.sort_unstable();
+    explicit.dedup();
+    desc.extend(data.desc);
+    FileRecord::new(
+        path,
+        explicit,
+        inherited
+            .iter()
+            .map(|t| config.canonical(t).to_string())
+            .collect(),
+        infer_implicit_tags(filename)
+            .map(|t| config.canonical(&t.to_string()).to_string())
+            .collect(),
+        Some(desc.join("\n")),
+    )
+}
+
+/// Tag of a filter that no file has, and the known tags that are closest to
+/// it, to suggest in its place.
+pub struct UnknownTag {
+    pub tag: String,
+    pub suggestions: Vec<String>,
+}
+
+impl UnknownTag {
+    /// Suggest the tags among `known` that are within a small edit distance of
+    /// `tag`, closest first.
+    pub fn new<'a>(tag: &str, known: impl Iterator<Item = &'a str>) -> Self {
+        const MAX_SUGGESTIONS: usize = 3;
+        let limit = usize::max(1, tag.chars().count() / 3);
+        let mut suggestions: Vec<(usize, &str)> = known
+            .map(|k| (edit_distance(tag, k), k))
+            .filter(|(d, _k)| *d <= limit)
+            .collect();
+        suggestions.sort_unstable();
+        suggestions.dedup();
+        UnknownTag {
+            tag: tag.to_string(),
+            suggestions: suggestions
+                .into_iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|(_d, k)| k.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl Display for UnknownTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "'{}' is not a known tag.", self.tag)?;
+        if !self.suggestions.is_empty() {
+            let quoted: Vec<String> = self
+                .suggestions
+                .iter()
+                .map(|s| format!("'{}'", s))
+                .collect();
+            write!(f, " Did you mean {}?", quoted.join(" or "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of single character insertions, deletions, substitutions and swaps
+/// of adjacent characters needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let ncols = b.len() + 1;
+    let mut dist = vec![0usize; (a.len() + 1) * ncols];
+    for i in 0..=a.len() {
+        dist[i * ncols] = i;
+    }
+    for (j, d) in dist[..ncols].iter_mut().enumerate() {
+        *d = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut d = usize::min(
+                dist[(i - 1) * ncols + j - 1] + cost,
+                usize::min(dist[(i - 1) * ncols + j], dist[i * ncols + j - 1]) + 1,
+            );
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = usize::min(d, dist[(i - 2) * ncols + j - 2] + 1);
+            }
+            dist[i * ncols + j] = d;
+        }
+    }
+    dist[a.len() * ncols + b.len()]
+}
+
+/// Run the query `filter`, and print the matching files. Tags of the filter
+/// that no file has are reported as warnings after the matching files. If
+/// `strict_tags` is true, they are reported as an error instead, and nothing
+/// is printed.
+pub fn run_query(
+    dirpath: PathBuf,
+    filter: &str,
+    options: &OutputOptions,
+    walk: &WalkOptions,
+    strict_tags: bool,
+) -> Result<(), Error> {
+    let config = Config::load(&dirpath)?;
+    let (filter, terms) = FilterTerms::parse(filter, &config)?;
+    for unknown in find_matches(
+        &dirpath,
+        &filter,
+        &terms,
+        &config,
+        options,
+        walk,
+        strict_tags,
+    )? {
+        eprintln!("Warning: {}", unknown);
+    }
+    Ok(())
+}
+
+/// Print the files that match the filter, and get the tags of the filter that
+/// no file has. The tags found in the directory tree are collected during the
+/// same walk. If `strict_tags` is true, the matches are only printed after
+/// checking that there are no unknown tags, otherwise an error is returned.
+fn find_matches(
+    dirpath: &Path,
+    filter: &Filter,
+    terms: &FilterTerms,
+    config: &Config,
+    options: &OutputOptions,
+    walk: &WalkOptions,
+    strict_tags: bool,
+) -> Result<Vec<UnknownTag>, Error> {
+    // The index doesn't store descriptions.
+    let descs = !terms.descs.is_empty();
+    if options.format == Format::Text && !descs {
+        if let Some(index) = Index::open(dirpath, walk)? {
+            return query_index(&index, dirpath, filter, terms, config, options, strict_tags);
+        }
+    }
+    // The full records of the files, and the names of all inherited tags, are
+    // only needed for structured output. Descriptions are only loaded if they
+    // are needed for the output, or by the filter.
+    let structured = options.format != Format::Text;
+    // The names of the tags in the directory tree are only needed to check
+    // the tags of the filter.
+    let collect_tags = terms.has_tags();
+    let printer = Mutex::new((!strict_tags).then(|| Printer::new::<FileRecord>(options, dirpath)));
+    let workers = walk_parallel(
         dirpath,
         LoaderOptions::new(
             true,
-            false,
+            structured || descs,
             FileLoadingOptions::Load {
                 file_tags: true,
-                file_desc: false,
+                file_desc: structured || descs,
             },
         ),
-    )?;
-    let mut filetags = vec![false; tag_index.len()].into_boxed_slice();
-    while let Some(VisitedDir {
-        traverse_depth,
-        rel_dir_path,
-        files,
-        metadata,
-        ..
-    }) = dir.walk()
-    {
-        inherited.update(traverse_depth)?;
-        let data = match metadata {
-            MetaData::Ok(d) => d,
-            MetaData::NotFound => continue,
-            MetaData::FailedToLoad(e) => return Err(e),
-        };
-        // Push directory tags.
-        inherited.tag_indices.extend(
-            data.tags()
+        walk,
+        QueryInherited::default(),
+        || QueryWorker {
+            matcher: GlobMatches::new(),
+            filetags: vec![false; terms.len()].into_boxed_slice(),
+            seen: vec![false; terms.len()].into_boxed_slice(),
+            known: HashSet::new(),
+            indices: Vec::new(),
+            paths: Vec::new(),
+            records: Vec::new(),
+        },
+        |worker,
+         parent,
+         VisitedDir {
+             abs_dir_path,
+             rel_dir_path,
+             files,
+             metadata,
+             ..
+         }| {
+            let data = match metadata {
+                MetaData::Ok(d) => d,
+                MetaData::NotFound => return Ok(QueryInherited::clone(parent)),
+                MetaData::FailedToLoad(e) => return Err(e),
+            };
+            // Push directory tags.
+            let dirname = get_filename_lossy(rel_dir_path);
+            let dirname: &str = &dirname;
+            let mut inherited = QueryInherited::clone(parent);
+            let QueryWorker {
+                matcher,
+                filetags,
+                seen,
+                known,
+                indices,
+                paths,
+                records,
+            } = worker;
+            for tag in data
+                .tags()
                 .iter()
                 .map(|t| Tag::Text(t))
-                .chain(infer_implicit_tags(get_filename_str(rel_dir_path)?))
-                .filter_map(|tag| match tag {
-                    Tag::Text(t) | Tag::Format(t) => tag_index.get(t).copied(),
-                    Tag::Year(y) => tag_index.get(&y.to_string()).copied(),
-                }),
+                .chain(infer_implicit_tags(dirname))
+            {
+                if collect_tags {
+                    add_known_tag(known, &tag);
+                }
+                terms.find(tag, config, &mut inherited.tags);
+            }
+            if descs {
+                inherited.descs.extend(data.desc.map(|d| d.to_string()));
+            }
+            if structured {
+                inherited.names.extend(
+                    data.tags()
+                        .iter()
+                        .map(|t| t.to_string())
+                        .chain(infer_implicit_tags(dirname).map(|t| t.to_string())),
+                );
+            }
+            // Process all files in the directory.
+            matcher.find_matches(files, &data.globs, false);
+            for (fi, file) in files
+                .iter()
+                .enumerate()
+                .filter(|(fi, _)| matcher.is_file_matched(*fi))
+            {
+                // Names that are not valid UTF-8 can still be matched by globs.
+                let fname = file.name().to_string_lossy();
+                filetags.fill(false);
+                indices.clear();
+                for tag in matcher
+                    .matched_globs(fi) // Tags associated with matching globs.
+                    .flat_map(|gi| {
+                        data.globs[gi]
+                            .tags(&data.alltags)
+                            .iter()
+                            .map(|t| Tag::Text(t))
+                    })
+                    // Implicit tags.
+                    .chain(infer_implicit_tags(&fname))
+                {
+                    if collect_tags {
+                        add_known_tag(known, &tag);
+                    }
+                    terms.find(tag, config, indices);
+                }
+                for index in indices.iter().chain(inherited.tags.iter()) {
+                    filetags[*index] = true;
+                    seen[*index] = true;
+                }
+                // Only read the metadata of the file, and join its descriptions,
+                // if a predicate needs them.
+                let stat = OnceCell::new();
+                let desc = OnceCell::new();
+                if filter.eval(|ti| {
+                    filetags[ti]
+                        || terms.predicate(ti).is_some_and(|p| {
+                            p.is_match(&fname, || {
+                                *stat
+                                    .get_or_init(|| FileStat::read(&abs_dir_path.join(file.name())))
+                            })
+                        })
+                        || terms.desc(ti).is_some_and(|d| {
+                            d.is_match(desc.get_or_init(|| {
+                                matcher
+                                    .matched_globs(fi)
+                                    .filter_map(|gi| data.globs[gi].desc)
+                                    .chain(inherited.descs.iter().map(|d| d.as_str()))
+                                    .collect::<Vec<_>>()
+                                    .join("\n")
+                            }))
+                        })
+                }) {
+                    let mut path = rel_dir_path.to_path_buf();
+                    path.push(file.name());
+                    if structured {
+                        records.push(file_record(
+                            path,
+                            data,
+                            matcher.matched_globs(fi),
+                            &fname,
+                            &inherited.names,
+                            config,
+                        ));
+                    } else {
+                        paths.push(path);
+                    }
+                }
+            }
+            // Print the matches from this directory together, so that the
+            // output of different threads is not interleaved. Without a
+            // printer, the matches are kept until the end of the walk.
+            if !paths.is_empty() || !records.is_empty() {
+                if let Some(printer) = lock(&printer).as_mut() {
+                    for path in paths.drain(..) {
+                        printer.print_path(&path);
+                    }
+                    for record in records.drain(..) {
+                        printer.print(&record);
+                    }
+                }
+            }
+            Ok(inherited)
+        },
+    )?;
+    let mut seen = vec![false; terms.len()].into_boxed_slice();
+    let mut known = HashSet::new();
+    for worker in workers.iter() {
+        seen.iter_mut()
+            .zip(worker.seen.iter())
+            .for_each(|(dst, src)| *dst = *dst || *src);
+        known.extend(worker.known.iter().map(|t| t.as_str()));
+    }
+    let unknown = terms.unknown_tags(&seen, known.into_iter(), config);
+    let printer = printer.into_inner().unwrap_or_else(PoisonError::into_inner);
+    match printer {
+        Some(printer) => printer.finish(),
+        None if !unknown.is_empty() => return Err(Error::UnknownTags(unknown)),
+        None => {
+            let mut printer = Printer::new::<FileRecord>(options, dirpath);
+            for worker in workers {
+                for path in worker.paths {
+                    printer.print_path(&path);
+                }
+                for record in worker.records {
+                    printer.print(&record);
+                }
+            }
+            printer.finish();
+        }
+    }
+    Ok(unknown)
+}
+
+/// Add `tag` to the set of tags found in the directory tree.
+fn add_known_tag(known: &mut HashSet<String>, tag: &Tag) {
+    match tag {
+        Tag::Text(t) | Tag::Format(t) if known.contains(*t) => {}
+        tag => {
+            known.insert(tag.to_string());
+        }
+    }
+}
+
+/// The terms of a filter. Each term is either a tag, a pattern that matches
+/// many tags, or a predicate on the attributes or the descriptions of files,
+/// and has an index that is used by the filter to refer to it.
+struct FilterTerms {
+    /// Tag, pattern or predicate as written -> index of the term.
+    index: BTreeMap<String, usize>,
+    /// Patterns, and the indices of their terms.
+    patterns: Vec<(usize, TagPattern)>,
+    /// File predicates, and the indices of their terms.
+    predicates: Vec<(usize, FilePredicate)>,
+    /// Description predicates, and the indices of their terms.
+    descs: Vec<(usize, DescPredicate)>,
+}
+
+impl FilterTerms {
+    /// Parse and simplify a filter. The tags in the filter are replaced with
+    /// their canonical names.
+    fn parse(filter: &str, config: &Config) -> Result<(Filter, FilterTerms), Error> {
+        let mut index = BTreeMap::<String, usize>::new();
+        let mut patterns = Vec::new();
+        let mut predicates = Vec::new();
+        let mut descs = Vec::new();
+        let filter = Filter::parse(filter, |word| {
+            let size = index.len();
+            if let Some(tag) = unquote(word) {
+                return Filter::Tag(
+                    *index
+                        .entry(config.canonical(tag).to_string())
+                        .or_insert(size),
+                );
+            }
+            if let Ok(Some(predicate)) = FilePredicate::parse(word) {
+                let ti = *index.entry(word.to_string()).or_insert(size);
+                if ti == size {
+                    predicates.push((ti, predicate));
+                }
+                return Filter::Tag(ti);
+            }
+            if let Ok(Some(predicate)) = DescPredicate::parse(word) {
+                let ti = *index.entry(word.to_string()).or_insert(size);
+                if ti == size {
+                    descs.push((ti, predicate));
+                }
+                return Filter::Tag(ti);
+            }
+            match TagPattern::parse(word) {
+                Some(pattern) => {
+                    let ti = *index.entry(word.to_string()).or_insert(size);
+                    if ti == size {
+                        patterns.push((ti, pattern));
+                    }
+                    Filter::Tag(ti)
+                }
+                None => Filter::Tag(
+                    *index
+                        .entry(config.canonical(word).to_string())
+                        .or_insert(size),
+                ),
+            }
+        })
+        .map_err(Error::InvalidFilter)?;
+        Ok((
+            filter.simplify(),
+            FilterTerms {
+                index,
+                patterns,
+                predicates,
+                descs,
+            },
+        ))
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Check if any of the terms is a tag, rather than a pattern or a
+    /// predicate.
+    fn has_tags(&self) -> bool {
+        self.index.values().any(|ti| self.is_tag(*ti))
+    }
+
+    fn is_tag(&self, ti: usize) -> bool {
+        !self.patterns.iter().any(|(i, _p)| *i == ti)
+            && self.predicate(ti).is_none()
+            && self.desc(ti).is_none()
+    }
+
+    /// Find the tags of the filter that are not `seen`, and are not among the
+    /// `known` tags found in the directory tree.
+    fn unknown_tags<'a>(
+        &self,
+        seen: &[bool],
+        known: impl Iterator<Item = &'a str>,
+        config: &Config,
+    ) -> Vec<UnknownTag> {
+        let candidates: Vec<&str> = self
+            .index
+            .iter()
+            .filter(|(_t, ti)| !seen[**ti] && self.is_tag(**ti))
+            .map(|(t, _ti)| t.as_str())
+            .collect();
+        if candidates.is_empty() {
+            return Vec::new();
+        }
+        // Every query term satisfied by the known tags.
+        let mut terms = HashSet::new();
+        for tag in known {
+            for t in config.expand(tag) {
+                terms.extend(tag_query_terms(t).map(|t| t.to_string()));
+            }
+        }
+        candidates
+            .into_iter()
+            .filter(|t| !terms.contains(*t))
+            .map(|t| {
+                UnknownTag::new(
+                    t,
+                    terms
+                        .iter()
+                        .filter(|k| !k.ends_with('/'))
+                        .map(|k| k.as_str()),
+                )
+            })
+            .collect()
+    }
+
+    /// Get the file predicate of the term with index `ti`, if it is one.
+    fn predicate(&self, ti: usize) -> Option<&FilePredicate> {
+        self.predicates
+            .iter()
+            .find(|(i, _p)| *i == ti)
+            .map(|(_i, p)| p)
+    }
+
+    /// Get the description predicate of the term with index `ti`, if it is
+    /// one.
+    fn desc(&self, ti: usize) -> Option<&DescPredicate> {
+        self.descs.iter().find(|(i, _d)| *i == ti).map(|(_i, d)| d)
+    }
+
+    /// Push the indices of the terms that are satisfied by a file with `tag`
+    /// into `out`.
+    fn find(&self, tag: Tag, config: &Config, out: &mut Vec<usize>) {
+        match tag {
+            Tag::Text(t) | Tag::Format(t) => {
+                for tag in config.expand(t) {
+                    out.extend(tag_query_terms(tag).filter_map(|t| self.index.get(t).copied()));
+                    for tag in implied_tags(tag) {
+                        self.find_patterns(tag, out);
+                    }
+                }
+            }
+            Tag::Year(y) => {
+                out.extend(self.index.get(&y.to_string()).copied());
+                out.extend(
+                    self.patterns
+                        .iter()
+                        .filter(|(_ti, pattern)| pattern.is_year_match(y))
+                        .map(|(ti, _pattern)| *ti),
+                );
+            }
+        }
+    }
+
+    fn find_patterns(&self, tag: &str, out: &mut Vec<usize>) {
+        out.extend(
+            self.patterns
+                .iter()
+                .filter(|(_ti, pattern)| pattern.is_match(tag))
+                .map(|(ti, _pattern)| *ti),
         );
-        // Process all files in the directory.
-        matcher.find_matches(files, &data.globs, false);
-        for (fi, file) in files
+    }
+}
+
+/// Tags inherited by the files of a directory during a query.
+#[derive(Clone, Default)]
+struct QueryInherited {
+    /// Indices of the inherited tags that appear in the filter.
+    tags: Vec<usize>,
+    /// Names of all inherited tags, only needed for structured output.
+    names: Vec<String>,
+    /// Inherited descriptions, only needed by description predicates.
+    descs: Vec<String>,
+}
+
+/// Buffers used by a thread running a query.
+struct QueryWorker {
+    matcher: GlobMatches,
+    filetags: Box<[bool]>,
+    /// Terms satisfied by at least one file.
+    seen: Box<[bool]>,
+    /// Tags found in the directory tree, if the filter has any tags.
+    known: HashSet<String>,
+    /// Indices of the terms satisfied by a file.
+    indices: Vec<usize>,
+    paths: Vec<PathBuf>,
+    records: Vec<FileRecord>,
+}
+
+/// Run a query using the index instead of walking the directories, like
+/// `find_matches`. The index only stores tags, so this can only print paths.
+fn query_index(
+    index: &Index,
+    root: &Path,
+    filter: &Filter,
+    terms: &FilterTerms,
+    config: &Config,
+    options: &OutputOptions,
+    strict_tags: bool,
+) -> Result<Vec<UnknownTag>, Error> {
+    // Index tag id -> indices of the filter terms it satisfies.
+    let lookup: Vec<Vec<usize>> = (0..index.num_tags())
+        .map(|id| {
+            let mut indices = Vec::new();
+            terms.find(Tag::Text(index.tag(id as u32)), config, &mut indices);
+            indices
+        })
+        .collect();
+    let mut filetags = vec![false; terms.len()].into_boxed_slice();
+    let mut seen = vec![false; terms.len()].into_boxed_slice();
+    let mut printer = (!strict_tags).then(|| Printer::new::<FileRecord>(options, root));
+    let mut matches = Vec::new();
+    index.visit_files(|dir, file, inherited| {
+        filetags.fill(false);
+        for ti in file
+            .tags
             .iter()
-            .enumerate()
-            .filter(|(fi, _)| matcher.is_file_matched(*fi))
+            .chain(inherited.iter())
+            .flat_map(|id| lookup[*id as usize].iter().copied())
         {
-            filetags.fill(false);
-            for index in matcher
-                .matched_globs(fi) // Tags associated with matching globs.
-                .flat_map(|gi| {
-                    data.globs[gi]
-                        .tags(&data.alltags)
-                        .iter()
-                        .map(|t| Tag::Text(t))
-                })
-                // Implicit tags.
-                .chain(infer_implicit_tags(match file.name().to_str() {
-                    Some(fname) => fname,
-                    None => return Err(Error::InvalidPath(file.name().into())),
-                }))
-                .filter_map(|tag| match tag {
-                    Tag::Text(t) | Tag::Format(t) => tag_index.get(t).copied(),
-                    Tag::Year(y) => tag_index.get(&y.to_string()).copied(),
+            filetags[ti] = true;
+            seen[ti] = true;
+        }
+        let path = dir.path.join(&file.name);
+        let stat = OnceCell::new();
+        if filter.eval(|ti| {
+            filetags[ti]
+                || terms.predicate(ti).is_some_and(|p| {
+                    p.is_match(&file.name.to_string_lossy(), || {
+                        *stat.get_or_init(|| FileStat::read(&root.join(&path)))
+                    })
                 })
-                .chain(inherited.tag_indices.iter().copied())
-            {
-                filetags[index] = true;
+        }) {
+            match printer.as_mut() {
+                Some(printer) => printer.print_path(&path),
+                None => matches.push(path),
             }
-            if filter.eval(|ti| filetags[ti]) {
-                let mut path = rel_dir_path.to_path_buf();
-                path.push(file.name());
-                println!("{}", path.display());
+        }
+    })?;
+    let unknown = terms.unknown_tags(
+        &seen,
+        (0..index.num_tags()).map(|id| index.tag(id as u32)),
+        config,
+    );
+    match printer {
+        Some(printer) => printer.finish(),
+        None if !unknown.is_empty() => return Err(Error::UnknownTags(unknown)),
+        None => {
+            let mut printer = Printer::new::<FileRecord>(options, root);
+            for path in matches {
+                printer.print_path(&path);
             }
+            printer.finish();
         }
     }
-    Ok(())
+    Ok(unknown)
+}
+
+/// Files and tags found by a thread while building a `TagTable`.
+#[derive(Default)]
+struct TableWorker {
+    matcher: GlobMatches,
+    /// Tags found by this thread, and their indices local to this thread.
+    tag_index: HashMap<String, usize>,
+    files: Vec<String>,
+    /// Pairs of file and tag indices, local to this thread.
+    table: Vec<(usize, usize)>,
+    filetags: Vec<String>,
 }
 
 /// 2d array of bools.
@@ -225,8 +825,11 @@ pub struct TagTable {
     root: PathBuf,
     flags: BoolTable,
     files: Box<[String]>,
+    /// Metadata of the files, only read when a file predicate needs it.
+    stats: Box<[OnceLock<Option<FileStat>>]>,
     tags: Box<[String]>,
     tag_index: HashMap<String, usize>,
+    config: Config,
 }
 
 impl TagTable {
@@ -235,19 +838,13 @@ impl TagTable {
         *(map.entry(tag).or_insert(size))
     }
 
-    pub fn from_dir(dirpath: PathBuf) -> Result<TagTable, Error> {
-        let mut tag_index = HashMap::new();
-        let mut allfiles = Vec::new();
-        let mut table = HashSet::<(usize, usize)>::new();
-        let mut inherited = InheritedTags {
-            tag_indices: Vec::new(),
-            offsets: Vec::new(),
-            depth: 0,
-        };
-        let mut matcher = GlobMatches::new();
-        let mut filetags: Vec<String> = Vec::new();
-        let mut dir = DirTree::new(
-            dirpath.clone(),
+    pub fn from_dir(dirpath: PathBuf, walk: &WalkOptions) -> Result<TagTable, Error> {
+        let config = Config::load(&dirpath)?;
+        if let Some(index) = Index::open(&dirpath, walk)? {
+            return Self::from_index(&index, dirpath, config);
+        }
+        let workers = walk_parallel(
+            &dirpath,
             LoaderOptions::new(
                 true,
                 false,
@@ -256,98 +853,179 @@ impl TagTable {
                     file_desc: false,
                 },
             ),
-        )?;
-        while let Some(VisitedDir {
-            traverse_depth,
-            rel_dir_path,
-            files: dirfiles,
-            metadata,
-            ..
-        }) = dir.walk()
-        {
-            inherited.update(traverse_depth)?;
-            let data = match metadata {
-                MetaData::Ok(d) => d,
-                MetaData::NotFound => continue,
-                MetaData::FailedToLoad(e) => return Err(e),
-            };
-            // Push directory tags.
-            inherited.tag_indices.extend(
-                data.tags()
+            walk,
+            Vec::<String>::new(), // Names of the inherited tags.
+            TableWorker::default,
+            |worker,
+             parent,
+             VisitedDir {
+                 rel_dir_path,
+                 files: dirfiles,
+                 metadata,
+                 ..
+             }| {
+                let data = match metadata {
+                    MetaData::Ok(d) => d,
+                    MetaData::NotFound => return Ok(parent.to_vec()),
+                    MetaData::FailedToLoad(e) => return Err(e),
+                };
+                // Push directory tags.
+                let mut inherited = parent.to_vec();
+                inherited.extend(data.tags().iter().map(|t| t.to_string()).chain(
+                    infer_implicit_tags(&get_filename_lossy(rel_dir_path)).map(|t| t.to_string()),
+                ));
+                let TableWorker {
+                    matcher,
+                    tag_index,
+                    files: allfiles,
+                    table,
+                    filetags,
+                } = worker;
+                let inherited_indices: Vec<usize> = inherited
+                    .iter()
+                    .flat_map(|tag| config.expand(tag))
+                    .flat_map(implied_tags)
+                    .map(|tag| Self::get_tag_index(tag.to_string(), tag_index))
+                    .collect();
+                // Process all files in the directory.
+                matcher.find_matches(dirfiles, &data.globs, false);
+                allfiles.reserve(dirfiles.len());
+                for (fi, file) in dirfiles
                     .iter()
-                    .map(|t| Tag::Text(t))
-                    .chain(infer_implicit_tags(get_filename_str(rel_dir_path)?))
-                    .map(|tag| match tag {
-                        Tag::Text(t) | Tag::Format(t) => {
-                            Self::get_tag_index(t.to_string(), &mut tag_index)
+                    .enumerate()
+                    // Only interested in tracked files.
+                    .filter(|(fi, _)| matcher.is_file_matched(*fi))
+                {
+                    filetags.clear();
+                    filetags.extend(
+                        matcher
+                            .matched_globs(fi) // Tags associated with matching globs.
+                            .flat_map(|gi| {
+                                data.globs[gi]
+                                    .tags(&data.alltags)
+                                    .iter()
+                                    .map(|t| t.to_string())
+                            })
+                            // Implicit tags.
+                            .chain(
+                                infer_implicit_tags(&file.name().to_string_lossy())
+                                    .map(|t| t.to_string())
+                                    .collect::<Vec<_>>(),
+                            ),
+                    );
+                    let file_index = allfiles.len();
+                    allfiles.push(format!(
+                        "{}",
+                        {
+                            let mut relpath = rel_dir_path.to_path_buf();
+                            relpath.push(file.name());
+                            relpath
                         }
-                        Tag::Year(y) => Self::get_tag_index(y.to_string(), &mut tag_index),
-                    }),
+                        .display()
+                    ));
+                    table.extend(
+                        filetags
+                            .iter()
+                            .flat_map(|tag| config.expand(tag))
+                            .flat_map(implied_tags)
+                            .map(|tag| {
+                                (file_index, Self::get_tag_index(tag.to_string(), tag_index))
+                            }) // This file's explicit tags.
+                            .chain(inherited_indices.iter().map(|ti| (file_index, *ti))), // Inherited tags.
+                    );
+                    filetags.clear();
+                }
+                Ok(inherited)
+            },
+        )?;
+        // Merge the files and tags found by all the threads. Each thread
+        // numbered the tags in the order it found them.
+        let mut tag_index = HashMap::new();
+        let mut allfiles = Vec::new();
+        let mut table = HashSet::<(usize, usize)>::new();
+        for worker in workers {
+            let mut local: Vec<_> = worker.tag_index.into_iter().collect();
+            local.sort_unstable_by_key(|(_t, i)| *i);
+            let remap: Vec<usize> = local
+                .into_iter()
+                .map(|(tag, _i)| Self::get_tag_index(tag, &mut tag_index))
+                .collect();
+            let offset = allfiles.len();
+            allfiles.extend(worker.files);
+            table.extend(
+                worker
+                    .table
+                    .into_iter()
+                    .map(|(fi, ti)| (fi + offset, remap[ti])),
             );
-            // Process all files in the directory.
-            matcher.find_matches(dirfiles, &data.globs, false);
-            allfiles.reserve(dirfiles.len());
-            for (fi, file) in dirfiles
-                .iter()
-                .enumerate()
-                // Only interested in tracked files.
-                .filter(|(fi, _)| matcher.is_file_matched(*fi))
-            {
-                filetags.clear();
-                filetags.extend(
-                    matcher
-                        .matched_globs(fi) // Tags associated with matching globs.
-                        .flat_map(|gi| {
-                            data.globs[gi]
-                                .tags(&data.alltags)
-                                .iter()
-                                .map(|t| t.to_string())
-                        })
-                        // Implicit tags.
-                        .chain(
-                            infer_implicit_tags(match file.name().to_str() {
-                                Some(fname) => fname,
-                                None => return Err(Error::InvalidPath(file.name().into())),
-                            })
-                            .map(|t| t.to_string()),
-                        ),
-                );
-                let file_index = allfiles.len();
-                allfiles.push(format!(
-                    "{}",
-                    {
-                        let mut relpath = rel_dir_path.to_path_buf();
-                        relpath.push(file.name());
-                        relpath
-                    }
-                    .display()
-                ));
-                table.extend(
-                    filetags
-                        .drain(..)
-                        .map(|tag| (file_index, Self::get_tag_index(tag, &mut tag_index))) // This file's explicit tags.
-                        .chain(inherited.tag_indices.iter().map(|ti| (file_index, *ti))), // Inherited tags.
-                );
+        }
+        Ok(Self::build(dirpath, allfiles, table, tag_index, config))
+    }
+
+    fn from_index(index: &Index, dirpath: PathBuf, config: Config) -> Result<TagTable, Error> {
+        let mut tag_index = HashMap::new();
+        // Index tag id -> table indices of the tag and its ancestors, assigned
+        // in order of first use.
+        let mut lookup: Vec<Option<Vec<usize>>> = vec![None; index.num_tags()];
+        let mut allfiles = Vec::new();
+        let mut table = Vec::<(usize, usize)>::new();
+        index.visit_files(|dir, file, inherited| {
+            let file_index = allfiles.len();
+            let path = dir.path.join(&file.name);
+            allfiles.push(format!("{}", path.display()));
+            for id in file.tags.iter().chain(inherited.iter()) {
+                let indices = lookup[*id as usize].get_or_insert_with(|| {
+                    config
+                        .expand(index.tag(*id))
+                        .into_iter()
+                        .flat_map(implied_tags)
+                        .map(|tag| Self::get_tag_index(tag.to_string(), &mut tag_index))
+                        .collect()
+                });
+                table.extend(indices.iter().map(|ti| (file_index, *ti)));
             }
+        })?;
+        Ok(Self::build(dirpath, allfiles, table, tag_index, config))
+    }
+
+    /// Construct the table from the paths of the files, the pairs of file and
+    /// tag indices, and the indices of the tags. The files are sorted by path
+    /// and the tags by name, so the table doesn't depend on the order in which
+    /// the threads visited the directories.
+    fn build(
+        root: PathBuf,
+        files: Vec<String>,
+        table: impl IntoIterator<Item = (usize, usize)>,
+        tag_index: HashMap<String, usize>,
+        config: Config,
+    ) -> TagTable {
+        let mut files: Vec<(usize, String)> = files.into_iter().enumerate().collect();
+        files.sort_unstable_by(|(_a, a), (_b, b)| Path::new(a).cmp(Path::new(b)));
+        let mut file_remap = vec![0; files.len()];
+        for (new, (old, _f)) in files.iter().enumerate() {
+            file_remap[*old] = new;
+        }
+        let mut tags: Vec<(String, usize)> = tag_index.into_iter().collect();
+        tags.sort_unstable();
+        let mut tag_remap = vec![0; tags.len()];
+        for (new, (_t, old)) in tags.iter().enumerate() {
+            tag_remap[*old] = new;
         }
-        // Construct the bool-table.
-        let ntags = tag_index.len();
-        let mut flags = BoolTable::new(allfiles.len(), ntags);
-        for i in table.into_iter().map(move |(fi, ti)| fi * ntags + ti) {
-            flags.data[i] = true;
+        let (nfiles, ntags) = (files.len(), tags.len());
+        let mut flags = BoolTable::new(nfiles, ntags);
+        for (fi, ti) in table {
+            flags.data[file_remap[fi] * ntags + tag_remap[ti]] = true;
         }
-        Ok(TagTable {
-            root: dirpath,
+        let tags: Box<[String]> = tags.into_iter().map(|(t, _i)| t).collect();
+        TagTable {
+            root,
             flags,
-            files: allfiles.into_boxed_slice(),
-            tags: {
-                // Vec of tags sorted by their indices.
-                let mut pairs: Vec<_> = tag_index.iter().collect();
-                pairs.sort_unstable_by(|(_t1, i1), (_t2, i2)| i1.cmp(i2));
-                pairs.into_iter().map(|(t, _i)| t.clone()).collect()
-            },
-            tag_index,
-        })
+            files: files.into_iter().map(|(_i, f)| f).collect(),
+            stats: std::iter::repeat_with(OnceLock::new).take(nfiles).collect(),
+            tag_index: tags.iter().cloned().zip(0..).collect(),
+            tags,
+            config,
+        }
     }
 
     pub fn path(&self) -> &Path {
@@ -366,10 +1044,192 @@ impl TagTable {
         &self.files
     }
 
-    pub fn tag_parse_fn(&self) -> impl Fn(&str) -> Filter + use<'_> {
-        |tag| match self.tag_index.get(tag) {
-            Some(i) => Filter::Tag(*i),
-            None => Filter::FalseTag,
+    /// Check if `file` satisfies the term with index `ti` of a filter parsed
+    /// with `tag_parse_fn`.
+    pub fn check(&self, file: usize, ti: usize, terms: &TableTerms) -> bool {
+        match ti.checked_sub(self.tags.len()) {
+            None => self.flags(file)[ti],
+            Some(pi) => {
+                let name = Path::new(&self.files[file])
+                    .file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_default();
+                terms.predicates[pi].is_match(&name, || {
+                    *self.stats[file]
+                        .get_or_init(|| FileStat::read(&self.root.join(&self.files[file])))
+                })
+            }
+        }
+    }
+
+    /// Get a function that parses the words of a filter. File predicates and
+    /// unknown tags are pushed into `terms`.
+    pub fn tag_parse_fn<'a>(
+        &'a self,
+        terms: &'a mut TableTerms,
+    ) -> impl FnMut(&str) -> Filter + use<'a> {
+        |tag| {
+            if let Some(quoted) = unquote(tag) {
+                // Keep the quotes when the filter is printed.
+                return match self.tag_index.get(self.config.canonical(quoted)) {
+                    Some(i) => Filter::Match(tag.to_string(), vec![*i]),
+                    None => {
+                        terms.unknown.push(quoted.to_string());
+                        Filter::Match(tag.to_string(), Vec::new())
+                    }
+                };
+            }
+            if let Ok(Some(predicate)) = FilePredicate::parse(tag) {
+                terms.predicates.push(predicate);
+                return Filter::Match(
+                    tag.to_string(),
+                    vec![self.tags.len() + terms.predicates.len() - 1],
+                );
+            }
+            if let Ok(Some(_)) = DescPredicate::parse(tag) {
+                // Descriptions are not loaded, so this matches no files.
+                terms.unsupported.push(tag.to_string());
+                return Filter::Match(tag.to_string(), Vec::new());
+            }
+            match TagPattern::parse(tag) {
+                Some(pattern) => Filter::Match(
+                    tag.to_string(),
+                    self.tags
+                        .iter()
+                        .enumerate()
+                        .filter(|(_i, t)| pattern.is_match(t))
+                        .map(|(i, _t)| i)
+                        .collect(),
+                ),
+                // Unknown tags match no files, but keep their names so the
+                // filter can be printed and parsed again.
+                None => self.parse_tag(tag).unwrap_or_else(|| {
+                    terms.unknown.push(tag.to_string());
+                    Filter::Match(tag.to_string(), Vec::new())
+                }),
+            }
+        }
+    }
+
+    fn parse_tag(&self, tag: &str) -> Option<Filter> {
+        if let Some(i) = self.tag_index.get(self.config.canonical(tag)) {
+            return Some(Filter::Tag(*i));
+        }
+        // The table contains the ancestors of every tag, so the prefix form is
+        // satisfied by any of the children of the prefix.
+        tag.strip_suffix('/').filter(|p| !p.is_empty())?;
+        let children: Vec<usize> = self
+            .tags
+            .iter()
+            .enumerate()
+            .filter(|(_i, t)| {
+                t.strip_prefix(tag)
+                    .is_some_and(|child| !child.is_empty() && !child.contains('/'))
+            })
+            .map(|(i, _t)| i)
+            .collect();
+        (!children.is_empty()).then(|| Filter::Match(tag.to_string(), children))
+    }
+
+    /// Suggest the tags of the table that are closest to the unknown `tag`.
+    pub fn unknown_tag(&self, tag: &str) -> UnknownTag {
+        UnknownTag::new(tag, self.tags.iter().map(|t| t.as_str()))
+    }
+}
+
+/// Terms of a filter parsed with `TagTable::tag_parse_fn`, that are not tags
+/// of the table.
+#[derive(Default)]
+pub struct TableTerms {
+    /// File predicates, numbered after the tags of the table.
+    predicates: Vec<FilePredicate>,
+    /// Words that are neither tags of the table, nor patterns or predicates.
+    pub unknown: Vec<String>,
+    /// Description predicates, which never match because the table doesn't
+    /// have the descriptions of the files.
+    pub unsupported: Vec<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::FTAG_FILE;
+
+    #[test]
+    fn t_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("beach", ""), 5);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("notse", "notes"), 1); // Swap.
+        assert_eq!(edit_distance("café", "cafe"), 1);
+    }
+
+    #[test]
+    fn t_unknown_tag() {
+        let known = ["beach", "bench", "peach", "sunset", "place/europe"];
+        let unknown = UnknownTag::new("beahc", known.into_iter());
+        assert_eq!(unknown.suggestions, ["beach"]);
+        assert_eq!(
+            unknown.to_string(),
+            "'beahc' is not a known tag. Did you mean 'beach'?"
+        );
+        let unknown = UnknownTag::new("beech", known.into_iter());
+        assert_eq!(unknown.suggestions, ["beach", "bench"]);
+        assert_eq!(
+            unknown.to_string(),
+            "'beech' is not a known tag. Did you mean 'beach' or 'bench'?"
+        );
+        let unknown = UnknownTag::new("xyz", known.into_iter());
+        assert!(unknown.suggestions.is_empty());
+        assert_eq!(unknown.to_string(), "'xyz' is not a known tag.");
+    }
+
+    #[test]
+    fn t_tag_table_order() {
+        let root = std::env::temp_dir().join("ftag_t_tag_table_order");
+        let _ = std::fs::remove_dir_all(&root);
+        for (dir, tag) in [("b", "bee"), ("a", "ant"), ("a/z", "zebra"), ("c", "cat")] {
+            let dir = root.join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            for name in ["y.txt", "x.txt"] {
+                std::fs::write(dir.join(name), "").unwrap();
+            }
+            std::fs::write(
+                dir.join(FTAG_FILE),
+                format!("[path]\n*.txt\n[tags]\n{}\n", tag),
+            )
+            .unwrap();
+        }
+        let expected: Vec<String> = [
+            "a/x.txt",
+            "a/y.txt",
+            "a/z/x.txt",
+            "a/z/y.txt",
+            "b/x.txt",
+            "b/y.txt",
+            "c/x.txt",
+            "c/y.txt",
+        ]
+        .iter()
+        .map(|p| Path::new(p).display().to_string())
+        .collect();
+        for threads in [1, 4] {
+            let table = TagTable::from_dir(
+                root.clone(),
+                &WalkOptions {
+                    threads,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(table.files(), expected);
+            assert_eq!(table.tags(), ["ant", "bee", "cat", "zebra"]);
+            let zebra = table.tag_index["zebra"];
+            let tagged: Vec<usize> = (0..table.files().len())
+                .filter(|fi| table.flags(*fi)[zebra])
+                .collect();
+            assert_eq!(tagged, [2, 3]);
         }
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
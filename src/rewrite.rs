@@ -0,0 +1,698 @@
+use crate::{
+    core::{Error, FTAG_FILE},
+    load::{
+        escape, get_ftag_backup_path, is_comment, parse_blocks, split_tags, unescape, BlockSpan,
+        HeaderType,
+    },
+};
+use std::{
+    fs::File,
+    io::{self, Read},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+pub(crate) fn write_globs<T: AsRef<str>>(
+    globs: &[T],
+    w: &mut impl io::Write,
+) -> Result<(), io::Error> {
+    if globs.is_empty() {
+        return Ok(());
+    }
+    writeln!(w, "\n[path]")?;
+    for glob in globs.iter().map(|g| g.as_ref()) {
+        writeln!(w, "{}", escape(glob))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_tags<T: AsRef<str>>(
+    tags: &[T],
+    w: &mut impl io::Write,
+) -> Result<(), io::Error> {
+    if tags.is_empty() {
+        return Ok(());
+    }
+    writeln!(w, "[tags]")?;
+    if tags
+        .iter()
+        .try_fold(0usize, |len, tag| -> Result<usize, io::Error> {
+            let tag = tag.as_ref();
+            Ok(if len > 80 {
+                writeln!(w, "{}", tag)?;
+                0usize
+            } else {
+                write!(w, "{} ", tag)?;
+                len + tag.len() + 1
+            })
+        })?
+        > 0
+    {
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_desc<T: AsRef<str>>(
+    desc: Option<&T>,
+    w: &mut impl io::Write,
+) -> Result<(), io::Error> {
+    match desc {
+        Some(desc) => writeln!(w, "[desc]\n{}", desc.as_ref()),
+        None => Ok(()),
+    }
+}
+
+/// Run one of the `write_*` functions into a string.
+fn write_string<F>(f: F) -> String
+where
+    F: FnOnce(&mut Vec<u8>) -> Result<(), io::Error>,
+{
+    let mut buf = Vec::new();
+    // Writing to a vector cannot fail, and the inputs are all valid strings.
+    f(&mut buf).expect("FATAL: Failed to write to a buffer");
+    String::from_utf8(buf).expect("FATAL: Wrote invalid UTF-8 to a buffer")
+}
+
+/// An entry in an ftag file, i.e. a `[path]` block followed by the `[tags]` and
+/// `[desc]` blocks that apply to the globs listed in it.
+struct Entry {
+    path: usize,
+    tags: Option<usize>,
+    desc: Option<usize>,
+    /// From the start of the `[path]` header to the start of the next entry.
+    span: Range<usize>,
+}
+
+/// The owner of a set of tags in an ftag file.
+#[derive(Clone, Copy)]
+pub(crate) enum TagOwner {
+    Dir,
+    Entry(usize),
+}
+
+/// Lossless view of an ftag file, that records the byte spans of all the
+/// headers and their contents. Changes are staged as edits to these spans, and
+/// spliced into the original text when applied, so that everything else in the
+/// file, i.e. ordering, layout and blank lines, is left byte-identical.
+pub(crate) struct FtagDocument {
+    filepath: PathBuf,
+    text: String,
+    blocks: Vec<BlockSpan>,
+    dir_tags: Option<usize>,
+    dir_desc: Option<usize>,
+    entries: Vec<Entry>,
+    edits: Vec<(Range<usize>, String)>,
+    modified: bool,
+}
+
+impl FtagDocument {
+    /// Load the ftag file of the directory at `dirpath`. If the directory
+    /// doesn't have an ftag file, the document will be empty.
+    pub fn load(dirpath: &Path) -> Result<Self, Error> {
+        let filepath = dirpath.join(FTAG_FILE);
+        let mut text = String::new();
+        if filepath.exists() {
+            File::open(&filepath)
+                .map_err(|_| Error::CannotReadStoreFile(filepath.clone()))?
+                .read_to_string(&mut text)
+                .map_err(|_| Error::CannotReadStoreFile(filepath.clone()))?;
+        }
+        Self::from_text(text, filepath)
+    }
+
+    pub fn from_text(text: String, filepath: PathBuf) -> Result<Self, Error> {
+        let mut doc = FtagDocument {
+            filepath,
+            text,
+            blocks: Vec::new(),
+            dir_tags: None,
+            dir_desc: None,
+            entries: Vec::new(),
+            edits: Vec::new(),
+            modified: false,
+        };
+        doc.parse()?;
+        Ok(doc)
+    }
+
+    fn parse(&mut self) -> Result<(), Error> {
+        self.blocks = parse_blocks(&self.text, &self.filepath)?;
+        self.dir_tags = None;
+        self.dir_desc = None;
+        self.entries.clear();
+        for (bi, block) in self.blocks.iter().enumerate() {
+            let (slot, what) = match (block.kind, self.entries.last_mut()) {
+                (HeaderType::Path, _) => {
+                    if let Some(last) = self.entries.last_mut() {
+                        last.span.end = block.header.start;
+                    }
+                    self.entries.push(Entry {
+                        path: bi,
+                        tags: None,
+                        desc: None,
+                        span: block.header.start..self.text.len(),
+                    });
+                    continue;
+                }
+                (HeaderType::Tags, Some(entry)) => (&mut entry.tags, "more than one 'tags' header"),
+                (HeaderType::Desc, Some(entry)) => (&mut entry.desc, "more than one description"),
+                (HeaderType::Tags, None) => (&mut self.dir_tags, "more than one 'tags' header"),
+                (HeaderType::Desc, None) => (&mut self.dir_desc, "more than one description"),
+            };
+            if slot.is_some() {
+                return Err(Error::CannotParseFtagFile(
+                    self.filepath.clone(),
+                    format!("Found {} at byte {}.", what, block.header.start),
+                ));
+            }
+            *slot = Some(bi);
+        }
+        Ok(())
+    }
+
+    fn content(&self, block: usize) -> &str {
+        &self.text[self.blocks[block].content.clone()]
+    }
+
+    /// Iterate over the lines in `span` of the text, along with their spans
+    /// including the line breaks.
+    fn lines(&self, span: Range<usize>) -> impl Iterator<Item = (Range<usize>, &str)> {
+        self.text[span.clone()]
+            .split_inclusive('\n')
+            .scan(span.start, |pos, line| {
+                let start = *pos;
+                *pos += line.len();
+                Some((start..*pos, line))
+            })
+    }
+
+    /// The position just past the line break that follows the last non
+    /// whitespace character in the contents of `block`. The second value
+    /// indicates whether the text inserted at this position must start with a
+    /// line break, because the block ends without one.
+    fn block_end(&self, block: usize) -> (usize, bool) {
+        let span = &self.blocks[block].content;
+        let content = self.content(block);
+        let last = content.trim_end().len();
+        match content[last..].find('\n') {
+            Some(i) => (span.start + last + i + 1, false),
+            None => (span.end, true),
+        }
+    }
+
+    pub fn num_entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Iterate over the globs in the entry at `entry`, along with the spans of
+    /// the lines they were found in. Comments are skipped.
+    pub fn globs(&self, entry: usize) -> impl Iterator<Item = (Range<usize>, &str)> {
+        self.lines(self.blocks[self.entries[entry].path].content.clone())
+            .filter_map(|(span, line)| match line.trim() {
+                "" => None,
+                _ if is_comment(line) => None,
+                glob => Some((span, unescape(glob))),
+            })
+    }
+
+    fn tags_block(&self, owner: TagOwner) -> Option<usize> {
+        match owner {
+            TagOwner::Dir => self.dir_tags,
+            TagOwner::Entry(ei) => self.entries[ei].tags,
+        }
+    }
+
+    pub fn tags(&self, owner: TagOwner) -> impl Iterator<Item = &str> {
+        self.tags_block(owner)
+            .map(|b| self.content(b))
+            .map(split_tags)
+            .into_iter()
+            .flatten()
+    }
+
+    /// The text of the description of `owner`, including any comments, so
+    /// that they are kept when the description is copied elsewhere.
+    pub fn desc(&self, owner: TagOwner) -> Option<&str> {
+        match owner {
+            TagOwner::Dir => self.dir_desc,
+            TagOwner::Entry(ei) => self.entries[ei].desc,
+        }
+        .map(|b| self.content(b).trim())
+    }
+
+    /// Find the first entry that lists `glob`, starting from the entry at
+    /// `from`.
+    pub fn find_entry(&self, glob: &str, from: usize) -> Option<usize> {
+        (from..self.entries.len()).find(|ei| self.globs(*ei).any(|(_, g)| g == glob))
+    }
+
+    fn stage(&mut self, span: Range<usize>, text: String) {
+        self.edits.push((span, text));
+    }
+
+    /// Stage the addition of `tags` that aren't already present to `owner`.
+    pub fn add_tags<T: AsRef<str>>(&mut self, owner: TagOwner, tags: &[T]) {
+        let mut new: Vec<&str> = Vec::with_capacity(tags.len());
+        for tag in tags.iter().map(|t| t.as_ref()) {
+            if !new.contains(&tag) && !self.tags(owner).any(|t| t == tag) {
+                new.push(tag);
+            }
+        }
+        if new.is_empty() {
+            return;
+        }
+        let (pos, text) = match self.tags_block(owner) {
+            Some(block) => match self
+                .lines(self.blocks[block].content.clone())
+                .filter(|(_, line)| !line.trim().is_empty() && !is_comment(line))
+                .last()
+            {
+                // Continue the last line of tags.
+                Some((span, line)) => (
+                    span.start + line.trim_end().len(),
+                    format!(" {}", new.join(" ")),
+                ),
+                None => {
+                    let (pos, newline) = self.block_end(block);
+                    (
+                        pos,
+                        format!("{}{}\n", if newline { "\n" } else { "" }, new.join(" ")),
+                    )
+                }
+            },
+            None => {
+                let tagstr = write_string(|w| write_tags(&new, w));
+                match owner {
+                    TagOwner::Dir => match self.blocks.first() {
+                        Some(first) if first.kind == HeaderType::Path => {
+                            (first.header.start, format!("{}\n", tagstr))
+                        }
+                        Some(first) => (first.header.start, tagstr),
+                        None => (
+                            self.text.len(),
+                            match self.text.is_empty() || self.text.ends_with('\n') {
+                                true => tagstr,
+                                false => format!("\n{}", tagstr),
+                            },
+                        ),
+                    },
+                    TagOwner::Entry(ei) => {
+                        let (pos, newline) = self.block_end(self.entries[ei].path);
+                        (
+                            pos,
+                            format!("{}{}", if newline { "\n" } else { "" }, tagstr),
+                        )
+                    }
+                }
+            }
+        };
+        self.stage(pos..pos, text);
+    }
+
+    /// Stage the removal of `tags` from `owner`. Lines that don't contain any
+    /// of the `tags` are not modified. If no tags or comments remain, the
+    /// header is removed as well.
+    pub fn remove_tags<T: AsRef<str>>(&mut self, owner: TagOwner, tags: &[T]) {
+        let block = match self.tags_block(owner) {
+            Some(b) => b,
+            None => return,
+        };
+        let is_removed = |word: &str| tags.iter().any(|t| t.as_ref() == word);
+        let mut edits = Vec::new();
+        let mut remaining = 0usize;
+        let mut first_line = None;
+        for (span, line) in self.lines(self.blocks[block].content.clone()) {
+            first_line.get_or_insert(span.clone());
+            if is_comment(line) {
+                remaining += 1; // Keep the header for the comments.
+                continue;
+            }
+            let nremoved = line.split_whitespace().filter(|w| is_removed(w)).count();
+            let nwords = line.split_whitespace().count();
+            remaining += nwords - nremoved;
+            if nremoved == 0 {
+                continue;
+            }
+            let replacement = if nremoved == nwords {
+                String::new()
+            } else {
+                let indent = &line[..(line.len() - line.trim_start().len())];
+                let words: Vec<&str> = line.split_whitespace().filter(|w| !is_removed(w)).collect();
+                let ending = &line[line.trim_end().len()..];
+                let ending = if ending.contains('\n') { "\n" } else { "" };
+                format!("{}{}{}", indent, words.join(" "), ending)
+            };
+            edits.push((span, replacement));
+        }
+        if remaining == 0 {
+            // Remove the header, and the rest of its line if it's blank.
+            let header = self.blocks[block].header.clone();
+            let end = match first_line {
+                Some(first) if self.text[first.clone()].trim().is_empty() => first.end,
+                _ => header.end,
+            };
+            edits.push((header.start..end, String::new()));
+        }
+        self.edits.extend(edits);
+    }
+
+    /// Stage the removal of the line at `span`, as returned by `globs`.
+    pub fn remove_glob(&mut self, span: Range<usize>) {
+        self.stage(span, String::new());
+    }
+
+    /// Stage replacing the glob in the line at `span`, as returned by `globs`,
+    /// with `glob`. The indentation and line break are left untouched.
+    pub fn replace_glob(&mut self, span: Range<usize>, glob: &str) {
+        let line = &self.text[span.clone()];
+        let start = span.start + (line.len() - line.trim_start().len());
+        let end = span.start + line.trim_end().len();
+        self.stage(start..end, escape(glob).into_owned());
+    }
+
+    /// Stage the removal of the entire entry.
+    pub fn remove_entry(&mut self, entry: usize) {
+        self.stage(self.entries[entry].span.clone(), String::new());
+    }
+
+    /// Stage the addition of `globs` to the list of globs in `entry`.
+    pub fn add_globs<T: AsRef<str>>(&mut self, entry: usize, globs: &[T]) {
+        let (pos, newline) = self.block_end(self.entries[entry].path);
+        let mut text = String::from(if newline { "\n" } else { "" });
+        for glob in globs {
+            text.push_str(&escape(glob.as_ref()));
+            text.push('\n');
+        }
+        self.stage(pos..pos, text);
+    }
+
+    /// Stage the insertion of a new entry. If `after` is provided, the new
+    /// entry is inserted right after it, otherwise it is appended to the end
+    /// of the file.
+    pub fn insert_entry<T: AsRef<str>, U: AsRef<str>>(
+        &mut self,
+        after: Option<usize>,
+        globs: &[T],
+        tags: &[U],
+        desc: Option<&str>,
+    ) {
+        let mut text = write_string(|w| {
+            write_globs(globs, w)?;
+            write_tags(tags, w)?;
+            write_desc(desc.as_ref(), w)
+        });
+        let pos = match after.map(|ei| self.entries[ei].span.end) {
+            Some(pos) if pos < self.text.len() => {
+                // Move the leading blank line to the end, to separate the new
+                // entry from the one that follows.
+                text.remove(0);
+                text.push('\n');
+                pos
+            }
+            _ => {
+                if self.text.is_empty() || self.text.ends_with("\n\n") {
+                    text.remove(0);
+                } else if !self.text.ends_with('\n') {
+                    text.insert(0, '\n');
+                }
+                self.text.len()
+            }
+        };
+        self.stage(pos..pos, text);
+    }
+
+    /// Splice all the staged edits into the text and parse it again.
+    pub fn apply(&mut self) -> Result<(), Error> {
+        if self.edits.is_empty() {
+            return Ok(());
+        }
+        let mut edits = std::mem::take(&mut self.edits);
+        edits.sort_by_key(|(span, _)| (span.start, span.end));
+        let mut out = String::with_capacity(self.text.len());
+        let mut pos = 0usize;
+        for (span, text) in edits {
+            if span.start < pos {
+                return Err(Error::CannotParseFtagFile(
+                    self.filepath.clone(),
+                    "FATAL: Overlapping edits to the file.".into(),
+                ));
+            }
+            out.push_str(&self.text[pos..span.start]);
+            out.push_str(&text);
+            pos = span.end;
+        }
+        out.push_str(&self.text[pos..]);
+        self.text = out;
+        self.modified = true;
+        self.parse()
+    }
+
+    /// Write the document back to disk if it was modified, after backing up
+    /// the existing file. If no headers are left in the document, the file is
+    /// deleted instead, because a file without headers cannot be loaded.
+    pub fn save(&self) -> Result<(), Error> {
+        if !self.modified {
+            return Ok(());
+        }
+        if self.filepath.exists() {
+            std::fs::copy(&self.filepath, get_ftag_backup_path(&self.filepath))
+                .map_err(|_| Error::CannotWriteFile(self.filepath.clone()))?;
+            if self.blocks.is_empty() {
+                return std::fs::remove_file(&self.filepath)
+                    .map_err(|_| Error::CannotWriteFile(self.filepath.clone()));
+            }
+        } else if self.blocks.is_empty() {
+            return Ok(());
+        }
+        std::fs::write(&self.filepath, &self.text)
+            .map_err(|_| Error::CannotWriteFile(self.filepath.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEXT: &str = "[desc]
+My photos.
+
+[tags]
+photos   travel
+
+[path]
+a.jpg
+b.jpg
+[tags]
+beach
+sunset   sea
+[desc]
+Two photos
+
+[path]
+c.jpg
+[desc]
+Lonely photo
+";
+
+    fn parse(text: &str) -> FtagDocument {
+        FtagDocument::from_text(text.to_string(), PathBuf::from(".ftag")).unwrap()
+    }
+
+    #[test]
+    fn t_parse_entries() {
+        let doc = parse(TEXT);
+        assert_eq!(doc.num_entries(), 2);
+        assert_eq!(
+            doc.tags(TagOwner::Dir).collect::<Vec<_>>(),
+            ["photos", "travel"]
+        );
+        assert_eq!(doc.desc(TagOwner::Dir), Some("My photos."));
+        assert_eq!(
+            doc.globs(0).map(|(_, g)| g).collect::<Vec<_>>(),
+            ["a.jpg", "b.jpg"]
+        );
+        assert_eq!(
+            doc.tags(TagOwner::Entry(0)).collect::<Vec<_>>(),
+            ["beach", "sunset", "sea"]
+        );
+        assert_eq!(doc.tags(TagOwner::Entry(1)).count(), 0);
+        assert_eq!(doc.find_entry("c.jpg", 0), Some(1));
+        assert_eq!(doc.find_entry("a.jpg", 1), None);
+    }
+
+    #[test]
+    fn t_add_tags() {
+        let mut doc = parse(TEXT);
+        doc.add_tags(TagOwner::Dir, &["travel", "archive"]);
+        doc.add_tags(TagOwner::Entry(0), &["sea", "waves"]);
+        doc.add_tags(TagOwner::Entry(1), &["portrait"]);
+        doc.apply().unwrap();
+        assert_eq!(
+            doc.text.as_str(),
+            "[desc]
+My photos.
+
+[tags]
+photos   travel archive
+
+[path]
+a.jpg
+b.jpg
+[tags]
+beach
+sunset   sea waves
+[desc]
+Two photos
+
+[path]
+c.jpg
+[tags]
+portrait 
+[desc]
+Lonely photo
+"
+        );
+    }
+
+    #[test]
+    fn t_add_dir_tags() {
+        let mut doc = parse("[path]\na.jpg\n");
+        doc.add_tags(TagOwner::Dir, &["photos"]);
+        doc.apply().unwrap();
+        assert_eq!(doc.text.as_str(), "[tags]\nphotos \n\n[path]\na.jpg\n");
+        let mut empty = parse("");
+        empty.add_tags(TagOwner::Dir, &["photos"]);
+        empty.apply().unwrap();
+        assert_eq!(empty.text.as_str(), "[tags]\nphotos \n");
+    }
+
+    #[test]
+    fn t_remove_tags() {
+        let mut doc = parse(TEXT);
+        doc.remove_tags(TagOwner::Entry(0), &["sunset", "beach"]);
+        doc.apply().unwrap();
+        assert_eq!(doc.tags(TagOwner::Entry(0)).collect::<Vec<_>>(), ["sea"]);
+        assert!(doc.text.contains("[tags]\nsea\n[desc]\nTwo photos"));
+        doc.remove_tags(TagOwner::Entry(0), &["sea"]);
+        doc.remove_tags(TagOwner::Dir, &["travel"]);
+        doc.apply().unwrap();
+        assert!(doc.text.contains("[tags]\nphotos\n\n[path]"));
+        assert!(doc.text.contains("b.jpg\n[desc]\nTwo photos"));
+    }
+
+    #[test]
+    fn t_split_and_remove_entries() {
+        let mut doc = parse(TEXT);
+        let (span, _) = doc.globs(0).find(|(_, g)| *g == "a.jpg").unwrap();
+        doc.remove_glob(span);
+        doc.insert_entry(Some(0), &["a.jpg"], &["beach", "dunes"], Some("Sand"));
+        doc.remove_entry(1);
+        doc.apply().unwrap();
+        assert_eq!(
+            doc.text.as_str(),
+            "[desc]
+My photos.
+
+[tags]
+photos   travel
+
+[path]
+b.jpg
+[tags]
+beach
+sunset   sea
+[desc]
+Two photos
+
+[path]
+a.jpg
+[tags]
+beach dunes 
+[desc]
+Sand
+
+"
+        );
+        let (span, _) = doc.globs(0).next().unwrap();
+        doc.replace_glob(span, "c.jpg");
+        doc.apply().unwrap();
+        assert!(doc.text.contains("[path]\nc.jpg\n[tags]\nbeach\n"));
+        doc.add_globs(0, &["d.jpg"]);
+        doc.insert_entry(None, &["e.jpg"], &["x"], None);
+        doc.apply().unwrap();
+        assert_eq!(
+            doc.globs(0).map(|(_, g)| g).collect::<Vec<_>>(),
+            ["c.jpg", "d.jpg"]
+        );
+        assert!(doc.text.ends_with("Sand\n\n[path]\ne.jpg\n[tags]\nx \n"));
+    }
+
+    #[test]
+    fn t_comments() {
+        let mut doc = parse(
+            "[path]
+a.jpg
+# old.jpg
+\\#draft.jpg
+[tags]
+beach sea
+# Added later:
+sunset
+# Trailing comment
+",
+        );
+        assert_eq!(
+            doc.globs(0).map(|(_, g)| g).collect::<Vec<_>>(),
+            ["a.jpg", "#draft.jpg"]
+        );
+        assert_eq!(
+            doc.tags(TagOwner::Entry(0)).collect::<Vec<_>>(),
+            ["beach", "sea", "sunset"]
+        );
+        doc.add_tags(TagOwner::Entry(0), &["dunes"]);
+        doc.add_globs(0, &["#new.jpg"]);
+        doc.apply().unwrap();
+        assert_eq!(
+            doc.text.as_str(),
+            "[path]
+a.jpg
+# old.jpg
+\\#draft.jpg
+\\#new.jpg
+[tags]
+beach sea
+# Added later:
+sunset dunes
+# Trailing comment
+"
+        );
+        assert_eq!(
+            doc.globs(0).map(|(_, g)| g).collect::<Vec<_>>(),
+            ["a.jpg", "#draft.jpg", "#new.jpg"]
+        );
+        doc.remove_tags(TagOwner::Entry(0), &["beach", "sea", "sunset", "dunes"]);
+        doc.apply().unwrap();
+        assert_eq!(
+            doc.text.as_str(),
+            "[path]
+a.jpg
+# old.jpg
+\\#draft.jpg
+\\#new.jpg
+[tags]
+# Added later:
+# Trailing comment
+"
+        );
+    }
+
+    #[test]
+    fn t_untouched_round_trip() {
+        let mut doc = parse(TEXT);
+        doc.remove_tags(TagOwner::Entry(1), &["nothing"]);
+        doc.add_tags(TagOwner::Entry(0), &["beach"]);
+        doc.apply().unwrap();
+        assert_eq!(doc.text.as_str(), TEXT);
+    }
+}
@@ -0,0 +1,477 @@
+use crate::{
+    config::Config,
+    core::Error,
+    load::{
+        get_filename_lossy, infer_implicit_tags, FileLoadingOptions, GlobMatches, LoaderOptions,
+    },
+    output::{Field, FileRecord, Format, OutputOptions, Printer, Record},
+    query::{file_record, InheritedTags},
+    walk::{DirTree, MetaData, VisitedDir, WalkOptions},
+};
+use std::path::PathBuf;
+
+/*
+Search results are ranked using BM25, computed over three fields of each file:
+the tags (explicit, inherited and implicit), the descriptions (of the file and
+of the directories containing it), and the relative path. The term frequencies
+and lengths of the fields are weighted and summed before they're plugged into
+BM25, so a match in the tags counts for more than a match in the description.
+Only the statistics of the query terms are gathered during the walk, so memory
+usage only grows with the number of files that match the query.
+ */
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+const TAG_WEIGHT: f64 = 2.0;
+const DESC_WEIGHT: f64 = 1.0;
+const PATH_WEIGHT: f64 = 1.0;
+
+/// The fields of a file a term can be matched against.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Scope {
+    /// Tags and description.
+    Any,
+    Tag,
+    Desc,
+    Path,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Occur {
+    /// The term contributes to the score if it matches.
+    Should,
+    /// Only files that match the term are included in the results.
+    Must,
+    /// Files that match the term are excluded from the results.
+    MustNot,
+}
+
+/// A word or a phrase in a search string. A single unquoted word matches any
+/// part of a word, so 'beach' also matches 'beaches'. Quoted words and phrases
+/// match whole words, in the given order.
+#[derive(PartialEq, Eq, Debug)]
+struct Term {
+    words: Vec<String>,
+    exact: bool,
+    scope: Scope,
+    occur: Occur,
+}
+
+/// Split `text` into lower case words made of alphanumeric characters.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+/// Parse a search string into terms. Each term can be prefixed with '+' to
+/// make it required, or '-' to exclude the files that match it. This can be
+/// followed by 'tag:', 'desc:' or 'path:' to only match the term against that
+/// field of the files. Phrases are enclosed in double quotes.
+fn parse_terms(input: &str) -> Vec<Term> {
+    let mut terms = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let occur = if let Some(r) = rest.strip_prefix('+') {
+            rest = r;
+            Occur::Must
+        } else if let Some(r) = rest.strip_prefix('-') {
+            rest = r;
+            Occur::MustNot
+        } else {
+            Occur::Should
+        };
+        let mut scope = Scope::Any;
+        for (prefix, s) in [
+            ("tag:", Scope::Tag),
+            ("desc:", Scope::Desc),
+            ("path:", Scope::Path),
+        ] {
+            if rest
+                .get(..prefix.len())
+                .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+            {
+                rest = &rest[prefix.len()..];
+                scope = s;
+                break;
+            }
+        }
+        let (text, exact) = if let Some(r) = rest.strip_prefix('"') {
+            let end = r.find('"').unwrap_or(r.len());
+            rest = r.get((end + 1)..).unwrap_or("");
+            (&r[..end], true)
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let text = &rest[..end];
+            rest = &rest[end..];
+            (text, false)
+        };
+        let words: Vec<_> = tokenize(text).collect();
+        if !words.is_empty() {
+            terms.push(Term {
+                exact: exact || words.len() > 1,
+                words,
+                scope,
+                occur,
+            });
+        }
+        rest = rest.trim_start();
+    }
+    terms
+}
+
+/// Count the occurrences of `term` in the list of `words`.
+fn count_matches(term: &Term, words: &[String]) -> usize {
+    if term.exact {
+        let n = term.words.len();
+        if words.len() < n {
+            return 0;
+        }
+        (0..=(words.len() - n))
+            .filter(|i| words[*i..(*i + n)] == term.words[..])
+            .count()
+    } else {
+        words.iter().filter(|w| w.contains(&term.words[0])).count()
+    }
+}
+
+/// Words in the fields of a file.
+struct Document {
+    tags: Vec<String>,
+    desc: Vec<String>,
+    path: Vec<String>,
+}
+
+impl Document {
+    fn len(&self) -> f64 {
+        TAG_WEIGHT * self.tags.len() as f64
+            + DESC_WEIGHT * self.desc.len() as f64
+            + PATH_WEIGHT * self.path.len() as f64
+    }
+
+    /// Weighted frequency of `term` in this document.
+    fn frequency(&self, term: &Term) -> f64 {
+        let tags = || TAG_WEIGHT * count_matches(term, &self.tags) as f64;
+        let desc = || DESC_WEIGHT * count_matches(term, &self.desc) as f64;
+        match term.scope {
+            Scope::Any => tags() + desc(),
+            Scope::Tag => tags(),
+            Scope::Desc => desc(),
+            Scope::Path => PATH_WEIGHT * count_matches(term, &self.path) as f64,
+        }
+    }
+}
+
+/// Statistics of the query terms across all documents, used for scoring.
+struct Corpus {
+    num_docs: usize,
+    total_len: f64,
+    /// Number of documents containing each term.
+    doc_freq: Vec<usize>,
+}
+
+impl Corpus {
+    fn score(&self, freqs: &[f64], len: f64) -> f64 {
+        let n = self.num_docs as f64;
+        let avglen = if self.num_docs > 0 && self.total_len > 0. {
+            self.total_len / n
+        } else {
+            1.
+        };
+        freqs
+            .iter()
+            .zip(self.doc_freq.iter())
+            .filter(|(tf, _)| **tf > 0.)
+            .map(|(tf, df)| {
+                let df = *df as f64;
+                let idf = (1. + (n - df + 0.5) / (df + 0.5)).ln();
+                idf * tf * (K1 + 1.) / (tf + K1 * (1. - B + B * len / avglen))
+            })
+            .sum()
+    }
+}
+
+/// Check if a document with the given term frequencies should be included in
+/// the results. A document must match all required terms, and none of the
+/// excluded terms. If there are no required terms, at least one of the other
+/// terms must match.
+fn is_hit(terms: &[Term], freqs: &[f64]) -> bool {
+    let mut any_required = false;
+    let mut any_optional = false;
+    for (term, tf) in terms.iter().zip(freqs.iter()) {
+        let found = *tf > 0.;
+        match term.occur {
+            Occur::Must if !found => return false,
+            Occur::MustNot if found => return false,
+            Occur::Must => any_required = true,
+            Occur::Should if found => any_optional = true,
+            Occur::Should | Occur::MustNot => {}
+        }
+    }
+    any_required || any_optional
+}
+
+/// A file that matched the search, along with its score.
+pub struct SearchRecord {
+    pub score: f64,
+    pub record: FileRecord,
+}
+
+impl Record for SearchRecord {
+    const FIELDS: &'static [&'static str] = &[
+        "path",
+        "score",
+        "tags",
+        "explicit",
+        "inherited",
+        "implicit",
+        "desc",
+    ];
+
+    fn fields(&self) -> Vec<Field<'_>> {
+        let mut fields = self.record.fields();
+        fields.insert(1, Field::Float(self.score));
+        fields
+    }
+}
+
+struct Hit {
+    path: PathBuf,
+    record: Option<FileRecord>,
+    freqs: Vec<f64>,
+    len: f64,
+}
+
+/// Recursively search for files whose tags, descriptions or paths match the
+/// terms in `needle`, and print them best match first. Like in queries, files
+/// inherit the tags and descriptions of the directories containing them. If
+/// `scores` is true, the scores are printed along with the paths in text
+/// format. The scores are always included in the other formats.
+pub fn search(
+    path: PathBuf,
+    needle: &str,
+    scores: bool,
+    options: &OutputOptions,
+    walk: &WalkOptions,
+) -> Result<(), Error> {
+    let terms = parse_terms(needle);
+    let structured = options.format != Format::Text;
+    let config = Config::load(&path)?;
+    let mut corpus = Corpus {
+        num_docs: 0,
+        total_len: 0.,
+        doc_freq: vec![0; terms.len()],
+    };
+    let mut hits = Vec::new();
+    let mut inherited_names = InheritedTags::<String>::new();
+    let mut inherited_desc = InheritedTags::<String>::new();
+    let mut matcher = GlobMatches::new();
+    let mut dir = DirTree::new(
+        path.clone(),
+        LoaderOptions::new(
+            true,
+            true,
+            FileLoadingOptions::Load {
+                file_tags: true,
+                file_desc: true,
+            },
+        ),
+        walk,
+    )?;
+    let mut doc = Document {
+        tags: Vec::new(),
+        desc: Vec::new(),
+        path: Vec::new(),
+    };
+    let mut freqs = vec![0.; terms.len()];
+    while let Some(VisitedDir {
+        traverse_depth,
+        rel_dir_path,
+        files,
+        metadata,
+        ..
+    }) = dir.walk()
+    {
+        inherited_names.update(traverse_depth)?;
+        inherited_desc.update(traverse_depth)?;
+        let data = match metadata {
+            MetaData::Ok(data) => data,
+            MetaData::NotFound => continue, // No metadata, just keep going.
+            MetaData::FailedToLoad(e) => return Err(e),
+        };
+        inherited_names.tags.extend(
+            data.tags().iter().map(|t| t.to_string()).chain(
+                infer_implicit_tags(&get_filename_lossy(rel_dir_path)).map(|t| t.to_string()),
+            ),
+        );
+        inherited_desc
+            .tags
+            .extend(data.desc.into_iter().flat_map(tokenize));
+        matcher.find_matches(files, &data.globs, false);
+        for (fi, file) in files
+            .iter()
+            .enumerate()
+            .filter(|(fi, _)| matcher.is_file_matched(*fi))
+        {
+            let fname = file.name().to_string_lossy();
+            let mut relpath = rel_dir_path.to_path_buf();
+            relpath.push(file.name());
+            doc.tags.clear();
+            doc.desc.clear();
+            doc.path.clear();
+            for g in matcher.matched_globs(fi).map(|gi| &data.globs[gi]) {
+                doc.tags
+                    .extend(g.tags(&data.alltags).iter().flat_map(|t| tokenize(t)));
+                doc.desc.extend(g.desc.into_iter().flat_map(tokenize));
+            }
+            doc.tags.extend(
+                infer_implicit_tags(&fname)
+                    .map(|t| t.to_string())
+                    .chain(inherited_names.tags.iter().cloned())
+                    .flat_map(|t| tokenize(&t).collect::<Vec<_>>()),
+            );
+            doc.desc.extend(inherited_desc.tags.iter().cloned());
+            doc.path
+                .extend(tokenize(&relpath.to_string_lossy()).collect::<Vec<_>>());
+            let len = doc.len();
+            corpus.num_docs += 1;
+            corpus.total_len += len;
+            for ((tf, df), term) in freqs
+                .iter_mut()
+                .zip(corpus.doc_freq.iter_mut())
+                .zip(terms.iter())
+            {
+                *tf = doc.frequency(term);
+                if *tf > 0. {
+                    *df += 1;
+                }
+            }
+            if !is_hit(&terms, &freqs) {
+                continue;
+            }
+            hits.push(Hit {
+                record: structured.then(|| {
+                    file_record(
+                        relpath.clone(),
+                        data,
+                        matcher.matched_globs(fi),
+                        &fname,
+                        &inherited_names.tags,
+                        &config,
+                    )
+                }),
+                path: relpath,
+                freqs: freqs.clone(),
+                len,
+            });
+        }
+    }
+    dir.finish()?;
+    let mut scored: Vec<_> = hits
+        .into_iter()
+        .map(|hit| (corpus.score(&hit.freqs, hit.len), hit))
+        .collect();
+    scored.sort_by(|(sa, a), (sb, b)| sb.total_cmp(sa).then_with(|| a.path.cmp(&b.path)));
+    let mut printer = Printer::new::<SearchRecord>(options, &path);
+    for (score, hit) in scored {
+        match hit.record {
+            Some(record) => printer.print(&SearchRecord { score, record }),
+            None if scores => printer.print_prefixed_path(&format!("{:.3}\t", score), &hit.path),
+            None => printer.print_path(&hit.path),
+        }
+    }
+    printer.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    /// Check if a file with the given fields matches the search string.
+    fn is_match(needle: &str, tags: &str, desc: &str, path: &Path) -> bool {
+        let terms = parse_terms(needle);
+        let doc = Document {
+            tags: tokenize(tags).collect(),
+            desc: tokenize(desc).collect(),
+            path: tokenize(&path.to_string_lossy()).collect(),
+        };
+        let freqs: Vec<_> = terms.iter().map(|t| doc.frequency(t)).collect();
+        is_hit(&terms, &freqs)
+    }
+
+    #[test]
+    fn t_parse_terms() {
+        let terms = parse_terms(r#"beach +tag:2019 -desc:"old stuff" PATH:new-york "sun""#);
+        let expected = [
+            (vec!["beach"], false, Scope::Any, Occur::Should),
+            (vec!["2019"], false, Scope::Tag, Occur::Must),
+            (vec!["old", "stuff"], true, Scope::Desc, Occur::MustNot),
+            (vec!["new", "york"], true, Scope::Path, Occur::Should),
+            (vec!["sun"], true, Scope::Any, Occur::Should),
+        ];
+        assert_eq!(terms.len(), expected.len());
+        for (term, (words, exact, scope, occur)) in terms.iter().zip(expected) {
+            assert_eq!(term.words, words);
+            assert_eq!(term.exact, exact);
+            assert_eq!(term.scope, scope);
+            assert_eq!(term.occur, occur);
+        }
+        // Unterminated quotes run till the end.
+        let terms = parse_terms(r#"-"a b"#);
+        assert_eq!(terms.len(), 1);
+        assert_eq!(terms[0].words, ["a", "b"]);
+        assert!(parse_terms("  + - \"\" ").is_empty());
+    }
+
+    #[test]
+    fn t_match_terms() {
+        let path = Path::new("2019/IMG_001.jpg");
+        let (tags, desc) = ("beach vacation 2019", "Sunset at the old pier.");
+        assert!(is_match("beaches sun", tags, desc, path));
+        assert!(is_match("vaca", tags, desc, path));
+        assert!(!is_match("\"vaca\"", tags, desc, path));
+        assert!(is_match("\"old pier\"", tags, desc, path));
+        assert!(!is_match("\"pier old\"", tags, desc, path));
+        assert!(!is_match("img", tags, desc, path));
+        assert!(is_match("path:img", tags, desc, path));
+        assert!(!is_match("tag:sunset", tags, desc, path));
+        assert!(is_match("desc:sunset", tags, desc, path));
+        assert!(!is_match("beach -pier", tags, desc, path));
+        assert!(!is_match("beach +mountain", tags, desc, path));
+        assert!(is_match("+beach mountain", tags, desc, path));
+        assert!(!is_match("-mountain", tags, desc, path));
+    }
+
+    #[test]
+    fn t_bm25_ranking() {
+        let terms = parse_terms("beach");
+        let doc = |tags: &str, desc: &str| Document {
+            tags: tokenize(tags).collect(),
+            desc: tokenize(desc).collect(),
+            path: Vec::new(),
+        };
+        let docs = [
+            doc("beach", "sunset pier"),
+            doc("beach", "beach pier"),
+            doc("mountain", ""),
+            doc("beach", "a long description of something else"),
+        ];
+        let corpus = Corpus {
+            num_docs: docs.len(),
+            total_len: docs.iter().map(|d| d.len()).sum(),
+            doc_freq: vec![3],
+        };
+        let scores: Vec<_> = docs
+            .iter()
+            .map(|d| corpus.score(&[d.frequency(&terms[0])], d.len()))
+            .collect();
+        assert_eq!(scores[2], 0.);
+        // Repeated matches count for more, but with diminishing returns.
+        assert!(scores[1] > scores[0]);
+        assert!(scores[1] < 1.5 * scores[0]);
+        // Matches in longer documents count for less.
+        assert!(scores[0] > scores[3]);
+        assert!(scores[3] > 0.);
+    }
+}
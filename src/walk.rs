@@ -1,13 +1,228 @@
 use std::{
+    collections::VecDeque,
     ffi::{OsStr, OsString},
+    fmt::Display,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
 };
 
 use crate::{
-    core::{Error, FTAG_BACKUP_FILE, FTAG_FILE},
+    core::{
+        Error, FTAG_BACKUP_FILE, FTAG_CONFIG_FILE, FTAG_FILE, FTAG_HASH_FILE, FTAG_IGNORE_FILE,
+        FTAG_INDEX_FILE,
+    },
+    ignore::Ignores,
     load::{get_ftag_path, DirData, Loader, LoaderOptions},
 };
 
+/// Options that control how the directory tree is walked.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct WalkOptions {
+    /// Number of threads used to walk the directories. Zero means one thread
+    /// per available core.
+    pub threads: usize,
+    /// Don't skip the files and directories listed in ignore files.
+    pub no_ignore: bool,
+    /// Don't descend more than this many levels below the root. Zero only
+    /// visits the root.
+    pub max_depth: Option<usize>,
+    /// Follow symlinks to files and directories. Symlinks that lead back to
+    /// a directory containing them are skipped.
+    pub follow_symlinks: bool,
+    /// Don't descend into directories on other filesystems than the root.
+    pub one_file_system: bool,
+    /// Fail on the first problem found while walking, instead of skipping
+    /// what can't be read and reporting the problems at the end.
+    pub strict: bool,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions {
+            threads: 1,
+            no_ignore: false,
+            max_depth: None,
+            follow_symlinks: false,
+            one_file_system: false,
+            strict: false,
+        }
+    }
+}
+
+/// Problem found while walking the directories.
+#[derive(Debug)]
+pub enum Problem {
+    /// A directory or entry could not be read.
+    Io(String),
+    /// The name is not valid UTF-8. Such files can still be matched by globs,
+    /// but their implicit tags can't be inferred reliably.
+    NonUtf8Name,
+}
+
+/// Problem found at a path while walking the directories.
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// Path relative to the root of the walk.
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    fn io(path: PathBuf, e: std::io::Error) -> Self {
+        Diagnostic {
+            path,
+            problem: Problem::Io(e.to_string()),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.problem {
+            Problem::Io(message) => write!(f, "'{}': {}", self.path.display(), message),
+            Problem::NonUtf8Name => {
+                write!(f, "'{}': Name is not valid UTF-8", self.path.display())
+            }
+        }
+    }
+}
+
+/// Print the problems found during a walk to stderr, so that they don't mix
+/// with the results.
+pub(crate) fn report(mut diagnostics: Vec<Diagnostic>) {
+    if diagnostics.is_empty() {
+        return;
+    }
+    diagnostics.sort_by(|a, b| a.path.cmp(&b.path));
+    eprintln!(
+        "Skipped {} problem(s) while walking the directories:",
+        diagnostics.len()
+    );
+    for d in diagnostics {
+        eprintln!("  {}", d);
+    }
+}
+
+/// Identity of a directory on disk.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct DirId {
+    dev: u64,
+    ino: u64,
+}
+
+#[cfg(unix)]
+fn dir_id(path: &Path) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| DirId {
+        dev: m.dev(),
+        ino: m.ino(),
+    })
+}
+
+#[cfg(not(unix))]
+fn dir_id(_path: &Path) -> Option<DirId> {
+    None // Cycles and filesystem boundaries are not detected.
+}
+
+/// Chain of directories from the root of the walk to a directory, used by the
+/// parallel walker to detect symlink cycles.
+struct Ancestors {
+    id: Option<DirId>,
+    parent: Option<Arc<Ancestors>>,
+}
+
+impl WalkOptions {
+    /// Get the ignore patterns that apply to the contents of a directory.
+    fn ignores(
+        &self,
+        parent: Option<&Arc<Ignores>>,
+        abs_dir_path: &Path,
+        rel_dir_path: &Path,
+    ) -> Option<Arc<Ignores>> {
+        match self.no_ignore {
+            true => None,
+            false => Ignores::load(parent, abs_dir_path, rel_dir_path),
+        }
+    }
+
+    /// Get the identity of a directory, if it's needed to decide whether to
+    /// visit its subdirectories.
+    fn dir_id(&self, path: &Path) -> Option<DirId> {
+        match self.follow_symlinks || self.one_file_system {
+            true => dir_id(path),
+            false => None,
+        }
+    }
+
+    /// Check if the directory `id` should be visited, given the root of the
+    /// walk and the ancestors of the directory. Without following symlinks, a
+    /// directory can't be its own ancestor.
+    fn can_visit(
+        &self,
+        id: Option<DirId>,
+        root: Option<DirId>,
+        mut ancestors: impl Iterator<Item = DirId>,
+    ) -> bool {
+        match (id, root) {
+            (Some(id), Some(root)) => {
+                let crosses_fs = self.one_file_system && id.dev != root.dev;
+                !crosses_fs && !ancestors.any(|a| a == id)
+            }
+            _ => true,
+        }
+    }
+
+    /// Call `push` with the name and type of every file and directory in the
+    /// directory at `abs_dir_path`, that should be visited according to these
+    /// options. `rel_dir_path` is the path of the directory relative to the
+    /// root of the walk, and `depth` is its traversal depth. Entries that
+    /// match `ignores` are skipped.
+    pub(crate) fn read_entries(
+        &self,
+        abs_dir_path: &Path,
+        rel_dir_path: &Path,
+        depth: usize,
+        ignores: Option<&Ignores>,
+        diagnostics: &mut Vec<Diagnostic>,
+        mut push: impl FnMut(OsString, DirEntryType),
+    ) {
+        let skip_dirs = self.max_depth.is_some_and(|max| depth > max);
+        read_dir_entries(
+            abs_dir_path,
+            rel_dir_path,
+            self.follow_symlinks,
+            diagnostics,
+            |name, entry_type, diagnostics| match ignores {
+                _ if skip_dirs && entry_type == DirEntryType::Dir => {}
+                Some(ignores)
+                    if ignores.is_ignored(rel_dir_path, &name, entry_type == DirEntryType::Dir) => {
+                }
+                _ => {
+                    if name.to_str().is_none() {
+                        diagnostics.push(Diagnostic {
+                            path: rel_dir_path.join(&name),
+                            problem: Problem::NonUtf8Name,
+                        });
+                    }
+                    push(name, entry_type)
+                }
+            },
+        );
+    }
+
+    fn num_threads(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            n => n,
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Copy, Clone)]
 pub(crate) enum DirEntryType {
     File,
@@ -24,9 +239,22 @@ pub(crate) struct DirEntry {
 }
 
 impl DirEntry {
+    /// Create an entry that is not part of a recursive traversal.
+    pub fn new(name: OsString, entry_type: DirEntryType) -> Self {
+        DirEntry {
+            depth: 0,
+            entry_type,
+            name,
+        }
+    }
+
     pub fn name(&self) -> &OsStr {
         &self.name
     }
+
+    pub fn entry_type(&self) -> DirEntryType {
+        self.entry_type
+    }
 }
 
 /// Recursively walk directories, while caching useful information
@@ -38,6 +266,12 @@ pub(crate) struct DirTree {
     cur_depth: usize,
     num_children: usize,
     loader: Loader,
+    options: WalkOptions,
+    root_id: Option<DirId>,
+    /// Ignore patterns and identity of each directory in the current depth
+    /// first chain.
+    chain: Vec<(Option<Arc<Ignores>>, Option<DirId>)>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 pub(crate) enum MetaData<'a> {
@@ -54,12 +288,85 @@ pub(crate) struct VisitedDir<'a> {
     pub(crate) metadata: MetaData<'a>,
 }
 
-fn is_ftag_file(file: &OsStr) -> bool {
-    file == OsStr::new(FTAG_FILE) || file == OsStr::new(FTAG_BACKUP_FILE)
+pub(crate) fn is_ftag_file(file: &OsStr) -> bool {
+    file == OsStr::new(FTAG_FILE)
+        || file == OsStr::new(FTAG_BACKUP_FILE)
+        || file == OsStr::new(FTAG_HASH_FILE)
+        || file == OsStr::new(FTAG_INDEX_FILE)
+        || file == OsStr::new(FTAG_IGNORE_FILE)
+        || file == OsStr::new(FTAG_CONFIG_FILE)
+}
+
+/// Call `push` with the name and type of every file and directory in
+/// `dirpath`, except the files managed by ftag. Symlinks are skipped, unless
+/// `follow_symlinks` is true, in which case they have the type of their
+/// target. Entries that are neither files nor directories are skipped.
+/// Entries that can't be read are skipped and added to `diagnostics`.
+fn read_dir_entries(
+    dirpath: &Path,
+    rel_dir_path: &Path,
+    follow_symlinks: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+    mut push: impl FnMut(OsString, DirEntryType, &mut Vec<Diagnostic>),
+) {
+    let entries = match std::fs::read_dir(dirpath) {
+        Ok(entries) => entries,
+        Err(e) => return diagnostics.push(Diagnostic::io(rel_dir_path.to_path_buf(), e)),
+    };
+    for child in entries {
+        let child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                diagnostics.push(Diagnostic::io(rel_dir_path.to_path_buf(), e));
+                continue;
+            }
+        };
+        let cname = child.file_name();
+        if is_ftag_file(&cname) {
+            continue;
+        }
+        let entry_type = match child.file_type() {
+            Ok(ctype) if ctype.is_dir() => DirEntryType::Dir,
+            Ok(ctype) if ctype.is_file() => DirEntryType::File,
+            Ok(ctype) if ctype.is_symlink() && follow_symlinks => {
+                match std::fs::metadata(child.path()) {
+                    Ok(target) if target.is_dir() => DirEntryType::Dir,
+                    Ok(target) if target.is_file() => DirEntryType::File,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        // Broken link.
+                        diagnostics.push(Diagnostic::io(rel_dir_path.join(&cname), e));
+                        continue;
+                    }
+                }
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                diagnostics.push(Diagnostic::io(rel_dir_path.join(&cname), e));
+                continue;
+            }
+        };
+        push(cname, entry_type, diagnostics);
+    }
+}
+
+/// Order in which the contents of a directory are stored: directories first,
+/// followed by the files sorted by name.
+fn cmp_entries(a: &DirEntry, b: &DirEntry) -> std::cmp::Ordering {
+    match (a.entry_type, b.entry_type) {
+        (DirEntryType::File, DirEntryType::File) => a.name.cmp(&b.name),
+        (DirEntryType::File, DirEntryType::Dir) => std::cmp::Ordering::Greater,
+        (DirEntryType::Dir, DirEntryType::File) => std::cmp::Ordering::Less,
+        (DirEntryType::Dir, DirEntryType::Dir) => std::cmp::Ordering::Equal,
+    }
 }
 
 impl DirTree {
-    pub fn new(rootdir: PathBuf, options: LoaderOptions) -> Result<Self, Error> {
+    pub fn new(
+        rootdir: PathBuf,
+        options: LoaderOptions,
+        walk: &WalkOptions,
+    ) -> Result<Self, Error> {
         if !rootdir.is_dir() {
             return Err(Error::InvalidPath(rootdir));
         }
@@ -70,19 +377,36 @@ impl DirTree {
             name: OsString::new(),
         });
         Ok(DirTree {
+            root_id: walk.dir_id(&rootdir),
             abs_dir_path: rootdir,
             rel_dir_path: PathBuf::new(),
             stack,
             cur_depth: 0,
             num_children: 0,
             loader: Loader::new(options),
+            options: *walk,
+            chain: Vec::new(),
+            diagnostics: Vec::new(),
         })
     }
 
+    /// Report the problems found during the walk. In strict mode, the walk
+    /// stops at the first problem, and it is returned as an error here.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.options.strict && !self.diagnostics.is_empty() {
+            return Err(Error::WalkFailed(self.diagnostics.swap_remove(0)));
+        }
+        report(self.diagnostics);
+        Ok(())
+    }
+
     /// Move on to the next directory. Returns a tuple containing the depth of
     /// the directory, its absolute path, its path relative to the root of the
     /// walk, and a slice containing info about the files in this directory.
-    pub fn walk(&mut self) -> Option<VisitedDir> {
+    pub fn walk(&mut self) -> Option<VisitedDir<'_>> {
+        if self.options.strict && !self.diagnostics.is_empty() {
+            return None;
+        }
         while let Some(DirEntry {
             depth,
             entry_type,
@@ -100,40 +424,46 @@ impl DirTree {
                     self.abs_dir_path.push(name.clone());
                     self.rel_dir_path.push(name);
                     self.cur_depth += 1;
+                    self.chain.truncate(depth - 1);
+                    let id = self.options.dir_id(&self.abs_dir_path);
+                    if depth > 1
+                        && !self.options.can_visit(
+                            id,
+                            self.root_id,
+                            self.chain.iter().filter_map(|(_, id)| *id),
+                        )
+                    {
+                        continue;
+                    }
+                    let ignores = self.options.ignores(
+                        self.chain.last().and_then(|(ignores, _)| ignores.as_ref()),
+                        &self.abs_dir_path,
+                        &self.rel_dir_path,
+                    );
                     // Push all children.
                     let mut numfiles = 0;
                     let before = self.stack.len();
-                    if let Ok(entries) = std::fs::read_dir(&self.abs_dir_path) {
-                        for child in entries.flatten() {
-                            match (child.file_name(), child.file_type()) {
-                                (cname, _) if is_ftag_file(&cname) => continue,
-                                (cname, Ok(ctype)) if ctype.is_dir() => self.stack.push(DirEntry {
-                                    depth: depth + 1,
-                                    entry_type: DirEntryType::Dir,
-                                    name: cname,
-                                }),
-                                (cname, Ok(ctype)) if ctype.is_file() => {
-                                    self.stack.push(DirEntry {
-                                        depth: depth + 1,
-                                        entry_type: DirEntryType::File,
-                                        name: cname,
-                                    });
-                                    numfiles += 1;
-                                }
-                                _ => continue,
+                    self.options.read_entries(
+                        &self.abs_dir_path,
+                        &self.rel_dir_path,
+                        depth,
+                        ignores.as_deref(),
+                        &mut self.diagnostics,
+                        |name, entry_type| {
+                            if entry_type == DirEntryType::File {
+                                numfiles += 1;
                             }
-                        }
-                    }
+                            self.stack.push(DirEntry {
+                                depth: depth + 1,
+                                entry_type,
+                                name,
+                            });
+                        },
+                    );
+                    self.chain.push((ignores, id));
                     self.num_children = self.stack.len() - before;
                     // Sort the contents of this folder to move all the files to the end of the stack.
-                    self.stack[before..].sort_unstable_by(|a, b| {
-                        match (a.entry_type, b.entry_type) {
-                            (DirEntryType::File, DirEntryType::File) => a.name.cmp(&b.name),
-                            (DirEntryType::File, DirEntryType::Dir) => std::cmp::Ordering::Greater,
-                            (DirEntryType::Dir, DirEntryType::File) => std::cmp::Ordering::Less,
-                            (DirEntryType::Dir, DirEntryType::Dir) => std::cmp::Ordering::Equal,
-                        }
-                    });
+                    self.stack[before..].sort_unstable_by(cmp_entries);
                     return Some(VisitedDir {
                         traverse_depth: depth,
                         abs_dir_path: &self.abs_dir_path,
@@ -153,3 +483,466 @@ impl DirTree {
         None
     }
 }
+
+/// Directory waiting to be visited by the parallel walker.
+struct Job<C> {
+    depth: usize,
+    rel_dir_path: PathBuf,
+    /// Context returned by the visitor for the parent directory.
+    parent: Arc<C>,
+    /// Ignore patterns that apply to the parent directory.
+    ignores: Option<Arc<Ignores>>,
+    ancestors: Option<Arc<Ancestors>>,
+}
+
+/// Directories shared by the workers of the parallel walker. Every worker
+/// has its own queue. A worker pushes the subdirectories it finds to the back
+/// of its own queue, and pops directories from the back of its own queue, so
+/// that each worker walks its part of the tree depth first. When its own queue
+/// is empty, a worker steals directories from the front of the other queues,
+/// i.e. the ones closest to the root, that are likely to contain the most work.
+struct Jobs<C> {
+    queues: Box<[Mutex<VecDeque<Job<C>>>]>,
+    /// Number of directories that are either queued or being visited.
+    pending: AtomicUsize,
+    /// Set when a worker fails, to stop all the others.
+    failed: AtomicBool,
+    error: Mutex<Option<Error>>,
+    diagnostics: Mutex<Vec<Diagnostic>>,
+    /// Idle workers wait on `wakeup` while holding `idle`, until more jobs
+    /// are pushed, or the walk is complete or failed.
+    idle: Mutex<()>,
+    wakeup: Condvar,
+}
+
+/// A worker that panicked can only poison a mutex after the walk is already
+/// doomed, so the poison is ignored.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<C> Jobs<C> {
+    fn pop(&self, worker: usize) -> Option<Job<C>> {
+        if let Some(job) = lock(&self.queues[worker]).pop_back() {
+            return Some(job);
+        }
+        let n = self.queues.len();
+        (1..n).find_map(|k| lock(&self.queues[(worker + k) % n]).pop_front())
+    }
+
+    /// Get the next job for `worker`. If there are none, wait for the other
+    /// workers to push more. Returns `None` once the walk is complete, or
+    /// after a worker failed.
+    fn next(&self, worker: usize) -> Option<Job<C>> {
+        loop {
+            if self.failed.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(job) = self.pop(worker) {
+                return Some(job);
+            }
+            let guard = lock(&self.idle);
+            // Check again while holding the lock, because the workers that
+            // change the state take the lock before notifying. That way no
+            // notification is missed between these checks and the wait.
+            if self.failed.load(Ordering::Acquire) || self.pending.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+            if let Some(job) = self.pop(worker) {
+                return Some(job);
+            }
+            drop(
+                self.wakeup
+                    .wait(guard)
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+    }
+
+    /// Queue new jobs found by `worker`, and wake up as many idle workers.
+    fn push(&self, worker: usize, jobs: impl Iterator<Item = Job<C>>) {
+        let count = {
+            let mut queue = lock(&self.queues[worker]);
+            let before = queue.len();
+            queue.extend(jobs);
+            queue.len() - before
+        };
+        let _guard = lock(&self.idle);
+        for _ in 0..count {
+            self.wakeup.notify_one();
+        }
+    }
+
+    /// Mark a job as done, and wake up all the workers when it was the last.
+    fn done(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let _guard = lock(&self.idle);
+            self.wakeup.notify_all();
+        }
+    }
+
+    fn fail(&self, e: Error) {
+        let mut error = lock(&self.error);
+        if error.is_none() {
+            *error = Some(e);
+        }
+        self.failed.store(true, Ordering::Release);
+        drop(error);
+        let _guard = lock(&self.idle);
+        self.wakeup.notify_all();
+    }
+}
+
+/// Recursively walk the directories starting from `rootdir` using the number
+/// of threads given in `options`. Every directory is visited exactly once, by
+/// calling `visit` on one of the worker threads. `visit` receives the state of
+/// the worker thread, created with `init`, the context returned by `visit`
+/// for the parent directory (`root` for the root directory), and the visited
+/// directory. It returns the context for the subdirectories. This is how
+/// inherited tags are passed down the tree, because unlike `DirTree`, the
+/// directories are visited in no particular order, except that every
+/// directory is visited before its subdirectories. With a single thread, the
+/// directories are visited in the same order as `DirTree`.
+///
+/// Returns the states of all the worker threads once the walk is complete, or
+/// the first error returned by `visit`. Problems found while walking are
+/// reported like `DirTree::finish`.
+pub(crate) fn walk_parallel<C, S>(
+    rootdir: &Path,
+    loader_options: LoaderOptions,
+    options: &WalkOptions,
+    root: C,
+    init: impl Fn() -> S + Sync,
+    visit: impl Fn(&mut S, &Arc<C>, VisitedDir) -> Result<C, Error> + Sync,
+) -> Result<Vec<S>, Error>
+where
+    C: Send + Sync,
+    S: Send,
+{
+    if !rootdir.is_dir() {
+        return Err(Error::InvalidPath(rootdir.to_path_buf()));
+    }
+    let nthreads = options.num_threads().max(1);
+    let jobs = Jobs {
+        queues: (0..nthreads).map(|_| Mutex::new(VecDeque::new())).collect(),
+        pending: AtomicUsize::new(1),
+        failed: AtomicBool::new(false),
+        error: Mutex::new(None),
+        diagnostics: Mutex::new(Vec::new()),
+        idle: Mutex::new(()),
+        wakeup: Condvar::new(),
+    };
+    lock(&jobs.queues[0]).push_back(Job {
+        depth: 1,
+        rel_dir_path: PathBuf::new(),
+        parent: Arc::new(root),
+        ignores: None,
+        ancestors: None,
+    });
+    let run = |worker: usize| {
+        let mut state = init();
+        run_worker(
+            worker,
+            rootdir,
+            loader_options,
+            options,
+            &jobs,
+            &mut state,
+            &visit,
+        );
+        state
+    };
+    let states = if nthreads == 1 {
+        vec![run(0)]
+    } else {
+        std::thread::scope(|scope| {
+            let run = &run;
+            let handles: Vec<_> = (0..nthreads)
+                .map(|worker| scope.spawn(move || run(worker)))
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().ok())
+                .collect::<Vec<_>>()
+        })
+    };
+    let error = lock(&jobs.error).take();
+    match error {
+        Some(e) => Err(e),
+        None if states.len() < nthreads => Err(Error::DirectoryTraversalFailed),
+        None => {
+            report(std::mem::take(&mut *lock(&jobs.diagnostics)));
+            Ok(states)
+        }
+    }
+}
+
+fn run_worker<C, S>(
+    worker: usize,
+    rootdir: &Path,
+    loader_options: LoaderOptions,
+    options: &WalkOptions,
+    jobs: &Jobs<C>,
+    state: &mut S,
+    visit: &impl Fn(&mut S, &Arc<C>, VisitedDir) -> Result<C, Error>,
+) {
+    let mut loader = Loader::new(loader_options);
+    let mut entries: Vec<DirEntry> = Vec::new();
+    let mut diagnostics = Vec::new();
+    let root_id = options.dir_id(rootdir);
+    while let Some(Job {
+        depth,
+        rel_dir_path,
+        parent,
+        ignores,
+        ancestors,
+    }) = jobs.next(worker)
+    {
+        let abs_dir_path = rootdir.join(&rel_dir_path);
+        let id = options.dir_id(&abs_dir_path);
+        if depth > 1
+            && !options.can_visit(
+                id,
+                root_id,
+                std::iter::successors(ancestors.as_deref(), |a| a.parent.as_deref())
+                    .filter_map(|a| a.id),
+            )
+        {
+            jobs.done();
+            continue;
+        }
+        let ancestors = Some(Arc::new(Ancestors {
+            id,
+            parent: ancestors,
+        }));
+        let ignores = options.ignores(ignores.as_ref(), &abs_dir_path, &rel_dir_path);
+        entries.clear();
+        options.read_entries(
+            &abs_dir_path,
+            &rel_dir_path,
+            depth,
+            ignores.as_deref(),
+            &mut diagnostics,
+            |name, entry_type| {
+                entries.push(DirEntry {
+                    depth: depth + 1,
+                    entry_type,
+                    name,
+                })
+            },
+        );
+        if !diagnostics.is_empty() {
+            if options.strict {
+                jobs.fail(Error::WalkFailed(diagnostics.swap_remove(0)));
+                jobs.done();
+                continue;
+            }
+            lock(&jobs.diagnostics).append(&mut diagnostics);
+        }
+        entries.sort_unstable_by(cmp_entries);
+        let ndirs = entries
+            .iter()
+            .take_while(|e| e.entry_type == DirEntryType::Dir)
+            .count();
+        let visited = VisitedDir {
+            traverse_depth: depth,
+            abs_dir_path: &abs_dir_path,
+            rel_dir_path: &rel_dir_path,
+            files: &entries[ndirs..],
+            metadata: match get_ftag_path::<true>(&abs_dir_path) {
+                Some(fpath) => match loader.load(&fpath) {
+                    Ok(data) => MetaData::Ok(data),
+                    Err(e) => MetaData::FailedToLoad(e),
+                },
+                None => MetaData::NotFound,
+            },
+        };
+        match visit(state, &parent, visited) {
+            Ok(context) => {
+                if ndirs > 0 {
+                    let context = Arc::new(context);
+                    // Count the subdirectories before this directory is done,
+                    // so that the pending count never drops to zero early.
+                    jobs.pending.fetch_add(ndirs, Ordering::AcqRel);
+                    jobs.push(
+                        worker,
+                        entries.drain(..ndirs).map(|e| Job {
+                            depth: depth + 1,
+                            rel_dir_path: rel_dir_path.join(e.name),
+                            parent: context.clone(),
+                            ignores: ignores.clone(),
+                            ancestors: ancestors.clone(),
+                        }),
+                    );
+                }
+            }
+            Err(e) => jobs.fail(e),
+        }
+        jobs.done();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::load::FileLoadingOptions;
+
+    #[test]
+    fn t_walk_parallel() {
+        let root = std::env::temp_dir().join("ftag_t_walk_parallel");
+        let _ = std::fs::remove_dir_all(&root);
+        for a in ["a", "b", "c"] {
+            for b in ["x", "y"] {
+                let dir = root.join(a).join(b);
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(dir.join("file.txt"), "").unwrap();
+            }
+        }
+        std::fs::write(root.join("b").join(FTAG_FILE), "[tags]\nbee\n").unwrap();
+        for threads in [1, 4] {
+            let states = walk_parallel(
+                &root,
+                LoaderOptions::new(true, false, FileLoadingOptions::Skip),
+                &WalkOptions {
+                    threads,
+                    ..Default::default()
+                },
+                Vec::<String>::new(),
+                Vec::new,
+                |visited: &mut Vec<(PathBuf, Vec<String>, usize)>, parent, dir| {
+                    // The context is the list of names of the ancestors, and
+                    // the tags they declared.
+                    let mut context = parent.to_vec();
+                    if let Some(name) = dir.rel_dir_path.file_name() {
+                        context.push(name.to_string_lossy().to_string());
+                    }
+                    if let MetaData::Ok(data) = dir.metadata {
+                        context.extend(data.tags().iter().map(|t| t.to_string()));
+                    }
+                    visited.push((
+                        dir.rel_dir_path.to_path_buf(),
+                        context.clone(),
+                        dir.files.len(),
+                    ));
+                    Ok(context)
+                },
+            )
+            .unwrap();
+            let mut visited: Vec<_> = states.into_iter().flatten().collect();
+            visited.sort();
+            assert_eq!(visited.len(), 10);
+            assert_eq!(visited[0], (PathBuf::new(), vec![], 0));
+            assert_eq!(
+                visited[6],
+                (
+                    PathBuf::from("b/y"),
+                    vec!["b".to_string(), "bee".to_string(), "y".to_string()],
+                    1
+                )
+            );
+            assert_eq!(
+                visited[9],
+                (
+                    PathBuf::from("c/y"),
+                    vec!["c".to_string(), "y".to_string()],
+                    1
+                )
+            );
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn t_follow_symlinks() {
+        let root = std::env::temp_dir().join("ftag_t_follow_symlinks");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("photos").join("2019")).unwrap();
+        std::fs::create_dir_all(root.join("albums")).unwrap();
+        std::fs::write(root.join("photos").join("2019").join("a.jpg"), "").unwrap();
+        std::os::unix::fs::symlink("../photos/2019", root.join("albums").join("beach")).unwrap();
+        // Cycle back to the root.
+        std::os::unix::fs::symlink("../..", root.join("photos").join("2019").join("up")).unwrap();
+        let walk = |options: WalkOptions| -> Vec<PathBuf> {
+            let mut dir = DirTree::new(
+                root.clone(),
+                LoaderOptions::new(false, false, FileLoadingOptions::Skip),
+                &options,
+            )
+            .unwrap();
+            let mut files = Vec::new();
+            while let Some(visited) = dir.walk() {
+                files.extend(
+                    visited
+                        .files
+                        .iter()
+                        .map(|f| visited.rel_dir_path.join(f.name())),
+                );
+            }
+            dir.finish().unwrap();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            walk(WalkOptions::default()),
+            [PathBuf::from("photos/2019/a.jpg")]
+        );
+        assert_eq!(
+            walk(WalkOptions {
+                follow_symlinks: true,
+                ..Default::default()
+            }),
+            [
+                PathBuf::from("albums/beach/a.jpg"),
+                PathBuf::from("photos/2019/a.jpg"),
+            ]
+        );
+        assert!(walk(WalkOptions {
+            follow_symlinks: true,
+            max_depth: Some(1),
+            ..Default::default()
+        })
+        .is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn t_walk_diagnostics() {
+        use std::os::unix::ffi::OsStrExt;
+        let root = std::env::temp_dir().join("ftag_t_walk_diagnostics");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::write(root.join("a").join("good.jpg"), "").unwrap();
+        std::fs::write(root.join("a").join(OsStr::from_bytes(b"bad\xff.jpg")), "").unwrap();
+        let walk = |strict: bool| -> (usize, Result<(), Error>) {
+            let mut dir = DirTree::new(
+                root.clone(),
+                LoaderOptions::new(false, false, FileLoadingOptions::Skip),
+                &WalkOptions {
+                    strict,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let mut nfiles = 0;
+            while let Some(visited) = dir.walk() {
+                nfiles += visited.files.len();
+            }
+            (nfiles, dir.finish())
+        };
+        // The file is still visited, and the problem is only reported.
+        let (nfiles, result) = walk(false);
+        assert_eq!(nfiles, 2);
+        assert!(result.is_ok());
+        let (_, result) = walk(true);
+        match result {
+            Err(Error::WalkFailed(Diagnostic {
+                path,
+                problem: Problem::NonUtf8Name,
+            })) => assert_eq!(path, Path::new("a").join(OsStr::from_bytes(b"bad\xff.jpg"))),
+            _ => panic!("Expected the walk to fail on the non UTF-8 name"),
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}